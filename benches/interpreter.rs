@@ -0,0 +1,58 @@
+// Instructions per second with and without the decode cache, run it with
+// cargo bench, or cargo bench --features jit to see the JIT too
+
+use std::time::Instant;
+
+use system8::cpu::CPU;
+use system8::savestate;
+
+mod programs;
+
+use programs::{ALU, ROM};
+
+const INSTRUCTIONS: usize = 20_000_000;
+
+// step runs up to the instructions asked for and says how many it did
+fn run<F: FnMut(&mut CPU, u32) -> u32>(name: &str, program: &[u16], mut step: F) -> CPU {
+    let rom: Vec<u8> = program.iter().flat_map(|opcode| opcode.to_be_bytes().to_vec()).collect();
+    let mut cpu = CPU::initialize();
+    cpu.reseed(0);
+    cpu.load_fontset();
+    cpu.load_rom(&rom).unwrap();
+    let start = Instant::now();
+    let mut ran = 0;
+    while ran < INSTRUCTIONS {
+        ran += step(&mut cpu, (INSTRUCTIONS - ran) as u32) as usize;
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    assert!(!cpu.breakexe, "{} stopped at {:x}", name, cpu.pc);
+    println!("{:<8} {} instructions in {:.2}s, {:.1} million per second",
+             name, INSTRUCTIONS, elapsed, INSTRUCTIONS as f64 / elapsed / 1e6);
+    cpu
+}
+
+// Every way of running the program has to end up in the same state
+fn compare(program: &[u16]) {
+    let uncached = run("uncached", program, |cpu, _| {
+        cpu.fetch_uncached();
+        1
+    });
+    let cached = run("cached", program, |cpu, _| {
+        cpu.fetch_opcode();
+        1
+    });
+    assert!(savestate::save(&uncached) == savestate::save(&cached), "Both should end up the same");
+    #[cfg(feature = "jit")]
+    {
+        let mut jit = system8::jit::Jit::new(false).unwrap();
+        let jitted = run("jit", program, |cpu, count| jit.run(cpu, count));
+        assert!(savestate::save(&uncached) == savestate::save(&jitted), "Both should end up the same");
+    }
+}
+
+fn main() {
+    println!("Mixed and self-modifying:");
+    compare(&ROM);
+    println!("Arithmetic:");
+    compare(&ALU);
+}
@@ -0,0 +1,40 @@
+// The programs the benchmark runs, the JIT tests run them too
+
+// A busy loop with a bit of everything, arithmetic, random numbers, BCD,
+// a sprite and code that patches itself every time around
+pub const ROM: [u16; 18] = [
+    0x6000, // 200: LD V0, 0
+    0x6101, // 202: LD V1, 1
+    0x8014, // 204: ADD V0, V1
+    0x7201, // 206: ADD V2, 1
+    0x8320, // 208: LD V3, V2
+    0x8336, // 20A: SHR V3, V3
+    0x4F00, // 20C: SNE VF, 0
+    0x7401, // 20E: ADD V4, 1
+    0xC5FF, // 210: RND V5, 0xFF
+    0xA21B, // 212: LD I, 0x21B
+    0xF055, // 214: LD [I], V0, the NN of the next LD V6
+    0xA300, // 216: LD I, 0x300
+    0xF233, // 218: LD B, V2
+    0x6600, // 21A: LD V6, whatever V0 was
+    0x5600, // 21C: SE V6, V0
+    0x0000, // 21E: stale code ends up here
+    0xD011, // 220: DRW V0, V1, 1
+    0x1204  // 222: JP 0x204
+];
+
+// Nothing but arithmetic, where compiled code does best
+pub const ALU: [u16; 12] = [
+    0x7001, // 200: ADD V0, 1
+    0x8104, // 202: ADD V1, V0
+    0x8215, // 204: SUB V2, V1
+    0x8326, // 206: SHR V3, V2
+    0x8437, // 208: SUBN V4, V3
+    0x854E, // 20A: SHL V5, V4
+    0x8651, // 20C: OR V6, V5
+    0x8762, // 20E: AND V7, V6
+    0x8873, // 210: XOR V8, V7
+    0xF81E, // 212: ADD I, V8
+    0x4900, // 214: SNE V9, 0
+    0x1200  // 216: JP 0x200
+];
@@ -0,0 +1,83 @@
+/* Runs a ROM for a few seconds with key 5 held and prints the screen as
+ * text, then checks that loading a save state goes back in time. The capi
+ * test builds and runs it:
+ *
+ *   cc examples/capi.c -Iinclude -Ltarget/debug -lsystem8 -o capi
+ *   LD_LIBRARY_PATH=target/debug ./capi game.ch8
+ */
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "system8.h"
+
+static int fail(System8Machine *machine, const char *what) {
+    fprintf(stderr, "%s: %s\n", what, system8_last_error(machine));
+    system8_destroy(machine);
+    return 1;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 2) {
+        fprintf(stderr, "usage: %s ROM\n", argv[0]);
+        return 1;
+    }
+    FILE *file = fopen(argv[1], "rb");
+    if (!file) {
+        perror(argv[1]);
+        return 1;
+    }
+    uint8_t rom[4096];
+    size_t len = fread(rom, 1, sizeof(rom), file);
+    fclose(file);
+
+    System8Machine *machine = system8_create("chip8", 42);
+    if (!machine) {
+        fprintf(stderr, "Unknown quirks profile\n");
+        return 1;
+    }
+    if (system8_load_rom(machine, rom, len) != 0) {
+        return fail(machine, "load");
+    }
+
+    system8_set_key(machine, 5, true);
+    size_t state_size = system8_state_size(machine);
+    uint8_t *state = malloc(state_size);
+    int beeps = 0;
+    for (int frame = 0; frame < 180; frame++) {
+        if (frame == 60 && system8_save_state(machine, state, state_size) != 0) {
+            return fail(machine, "save");
+        }
+        if (system8_run_frame(machine, 10) != 0) {
+            return fail(machine, "run");
+        }
+        beeps += system8_sound(machine);
+    }
+
+    size_t width = system8_width(machine), height = system8_height(machine);
+    const uint8_t *pixels = system8_framebuffer(machine);
+    uint8_t *screen = malloc(width * height);
+    memcpy(screen, pixels, width * height);
+    for (size_t y = 0; y < height; y++) {
+        for (size_t x = 0; x < width; x++) {
+            putchar(screen[y * width + x] ? '#' : '.');
+        }
+        putchar('\n');
+    }
+    printf("%d frames with sound\n", beeps);
+
+    /* Running the same frames again from the save state gets the same screen */
+    if (system8_load_state(machine, state, state_size) != 0) {
+        return fail(machine, "restore");
+    }
+    for (int frame = 60; frame < 180; frame++) {
+        system8_run_frame(machine, 10);
+    }
+    int same = memcmp(screen, system8_framebuffer(machine), width * height) == 0;
+    printf("replay %s\n", same ? "matches" : "differs");
+
+    free(screen);
+    free(state);
+    system8_destroy(machine);
+    return same ? 0 : 1;
+}
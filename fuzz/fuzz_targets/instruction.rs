@@ -0,0 +1,63 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use system8::cpu::{Address, Quirks, CPU, MEMORY_SIZE};
+use system8::instruction::Instruction;
+
+// Any machine state, PC and I can be any address and SP can be outside the
+// stack
+#[derive(Arbitrary, Debug)]
+struct State {
+    opcode: u16,
+    pc: u16,
+    i: u16,
+    sp: u8,
+    v: [u8; 16],
+    stack: [u16; 16],
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: u16,
+    quirks: u8,
+    seed: u64,
+    memory: Vec<u8>
+}
+
+// Runs a single instruction from the state
+fuzz_target!(|state: State| {
+    // Decoding is the inverse of encoding
+    if let Ok(instruction) = Instruction::decode(state.opcode) {
+        assert_eq!(instruction.encode(), state.opcode, "{}", instruction);
+        assert!(!instruction.to_string().is_empty());
+    }
+
+    let mut cpu = CPU::initialize();
+    cpu.reseed(state.seed);
+    cpu.quirks = Quirks::from_bits(state.quirks);
+    let len = state.memory.len().min(MEMORY_SIZE);
+    cpu.write_memory(0, &state.memory[..len]);
+    cpu.pc = Address::new(state.pc as usize);
+    cpu.i = Address::new(state.i as usize);
+    cpu.sp = state.sp as usize;
+    cpu.v = state.v;
+    for (entry, &address) in cpu.stack.iter_mut().zip(state.stack.iter()) {
+        *entry = Address::new(address as usize);
+    }
+    cpu.delay_timer = state.delay_timer;
+    cpu.sound_timer = state.sound_timer;
+    cpu.set_keypad(state.keypad);
+
+    // The instruction goes wherever the PC points
+    cpu.write_memory(cpu.pc.index(), &state.opcode.to_be_bytes());
+
+    cpu.fetch_opcode();
+    assert_eq!(cpu.opcode, state.opcode);
+
+    // An SP that started out of the stack is only wrong if it moved
+    if cpu.sp == state.sp as usize && cpu.sp > cpu.stack.len() {
+        cpu.sp = 0;
+    }
+    if let Err(error) = cpu.invariants() {
+        panic!("{} after {:04x}", error, state.opcode);
+    }
+});
@@ -0,0 +1,50 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use system8::cpu::{Quirks, CPU};
+use system8::jit::Jit;
+use system8::savestate;
+
+// Frames to run before giving up on a ROM
+const FRAMES: usize = 1000;
+
+// Instructions per frame, the default speed
+const SPEED: u32 = 10;
+
+// The same input as the rom target but run by the JIT, with whole blocks
+// and not the single instructions of --jit-check. A copy runs in the
+// interpreter as many instructions as the JIT said it ran and both have
+// to end up the same
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let (header, rom) = data.split_at(3);
+    let mut cpu = CPU::initialize();
+    cpu.reseed(0);
+    cpu.quirks = Quirks::from_bits(header[0]);
+    cpu.set_keypad(u16::from_le_bytes([header[1], header[2]]));
+    cpu.load_fontset();
+    if cpu.load_rom(rom).is_err() {
+        return;
+    }
+    let mut jit = Jit::new(false).expect("Cranelift can't target this machine");
+    let mut interpreted = cpu.clone();
+    for _ in 0..FRAMES {
+        let mut remaining = SPEED;
+        while remaining > 0 && !cpu.breakexe && !cpu.waiting {
+            let ran = jit.run(&mut cpu, remaining);
+            for _ in 0..ran {
+                interpreted.fetch_uncached();
+            }
+            remaining -= ran;
+        }
+        if cpu.breakexe {
+            break;
+        }
+        cpu.tick_timers();
+        interpreted.tick_timers();
+    }
+    assert!(savestate::save(&cpu) == savestate::save(&interpreted),
+            "The JIT and the interpreter ended up different");
+});
@@ -0,0 +1,43 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use system8::cpu::{Quirks, CPU};
+use system8::savestate;
+
+// Instructions to run before giving up on a ROM, 1000 frames at the
+// default speed
+const CYCLES: usize = 10_000;
+
+// Any bytes as a ROM. The first byte picks the quirks and the next two the
+// keys held, the rest is loaded at 0x200 and run until it stops or runs out
+// of cycles. A copy runs without the decode cache and has to end up the same
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+    let (header, rom) = data.split_at(3);
+    let mut cpu = CPU::initialize();
+    cpu.reseed(0);
+    cpu.quirks = Quirks::from_bits(header[0]);
+    cpu.set_keypad(u16::from_le_bytes([header[1], header[2]]));
+    cpu.load_fontset();
+    if cpu.load_rom(rom).is_err() {
+        return;
+    }
+    let mut uncached = cpu.clone();
+    for cycle in 0..CYCLES {
+        cpu.fetch_opcode();
+        uncached.fetch_uncached();
+        if let Err(error) = cpu.invariants() {
+            panic!("{} after {} instructions", error, cycle + 1);
+        }
+        if cpu.breakexe {
+            break;
+        }
+        if cycle % 10 == 9 {
+            cpu.tick_timers();
+            uncached.tick_timers();
+        }
+    }
+    assert!(savestate::save(&cpu) == savestate::save(&uncached), "The decode cache ran stale code");
+});
@@ -0,0 +1,52 @@
+#ifndef SYSTEM8_H
+#define SYSTEM8_H
+
+/* Generated by cbindgen from src/capi.rs, which explains every function. Don't edit it by hand */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef struct System8Machine System8Machine;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+struct System8Machine *system8_create(const char *profile, uint64_t seed);
+
+void system8_destroy(struct System8Machine *machine);
+
+const char *system8_last_error(const struct System8Machine *machine);
+
+int32_t system8_load_rom(struct System8Machine *machine, const uint8_t *rom, size_t len);
+
+int32_t system8_step(struct System8Machine *machine, uint32_t instructions);
+
+int32_t system8_run_frame(struct System8Machine *machine, uint32_t speed);
+
+void system8_set_key(struct System8Machine *machine, uint8_t key, bool pressed);
+
+const uint8_t *system8_framebuffer(const struct System8Machine *machine);
+
+size_t system8_width(const struct System8Machine *machine);
+
+size_t system8_height(const struct System8Machine *machine);
+
+bool system8_sound(const struct System8Machine *machine);
+
+bool system8_halted(const struct System8Machine *machine);
+
+size_t system8_state_size(const struct System8Machine *machine);
+
+int32_t system8_save_state(struct System8Machine *machine, uint8_t *buffer, size_t len);
+
+int32_t system8_load_state(struct System8Machine *machine, const uint8_t *state, size_t len);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SYSTEM8_H */
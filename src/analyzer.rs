@@ -0,0 +1,386 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::cpu::Quirks;
+use crate::instruction::Instruction;
+
+// Where every ROM is loaded
+const START: usize = 0x200;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Platform {
+    Chip8,
+    SuperChip,
+    XoChip
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Platform::Chip8 => write!(f, "CHIP-8"),
+            Platform::SuperChip => write!(f, "SCHIP"),
+            Platform::XoChip => write!(f, "XO-CHIP")
+        }
+    }
+}
+
+// What we could find out about a ROM without running it
+#[derive(Debug)]
+pub struct Report {
+    pub platform: Platform,
+    // Opcode family ("8XY6", "DXYN"...) to the number of reachable uses
+    pub families: BTreeMap<&'static str, usize>,
+    // Human readable reasons to pick some quirks over others
+    pub hints: Vec<String>,
+    pub quirks: Quirks,
+    pub profile: &'static str,
+    // Inclusive address ranges
+    pub code: Vec<(usize, usize)>,
+    pub data: Vec<(usize, usize)>,
+    // Addresses loaded into I, usually sprites
+    pub data_refs: Vec<usize>,
+    // BNNN jumps and unknown opcodes stop the walk so part of the ROM
+    // may be code we didn't reach
+    pub incomplete: bool
+}
+
+// The first platform that had the instruction
+fn introduced_in(instruction: &Instruction) -> Platform {
+    match instruction {
+        Instruction::ScrollDown(_) | Instruction::ScrollRight | Instruction::ScrollLeft |
+        Instruction::Exit | Instruction::Lores | Instruction::Hires | Instruction::Draw(_, _, 0) |
+        Instruction::BigFont(_) | Instruction::SaveFlags(_) | Instruction::LoadFlags(_) => Platform::SuperChip,
+        Instruction::ScrollUp(_) | Instruction::SaveRange(..) | Instruction::LoadRange(..) |
+        Instruction::LongI | Instruction::Plane(_) | Instruction::Audio |
+        Instruction::Pitch(_) => Platform::XoChip,
+        _ => Platform::Chip8
+    }
+}
+
+// How control can leave an instruction
+enum Flow {
+    Next,
+    Skip,
+    Jump(usize),
+    Call(usize),
+    Stop
+}
+
+fn flow(instruction: &Instruction) -> Flow {
+    match *instruction {
+        Instruction::Return | Instruction::Exit | Instruction::JumpV0(_) => Flow::Stop,
+        Instruction::Jump(nnn) => Flow::Jump(nnn.index()),
+        Instruction::Call(nnn) => Flow::Call(nnn.index()),
+        Instruction::SkipEqual(..) | Instruction::SkipNotEqual(..) | Instruction::SkipEqualRegisters(..) |
+        Instruction::SkipNotEqualRegisters(..) | Instruction::SkipPressed(_) |
+        Instruction::SkipNotPressed(_) => Flow::Skip,
+        _ => Flow::Next
+    }
+}
+
+// The analyzer only sees the ROM, everything else reads as zeros
+struct Rom<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> Rom<'a> {
+
+    fn contains(&self, address: usize) -> bool {
+        address >= START && address + 1 < START + self.bytes.len()
+    }
+
+    fn opcode(&self, address: usize) -> u16 {
+        let offset = address - START;
+        (self.bytes[offset] as u16) << 8 | self.bytes[offset + 1] as u16
+    }
+
+    fn instruction(&self, address: usize) -> Result<Instruction, String> {
+        Instruction::decode(self.opcode(address))
+    }
+
+    // F000 NNNN takes two words
+    fn length(&self, address: usize) -> usize {
+        self.instruction(address).map_or(2, |instruction| instruction.length())
+    }
+
+}
+
+pub fn analyze(bytes: &[u8]) -> Report {
+    let rom = Rom { bytes };
+    let mut visited = vec![false; bytes.len()];
+    let mut is_code = vec![false; bytes.len()];
+    let mut families = BTreeMap::new();
+    let mut data_refs = Vec::new();
+    let mut incomplete = false;
+    let mut platform = Platform::Chip8;
+
+    // Walk every path from the entry point following jumps, calls and skips
+    let mut pending = vec![START];
+    while let Some(address) = pending.pop() {
+        if !rom.contains(address) {
+            continue;
+        }
+        if visited[address - START] {
+            continue;
+        }
+        visited[address - START] = true;
+
+        let instruction = match rom.instruction(address) {
+            Ok(instruction) => instruction,
+            Err(_) => {
+                incomplete = true;
+                continue;
+            }
+        };
+        let length = instruction.length();
+        *families.entry(instruction.family()).or_insert(0) += 1;
+        platform = platform.max(introduced_in(&instruction));
+        for offset in address..(address + length).min(START + bytes.len()) {
+            is_code[offset - START] = true;
+        }
+        if let Instruction::LoadI(nnn) = instruction {
+            data_refs.push(nnn.index());
+        } else if instruction == Instruction::LongI && rom.contains(address + 2) {
+            data_refs.push(rom.opcode(address + 2) as usize);
+        }
+
+        match flow(&instruction) {
+            Flow::Next => pending.push(address + length),
+            Flow::Skip => {
+                // Skipping over a F000 NNNN skips both words
+                let next = address + length;
+                pending.push(next);
+                if rom.contains(next) {
+                    pending.push(next + rom.length(next));
+                }
+            },
+            Flow::Jump(target) => pending.push(target),
+            Flow::Call(target) => {
+                pending.push(address + length);
+                pending.push(target);
+            },
+            Flow::Stop => incomplete |= matches!(instruction, Instruction::JumpV0(_))
+        }
+    }
+    data_refs.sort_unstable();
+    data_refs.dedup();
+
+    let mut hints = Vec::new();
+
+    // 8XY6 and 8XYE with different registers only make sense if VY is the
+    // one being shifted, like in the original interpreter
+    let vy_shifts = (START..START + bytes.len())
+        .filter(|&address| rom.contains(address) && visited[address - START])
+        .filter_map(|address| rom.instruction(address).ok())
+        .find(|instruction| match *instruction {
+            Instruction::ShiftRight(x, y) | Instruction::ShiftLeft(x, y) => x != y,
+            _ => false
+        });
+    if let Some(instruction) = vy_shifts {
+        hints.push(format!("{:04X} shifts VY into VX, the shift quirk must be off", instruction.encode()));
+    }
+
+    // Saving registers twice or loading them twice without pointing I
+    // somewhere else only works if FX55 and FX65 move I forward
+    let i_increment = relies_on_i_increment(&rom, &visited);
+    if let Some((first, second)) = i_increment {
+        hints.push(format!(
+            "0x{:03X} and 0x{:03X} both load or both store registers without changing I, \
+             the memory quirk must be on", first, second));
+    }
+
+    if families.contains_key("BNNN") {
+        hints.push("BNNN jumps are used, they differ between CHIP-8 and SCHIP".to_string());
+    }
+
+    // The platform picks the profile and the hints go on top of it, so the
+    // quirks say what the report does
+    let profile = match platform {
+        Platform::XoChip => "xochip",
+        Platform::SuperChip => "schip",
+        Platform::Chip8 if vy_shifts.is_some() || i_increment.is_some() => "chip8",
+        Platform::Chip8 => "rust8"
+    };
+    let mut quirks = Quirks::from_profile(profile).unwrap_or_default();
+    if vy_shifts.is_some() {
+        quirks.shift = false;
+    }
+    if i_increment.is_some() {
+        quirks.memory = true;
+    }
+
+    Report {
+        platform,
+        families,
+        hints,
+        quirks,
+        profile,
+        code: ranges(&is_code, true),
+        data: ranges(&is_code, false),
+        data_refs,
+        incomplete
+    }
+}
+
+// Follows the straight line code after every FX55/FX65 looking for another
+// of the same kind before I is set again, unconditional jumps are followed
+// to catch loops. Copy loops store or restore one block after the other,
+// FX55 and then FX65 is usually saving registers and getting them back
+// from the same place, which only works if I stays put
+fn relies_on_i_increment(rom: &Rom, visited: &[bool]) -> Option<(usize, usize)> {
+    // Some(true) for FX55 and Some(false) for FX65
+    let is_store = |instruction: &Instruction| match instruction {
+        Instruction::Store(_) => Some(true),
+        Instruction::Restore(_) => Some(false),
+        _ => None
+    };
+    for first in (START..START + visited.len()).filter(|&a| rom.contains(a) && visited[a - START]) {
+        let kind = match rom.instruction(first).ok().and_then(|instruction| is_store(&instruction)) {
+            Some(kind) => kind,
+            None => continue
+        };
+        let mut address = first + 2;
+        // Long enough for any sane loop body
+        for _ in 0..64 {
+            if !rom.contains(address) {
+                break;
+            }
+            let instruction = match rom.instruction(address) {
+                Ok(instruction) => instruction,
+                Err(_) => break
+            };
+            match is_store(&instruction) {
+                Some(other) if other == kind => return Some((first, address)),
+                Some(_) => break,
+                None => {}
+            }
+            match instruction {
+                Instruction::LoadI(_) | Instruction::AddI(_) | Instruction::Font(_) |
+                Instruction::BigFont(_) | Instruction::LongI => break,
+                _ => {}
+            }
+            match flow(&instruction) {
+                Flow::Next => address += 2,
+                Flow::Jump(target) => address = target,
+                _ => break
+            }
+        }
+    }
+    None
+}
+
+// Merges consecutive bytes of the same kind into address ranges
+fn ranges(is_code: &[bool], code: bool) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (offset, _) in is_code.iter().enumerate().filter(|&(_, &c)| c == code) {
+        let address = START + offset;
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == address => *end = address,
+            _ => ranges.push((address, address))
+        }
+    }
+    ranges
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let show_ranges = |ranges: &[(usize, usize)]| -> String {
+            if ranges.is_empty() {
+                return "none".to_string();
+            }
+            ranges.iter()
+                .map(|(start, end)| format!("0x{:03X}-0x{:03X}", start, end))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(f, "Platform: {}", self.platform)?;
+        writeln!(f, "Profile: {}", self.profile)?;
+        writeln!(f, "Opcodes:")?;
+        for (name, count) in &self.families {
+            writeln!(f, "  {:<10} {}", name, count)?;
+        }
+        writeln!(f, "Quirks:")?;
+        if self.hints.is_empty() {
+            writeln!(f, "  nothing special")?;
+        }
+        for hint in &self.hints {
+            writeln!(f, "  {}", hint)?;
+        }
+        writeln!(f, "Code: {}", show_ranges(&self.code))?;
+        writeln!(f, "Data: {}", show_ranges(&self.data))?;
+        if !self.data_refs.is_empty() {
+            let refs: Vec<String> = self.data_refs.iter().map(|a| format!("0x{:03X}", a)).collect();
+            writeln!(f, "I points to: {}", refs.join(", "))?;
+        }
+        if self.incomplete {
+            writeln!(f, "Some paths end in BNNN or unknown opcodes, part of the data may be code")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn shifting_vy_turns_the_shift_quirk_off() {
+        // 8126 and a jump to itself
+        let report = analyze(&[0x81, 0x26, 0x12, 0x02]);
+        assert_eq!(report.profile, "chip8");
+        assert!(!report.quirks.shift);
+        assert!(report.hints[0].contains("8126"));
+    }
+
+    #[test]
+    fn hints_win_over_the_profile() {
+        // SCHIP hires and then 8126, the schip profile shifts in place
+        let report = analyze(&[0x00, 0xFF, 0x81, 0x26, 0x12, 0x04]);
+        assert_eq!(report.profile, "schip");
+        assert!(!report.quirks.shift);
+        assert!(!report.quirks.memory);
+    }
+
+    #[test]
+    fn shifting_in_place_needs_nothing() {
+        let report = analyze(&[0x81, 0x16, 0x12, 0x02]);
+        assert_eq!(report.profile, "rust8");
+        assert!(report.hints.is_empty());
+        assert_eq!(report.quirks, Quirks::default());
+    }
+
+    #[test]
+    fn storing_twice_turns_the_memory_quirk_on() {
+        // A300, F155, F155 and a jump to itself
+        let report = analyze(&[0xA3, 0x00, 0xF1, 0x55, 0xF1, 0x55, 0x12, 0x06]);
+        assert_eq!(report.profile, "chip8");
+        assert!(report.quirks.memory);
+        assert!(report.hints[0].starts_with("0x202 and 0x204"));
+    }
+
+    #[test]
+    fn storing_and_restoring_needs_nothing() {
+        // A300, F155, F165 and a jump to itself, it wants the same bytes back
+        let report = analyze(&[0xA3, 0x00, 0xF1, 0x55, 0xF1, 0x65, 0x12, 0x06]);
+        assert_eq!(report.profile, "rust8");
+        assert!(report.hints.is_empty());
+        assert!(!report.quirks.memory);
+    }
+
+    #[test]
+    fn setting_i_in_between_needs_nothing() {
+        let report = analyze(&[0xA3, 0x00, 0xF1, 0x55, 0xA3, 0x10, 0xF1, 0x65, 0x12, 0x08]);
+        assert!(report.hints.is_empty());
+        assert!(!report.quirks.memory);
+    }
+
+    #[test]
+    fn addresses_are_shown_with_a_lowercase_0x() {
+        let report = analyze(&[0xA2, 0x04, 0x12, 0x02, 0xF0, 0x90]);
+        let text = report.to_string();
+        assert!(text.contains("Code: 0x200-0x203"), "{}", text);
+        assert!(text.contains("I points to: 0x204"), "{}", text);
+    }
+
+}
@@ -0,0 +1,298 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::cpu::CPU;
+
+// Lets other people watch a game over TCP. Spectators get the screen and the
+// buzzer, never the CPU, so the stream is only what changed on every frame:
+//
+//   "RUST8TV\0" once when connecting, then for every frame that changed
+//   width u16, height u16, buzzer u8, length u32 and the diff
+//
+// The diff is the framebuffer XORed with the one of the previous frame (or
+// a blank one for the first frame and when the size changes) as runs of a
+// count u8 from 1 to 255 and a value u8. Games only redraw a few sprites per
+// frame so it's mostly one long run of zeros. Numbers are little endian.
+const MAGIC: &[u8; 8] = b"RUST8TV\0";
+
+// Frames a spectator can fall behind before it's dropped, a second
+const BACKLOG: usize = 60;
+
+// The screen and the buzzer as the spectators see them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Frame {
+    pub display: (usize, usize),
+    pub gfx: Vec<u8>,
+    pub sound: bool
+}
+
+impl Frame {
+
+    pub fn of(cpu: &CPU) -> Frame {
+        let (width, height) = cpu.display_size();
+        Frame {
+            display: (width, height),
+            gfx: cpu.gfx[..width * height].to_vec(),
+            sound: cpu.sound_timer > 0
+        }
+    }
+
+    // The message that turns the previous frame into this one
+    pub fn encode(&self, previous: &Frame) -> Vec<u8> {
+        let blank = vec![0; self.gfx.len()];
+        let before = if previous.display == self.display { &previous.gfx } else { &blank };
+        let mut runs = Vec::new();
+        let mut xored = self.gfx.iter().zip(before.iter()).map(|(a, b)| a ^ b).peekable();
+        while let Some(value) = xored.next() {
+            let mut count = 1u8;
+            while count < 255 && xored.peek() == Some(&value) {
+                xored.next();
+                count += 1;
+            }
+            runs.push(count);
+            runs.push(value);
+        }
+        let mut message = Vec::with_capacity(runs.len() + 9);
+        message.extend_from_slice(&(self.display.0 as u16).to_le_bytes());
+        message.extend_from_slice(&(self.display.1 as u16).to_le_bytes());
+        message.push(self.sound as u8);
+        message.extend_from_slice(&(runs.len() as u32).to_le_bytes());
+        message.extend_from_slice(&runs);
+        message
+    }
+
+    // Reads the next message and applies it
+    pub fn decode<R: Read>(&mut self, reader: &mut R) -> io::Result<()> {
+        let mut header = [0; 9];
+        reader.read_exact(&mut header)?;
+        let display = (u16::from_le_bytes([header[0], header[1]]) as usize,
+                       u16::from_le_bytes([header[2], header[3]]) as usize);
+        let length = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        if display.0 * display.1 > 128 * 64 || length > 2 * 128 * 64 || !length.is_multiple_of(2) {
+            return Err(invalid("Invalid frame"));
+        }
+        let mut runs = vec![0; length];
+        reader.read_exact(&mut runs)?;
+        // The runs cover every pixel once, the frame stays as it was if not
+        let pixels: usize = runs.chunks_exact(2).map(|run| run[0] as usize).sum();
+        if pixels != display.0 * display.1 {
+            return Err(invalid("The runs don't match the size of the frame"));
+        }
+        if display != self.display {
+            self.display = display;
+            self.gfx = vec![0; display.0 * display.1];
+        }
+        let mut pixels = self.gfx.iter_mut();
+        for run in runs.chunks_exact(2) {
+            for pixel in pixels.by_ref().take(run[0] as usize) {
+                *pixel ^= run[1];
+            }
+        }
+        self.sound = header[4] != 0;
+        Ok(())
+    }
+
+}
+
+pub struct Broadcaster {
+    address: String,
+    spectators: Receiver<SyncSender<Arc<Vec<u8>>>>,
+    senders: Vec<SyncSender<Arc<Vec<u8>>>>,
+    last: Frame
+}
+
+impl Broadcaster {
+
+    // "PORT" only takes spectators from this machine, "HOST:PORT" from
+    // wherever HOST is reachable
+    pub fn bind(address: &str) -> Result<Broadcaster, String> {
+        let address = full_address(address);
+        let listener = TcpListener::bind(&address)
+            .map_err(|e| format!("Can't listen on {}: {}", address, e))?;
+        let (sender, spectators) = mpsc::channel();
+        thread::spawn(move || accept(listener, sender));
+        Ok(Broadcaster { address, spectators, senders: Vec::new(), last: Frame::default() })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    pub fn spectators(&self) -> usize {
+        self.senders.len()
+    }
+
+    // Call once per frame, after the instructions have run
+    pub fn frame(&mut self, cpu: &CPU) {
+        let frame = Frame::of(cpu);
+        if frame != self.last && !self.senders.is_empty() {
+            let message = Arc::new(frame.encode(&self.last));
+            // Disconnected or too far behind to catch up
+            self.senders.retain(|sender| sender.try_send(message.clone()).is_ok());
+        }
+        // Spectators that just came in start from a blank screen
+        let newcomers: Vec<_> = self.spectators.try_iter().collect();
+        if !newcomers.is_empty() {
+            let whole = Arc::new(frame.encode(&Frame::default()));
+            for sender in newcomers {
+                if sender.try_send(whole.clone()).is_ok() {
+                    self.senders.push(sender);
+                }
+            }
+        }
+        self.last = frame;
+    }
+
+}
+
+// Every spectator gets a thread that writes what the emulator sends, so a
+// slow connection never holds up the game
+fn accept(listener: TcpListener, spectators: Sender<SyncSender<Arc<Vec<u8>>>>) {
+    for stream in listener.incoming().flatten() {
+        let (sender, messages) = mpsc::sync_channel(BACKLOG);
+        if spectators.send(sender).is_err() {
+            return;
+        }
+        thread::spawn(move || {
+            let _ = stream.set_nodelay(true);
+            let mut writer = BufWriter::new(stream);
+            writer.write_all(MAGIC)?;
+            for message in messages {
+                writer.write_all(&message)?;
+                writer.flush()?;
+            }
+            Ok::<(), io::Error>(())
+        });
+    }
+}
+
+// The other end, keeps the latest frame of a broadcast
+pub struct Viewer {
+    frames: Receiver<Result<Frame, String>>
+}
+
+impl Viewer {
+
+    pub fn connect(address: &str) -> Result<Viewer, String> {
+        let address = &full_address(address);
+        let stream = TcpStream::connect(address)
+            .map_err(|e| format!("Can't connect to {}: {}", address, e))?;
+        let mut reader = BufReader::new(stream);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic).map_err(|e| e.to_string())?;
+        if &magic != MAGIC {
+            return Err(format!("{} isn't a rust8 broadcast", address));
+        }
+        let (sender, frames) = mpsc::channel();
+        thread::spawn(move || {
+            let mut frame = Frame::default();
+            loop {
+                let result = frame.decode(&mut reader).map(|()| frame.clone()).map_err(|e| match e.kind() {
+                    io::ErrorKind::UnexpectedEof => "The broadcast ended".to_string(),
+                    _ => format!("The broadcast failed: {}", e)
+                });
+                let failed = result.is_err();
+                if sender.send(result).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Viewer { frames })
+    }
+
+    // The newest frame since the last poll, if the screen changed
+    pub fn poll(&mut self) -> Result<Option<Frame>, String> {
+        let mut latest = None;
+        for frame in self.frames.try_iter() {
+            latest = Some(frame?);
+        }
+        Ok(latest)
+    }
+
+}
+
+// A bare port is on this machine
+fn full_address(address: &str) -> String {
+    if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("127.0.0.1:{}", address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn frame(display: (usize, usize), lit: &[usize]) -> Frame {
+        let mut gfx = vec![0; display.0 * display.1];
+        for &pixel in lit {
+            gfx[pixel] = 1;
+        }
+        Frame { display, gfx, sound: false }
+    }
+
+    #[test]
+    fn runs_longer_than_255_round_trip() {
+        let first = Frame { gfx: vec![1; 64 * 32], sound: true, ..frame((64, 32), &[]) };
+        let message = first.encode(&Frame::default());
+        // 2048 pixels are 8 runs of 255 and one of 8
+        assert_eq!(message.len(), 9 + 2 * 9);
+        let mut viewer = Frame::default();
+        viewer.decode(&mut &message[..]).unwrap();
+        assert_eq!(viewer, first);
+
+        // Only what changed, 300 blank pixels are the same XORed
+        let mut second = first.clone();
+        second.gfx[300] = 0;
+        second.sound = false;
+        let message = second.encode(&first);
+        assert_eq!(message.len(), 9 + 2 * 10);
+        viewer.decode(&mut &message[..]).unwrap();
+        assert_eq!(viewer, second);
+    }
+
+    #[test]
+    fn a_new_size_sends_the_whole_frame() {
+        let low = frame((64, 32), &[0, 1, 2]);
+        let high = frame((128, 64), &[0, 5000]);
+        let mut viewer = Frame::default();
+        viewer.decode(&mut &low.encode(&Frame::default())[..]).unwrap();
+        // Against a blank screen and not XORed with the pixels of the old size
+        let message = high.encode(&low);
+        assert_eq!(message, high.encode(&Frame::default()));
+        viewer.decode(&mut &message[..]).unwrap();
+        assert_eq!(viewer, high);
+    }
+
+    #[test]
+    fn broken_messages_are_rejected() {
+        let first = frame((64, 32), &[7]);
+        let mut viewer = Frame::default();
+        let message = first.encode(&Frame::default());
+        let error = viewer.decode(&mut &message[..message.len() - 1]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+
+        // One pixel too many, or too few, in the first run of 7 blank ones
+        let mut long = message.clone();
+        long[9] += 1;
+        let mut short = message.clone();
+        short[9] -= 1;
+        // Bigger than any screen
+        let mut huge = message.clone();
+        huge[0..4].copy_from_slice(&[0, 1, 0, 1]);
+        for broken in &[long, short, huge] {
+            let error = viewer.decode(&mut &broken[..]).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+        assert_eq!(viewer, Frame::default());
+        viewer.decode(&mut &message[..]).unwrap();
+        assert_eq!(viewer, first);
+    }
+
+}
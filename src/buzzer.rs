@@ -0,0 +1,32 @@
+// The buzzer as a 440Hz square wave, for everything that makes sound out
+// of the sound timer instead of just beeping
+pub const SAMPLE_RATE: u32 = 44100;
+const BUZZER_HZ: u32 = 440;
+const VOLUME: i16 = 8000;
+
+#[derive(Default)]
+pub struct Buzzer {
+    // Where the square wave is so the tone doesn't click between frames
+    phase: u32
+}
+
+impl Buzzer {
+
+    pub fn new() -> Buzzer {
+        Buzzer { phase: 0 }
+    }
+
+    // One 60Hz frame of mono samples, silence unless it's on
+    pub fn frame(&mut self, on: bool) -> Vec<i16> {
+        let half_period = SAMPLE_RATE / BUZZER_HZ / 2;
+        (0..SAMPLE_RATE / 60).map(|_| {
+            self.phase = (self.phase + 1) % (half_period * 2);
+            match (on, self.phase < half_period) {
+                (false, _) => 0,
+                (true, true) => VOLUME,
+                (true, false) => -VOLUME
+            }
+        }).collect()
+    }
+
+}
@@ -0,0 +1,211 @@
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+use crate::cpu::{Quirks, CPU};
+use crate::savestate;
+
+// The C API for hosts that aren't written in Rust, include/system8.h is
+// generated from this file with cbindgen (the capi test checks it's up to
+// date) and examples/capi.c shows how to use it. Machines are opaque
+// pointers, functions that can fail return 0 on success and -1 on error
+// with the message in system8_last_error. Null machines are ignored, any
+// other pointer has to be valid for the length given with it, which is
+// the safety contract of every function here
+pub struct Machine {
+    cpu: CPU,
+    error: CString
+}
+
+impl Machine {
+
+    fn fail(&mut self, error: String) -> i32 {
+        // Messages never have NULs in them, but just in case
+        self.error = CString::new(error.replace('\0', " ")).unwrap_or_default();
+        -1
+    }
+
+    // No opcode panics any more, but a panic must never unwind into C so
+    // this stays as a guard, the machine just halts
+    fn run(&mut self, run: impl FnOnce(&mut CPU)) -> i32 {
+        let cpu = &mut self.cpu;
+        match panic::catch_unwind(AssertUnwindSafe(|| run(cpu))) {
+            Ok(()) => 0,
+            Err(_) => {
+                self.cpu.breakexe = true;
+                self.fail(format!("The CPU crashed at {:#05x}", self.cpu.pc))
+            }
+        }
+    }
+
+}
+
+// Creates a machine with the quirks of a profile ("rust8", "chip8",
+// "schip" or "xochip", NULL is rust8) and a seed for CXNN. Returns NULL
+// if the profile doesn't exist
+#[no_mangle]
+pub unsafe extern "C" fn system8_create(profile: *const c_char, seed: u64) -> *mut Machine {
+    let quirks = if profile.is_null() {
+        Some(Quirks::default())
+    } else {
+        CStr::from_ptr(profile).to_str().ok().and_then(Quirks::from_profile)
+    };
+    match quirks {
+        Some(quirks) => {
+            let mut cpu = CPU::initialize();
+            cpu.quirks = quirks;
+            cpu.reseed(seed);
+            cpu.load_fontset();
+            Box::into_raw(Box::new(Machine { cpu, error: CString::default() }))
+        },
+        None => ptr::null_mut()
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn system8_destroy(machine: *mut Machine) {
+    if !machine.is_null() {
+        drop(Box::from_raw(machine));
+    }
+}
+
+// The message of the last error, owned by the machine and valid until the
+// next call that fails
+#[no_mangle]
+pub unsafe extern "C" fn system8_last_error(machine: *const Machine) -> *const c_char {
+    match machine.as_ref() {
+        Some(machine) => machine.error.as_ptr(),
+        None => b"No machine\0".as_ptr() as *const c_char
+    }
+}
+
+// Powers on again with the ROM, keeping the quirks and the seed
+#[no_mangle]
+pub unsafe extern "C" fn system8_load_rom(machine: *mut Machine, rom: *const u8, len: usize) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return -1
+    };
+    if rom.is_null() {
+        return machine.fail("The ROM is NULL".to_string());
+    }
+    let mut cpu = CPU::initialize();
+    cpu.quirks = machine.cpu.quirks;
+    cpu.reseed(machine.cpu.seed);
+    cpu.load_fontset();
+    match cpu.load_rom(slice::from_raw_parts(rom, len)) {
+        Ok(()) => {
+            machine.cpu = cpu;
+            0
+        },
+        Err(error) => machine.fail(error)
+    }
+}
+
+// Runs single instructions without ticking the timers
+#[no_mangle]
+pub unsafe extern "C" fn system8_step(machine: *mut Machine, instructions: u32) -> i32 {
+    match machine.as_mut() {
+        Some(machine) => machine.run(|cpu| {
+            for _ in 0..instructions {
+                if cpu.breakexe {
+                    break;
+                }
+                cpu.fetch_opcode();
+            }
+        }),
+        None => -1
+    }
+}
+
+// Runs one 60Hz frame of `speed` instructions and ticks the timers
+#[no_mangle]
+pub unsafe extern "C" fn system8_run_frame(machine: *mut Machine, speed: u32) -> i32 {
+    match machine.as_mut() {
+        Some(machine) => machine.run(|cpu| cpu.run_frame(speed)),
+        None => -1
+    }
+}
+
+// Keys go from 0 to 15, others are ignored
+#[no_mangle]
+pub unsafe extern "C" fn system8_set_key(machine: *mut Machine, key: u8, pressed: bool) {
+    if let Some(machine) = machine.as_mut() {
+        if key <= 0xF {
+            machine.cpu.key.set(key, pressed);
+        }
+    }
+}
+
+// One byte per pixel, rows first, system8_width * system8_height of them.
+// The pointer is valid until the machine runs again or is destroyed
+#[no_mangle]
+pub unsafe extern "C" fn system8_framebuffer(machine: *const Machine) -> *const u8 {
+    match machine.as_ref() {
+        Some(machine) => machine.cpu.gfx.as_ptr(),
+        None => ptr::null()
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn system8_width(machine: *const Machine) -> usize {
+    machine.as_ref().map_or(0, |machine| machine.cpu.display_size().0)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn system8_height(machine: *const Machine) -> usize {
+    machine.as_ref().map_or(0, |machine| machine.cpu.display_size().1)
+}
+
+// Whether the buzzer is on
+#[no_mangle]
+pub unsafe extern "C" fn system8_sound(machine: *const Machine) -> bool {
+    machine.as_ref().is_some_and(|machine| machine.cpu.sound_timer > 0)
+}
+
+// Whether the CPU stopped on an unknown opcode or crashed
+#[no_mangle]
+pub unsafe extern "C" fn system8_halted(machine: *const Machine) -> bool {
+    machine.as_ref().is_none_or(|machine| machine.cpu.breakexe)
+}
+
+// How many bytes system8_save_state needs
+#[no_mangle]
+pub unsafe extern "C" fn system8_state_size(machine: *const Machine) -> usize {
+    machine.as_ref().map_or(0, |machine| savestate::save(&machine.cpu).len())
+}
+
+// Writes the whole state into buffer, which has to be at least
+// system8_state_size bytes long
+#[no_mangle]
+pub unsafe extern "C" fn system8_save_state(machine: *mut Machine, buffer: *mut u8, len: usize) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return -1
+    };
+    let state = savestate::save(&machine.cpu);
+    if buffer.is_null() || len < state.len() {
+        return machine.fail(format!("The save state needs {} bytes", state.len()));
+    }
+    ptr::copy_nonoverlapping(state.as_ptr(), buffer, state.len());
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn system8_load_state(machine: *mut Machine, state: *const u8, len: usize) -> i32 {
+    let machine = match machine.as_mut() {
+        Some(machine) => machine,
+        None => return -1
+    };
+    if state.is_null() {
+        return machine.fail("The save state is NULL".to_string());
+    }
+    match savestate::load(&mut machine.cpu, slice::from_raw_parts(state, len)) {
+        Ok(()) => 0,
+        Err(error) => machine.fail(error)
+    }
+}
@@ -0,0 +1,476 @@
+// The RCA CDP1802, the CPU of the COSMAC VIP. Sixteen 16 bit registers and
+// any of them can be the program counter (P) or the data pointer (X), an
+// 8 bit accumulator (D) with a carry (DF) and a single output line (Q).
+// Instructions take 2 machine cycles, long branches and skips 3. DMA and
+// interrupts are only taken between instructions, which is all the 1861
+// needs, so the machine around it calls dma_out and interrupt when it's time
+
+// What the CPU is wired to
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, value: u8);
+    // INP 1 to 7, the byte also goes to memory
+    fn input(&mut self, port: u8) -> u8;
+    // OUT 1 to 7
+    fn output(&mut self, port: u8, value: u8);
+    // The four EF flags, true when asserted
+    fn flag(&mut self, flag: u8) -> bool;
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub p: u8,
+    pub x: u8,
+    pub d: u8,
+    pub df: bool,
+    // X and P saved by an interrupt
+    pub t: u8,
+    pub ie: bool,
+    pub q: bool,
+    // After IDL, until a DMA or an interrupt
+    pub idle: bool
+}
+
+impl Cdp1802 {
+
+    // What a reset leaves, P, X and R0 at zero so it starts at 0000
+    pub fn new() -> Cdp1802 {
+        Cdp1802 { r: [0; 16], p: 0, x: 0, d: 0, df: false, t: 0, ie: true, q: false, idle: false }
+    }
+
+    // Runs one instruction and returns the machine cycles it took
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 2;
+        }
+        let opcode = self.fetch(bus);
+        let n = (opcode & 0xF) as usize;
+        let x = self.x as usize;
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[n]),
+            0x1 => self.r[n] = self.r[n].wrapping_add(1),
+            0x2 => self.r[n] = self.r[n].wrapping_sub(1),
+            0x3 => {
+                let condition = self.condition(bus, opcode);
+                self.short_branch(bus, condition);
+            },
+            0x4 => {
+                self.d = bus.read(self.r[n]);
+                self.r[n] = self.r[n].wrapping_add(1);
+            },
+            0x5 => bus.write(self.r[n], self.d),
+            0x6 => self.io(bus, n as u8),
+            0x7 => self.misc(bus, opcode),
+            0x8 => self.d = self.r[n] as u8,
+            0x9 => self.d = (self.r[n] >> 8) as u8,
+            0xA => self.r[n] = (self.r[n] & 0xFF00) | self.d as u16,
+            0xB => self.r[n] = (self.r[n] & 0x00FF) | (self.d as u16) << 8,
+            0xC => {
+                self.long(bus, opcode);
+                return 3;
+            },
+            0xD => self.p = n as u8,
+            0xE => self.x = n as u8,
+            _ => {
+                // F8 to FF take an immediate byte instead of M(R(X)), but
+                // the shifts have no operand at all
+                let operand = match n {
+                    0x6 | 0xE => 0,
+                    0x8..=0xF => self.fetch(bus),
+                    _ => bus.read(self.r[x])
+                };
+                self.alu(n & 7, operand, n & 8 != 0);
+            }
+        }
+        2
+    }
+
+    // Saves X and P in T and jumps to R1 with R2 as the stack, interrupts
+    // stay off until RET. Takes one machine cycle
+    pub fn interrupt(&mut self) {
+        self.t = self.x << 4 | self.p;
+        self.x = 2;
+        self.p = 1;
+        self.ie = false;
+        self.idle = false;
+    }
+
+    // A DMA out cycle, what R0 points to goes to the device
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let byte = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        byte
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let p = self.p as usize;
+        let byte = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        byte
+    }
+
+    // 3N and CN branch on the low 3 bits, bit 3 negates
+    fn condition<B: Bus>(&mut self, bus: &mut B, opcode: u8) -> bool {
+        let holds = match opcode & 7 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            3 => self.df,
+            flag => bus.flag(flag - 3)
+        };
+        holds != (opcode & 8 != 0)
+    }
+
+    // The target replaces the low byte of the address of the target itself
+    fn short_branch<B: Bus>(&mut self, bus: &mut B, condition: bool) {
+        let p = self.p as usize;
+        if condition {
+            let target = bus.read(self.r[p]);
+            self.r[p] = (self.r[p] & 0xFF00) | target as u16;
+        } else {
+            self.r[p] = self.r[p].wrapping_add(1);
+        }
+    }
+
+    fn long<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let p = self.p as usize;
+        // C4 is NOP, the others with bit 2 set are skips
+        let (skip, condition) = match opcode {
+            0xC4 => (true, false),
+            0xC5 => (true, !self.q),
+            0xC6 => (true, self.d != 0),
+            0xC7 => (true, !self.df),
+            0xC8 => (true, true),
+            0xCC => (true, self.ie),
+            0xCD => (true, self.q),
+            0xCE => (true, self.d == 0),
+            0xCF => (true, self.df),
+            _ => (false, self.condition(bus, opcode))
+        };
+        if skip {
+            if condition {
+                self.r[p] = self.r[p].wrapping_add(2);
+            }
+        } else if condition {
+            let high = bus.read(self.r[p]) as u16;
+            let low = bus.read(self.r[p].wrapping_add(1)) as u16;
+            self.r[p] = high << 8 | low;
+        } else {
+            self.r[p] = self.r[p].wrapping_add(2);
+        }
+    }
+
+    // 61 to 67 write M(R(X)) to a port and move X on, 69 to 6F read one
+    // into M(R(X)) and D. 60 is IRX and 68 does nothing on the 1802
+    fn io<B: Bus>(&mut self, bus: &mut B, n: u8) {
+        let x = self.x as usize;
+        match n {
+            0 => self.r[x] = self.r[x].wrapping_add(1),
+            1..=7 => {
+                let value = bus.read(self.r[x]);
+                bus.output(n, value);
+                self.r[x] = self.r[x].wrapping_add(1);
+            },
+            8 => {},
+            _ => {
+                let value = bus.input(n - 8);
+                bus.write(self.r[x], value);
+                self.d = value;
+            }
+        }
+    }
+
+    fn misc<B: Bus>(&mut self, bus: &mut B, opcode: u8) {
+        let x = self.x as usize;
+        match opcode {
+            // RET and DIS
+            0x70 | 0x71 => {
+                let value = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0xF;
+                self.ie = opcode == 0x70;
+            },
+            // LDXA
+            0x72 => {
+                self.d = bus.read(self.r[x]);
+                self.r[x] = self.r[x].wrapping_add(1);
+            },
+            // STXD
+            0x73 => {
+                bus.write(self.r[x], self.d);
+                self.r[x] = self.r[x].wrapping_sub(1);
+            },
+            // SHRC
+            0x76 => {
+                let carry = self.d & 1 != 0;
+                self.d = self.d >> 1 | (self.df as u8) << 7;
+                self.df = carry;
+            },
+            // SHLC
+            0x7E => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as u8;
+                self.df = carry;
+            },
+            // SAV
+            0x78 => bus.write(self.r[x], self.t),
+            // MARK
+            0x79 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            },
+            0x7A => self.q = false,
+            0x7B => self.q = true,
+            // ADC, SDB and SMB and the immediate ADCI, SDBI and SMBI
+            _ => {
+                let immediate = opcode & 8 != 0;
+                let operand = if immediate { self.fetch(bus) } else { bus.read(self.r[x]) };
+                match opcode & 7 {
+                    4 => self.add(operand, self.df),
+                    5 => self.subtract(operand, self.d, self.df),
+                    _ => self.subtract(self.d, operand, self.df)
+                }
+            }
+        }
+    }
+
+    // F0 to F7 with M(R(X)) and F8 to FF with an immediate byte, except F0
+    // and F8 which are LDX and LDI
+    fn alu(&mut self, operation: usize, operand: u8, immediate: bool) {
+        match operation {
+            0 => self.d = operand,
+            1 => self.d |= operand,
+            2 => self.d &= operand,
+            3 => self.d ^= operand,
+            4 => self.add(operand, false),
+            5 => self.subtract(operand, self.d, true),
+            // SHR and SHL don't have an operand, FE is SHL and F6 SHR
+            6 if !immediate => {
+                self.df = self.d & 1 != 0;
+                self.d >>= 1;
+            },
+            6 => {
+                self.df = self.d & 0x80 != 0;
+                self.d <<= 1;
+            },
+            _ => self.subtract(self.d, operand, true)
+        }
+    }
+
+    fn add(&mut self, operand: u8, carry: bool) {
+        let sum = self.d as u16 + operand as u16 + carry as u16;
+        self.d = sum as u8;
+        self.df = sum > 0xFF;
+    }
+
+    // DF is set when there was no borrow, carry is that DF going in
+    fn subtract(&mut self, from: u8, operand: u8, carry: bool) {
+        let difference = from as i16 - operand as i16 - !carry as i16;
+        self.d = difference as u8;
+        self.df = difference >= 0;
+    }
+
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Cdp1802 {
+        Cdp1802::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // 64KB of RAM, one byte waiting on every input port and a record of
+    // what was written to the output ports
+    struct TestBus {
+        memory: Vec<u8>,
+        inputs: [u8; 8],
+        outputs: Vec<(u8, u8)>,
+        flags: [bool; 4]
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+        }
+
+        fn input(&mut self, port: u8) -> u8 {
+            self.inputs[port as usize]
+        }
+
+        fn output(&mut self, port: u8, value: u8) {
+            self.outputs.push((port, value));
+        }
+
+        fn flag(&mut self, flag: u8) -> bool {
+            self.flags[flag as usize - 1]
+        }
+    }
+
+    // The program goes at 0100 and runs with R3 as P, R2 as X points at
+    // 0200
+    fn setup(program: &[u8]) -> (Cdp1802, TestBus) {
+        let mut bus = TestBus { memory: vec![0; 0x10000], inputs: [0; 8], outputs: Vec::new(), flags: [false; 4] };
+        bus.memory[0x100..0x100 + program.len()].copy_from_slice(program);
+        let mut cpu = Cdp1802::new();
+        cpu.p = 3;
+        cpu.r[3] = 0x100;
+        cpu.x = 2;
+        cpu.r[2] = 0x200;
+        (cpu, bus)
+    }
+
+    // D and DF after running opcode with D, DF and M(R(X)) set
+    fn subtract(opcode: u8, d: u8, df: bool, operand: u8) -> (u8, bool) {
+        let (mut cpu, mut bus) = setup(&[opcode]);
+        cpu.d = d;
+        cpu.df = df;
+        bus.memory[0x200] = operand;
+        cpu.step(&mut bus);
+        (cpu.d, cpu.df)
+    }
+
+    #[test]
+    fn subtractions_leave_df_set_without_a_borrow() {
+        // SD is M - D and SM is D - M
+        assert_eq!(subtract(0xF5, 3, false, 5), (2, true));
+        assert_eq!(subtract(0xF5, 5, false, 3), (0xFE, false));
+        assert_eq!(subtract(0xF7, 5, false, 3), (2, true));
+        assert_eq!(subtract(0xF7, 3, true, 5), (0xFE, false));
+        assert_eq!(subtract(0xF7, 3, false, 3), (0, true));
+        // SDB and SMB also take one more when DF says there was a borrow
+        assert_eq!(subtract(0x75, 3, true, 5), (2, true));
+        assert_eq!(subtract(0x75, 3, false, 5), (1, true));
+        assert_eq!(subtract(0x75, 5, false, 5), (0xFF, false));
+        assert_eq!(subtract(0x77, 5, true, 3), (2, true));
+        assert_eq!(subtract(0x77, 3, false, 3), (0xFF, false));
+        assert_eq!(subtract(0x77, 0, false, 0xFF), (0, false));
+    }
+
+    #[test]
+    fn shifts_through_df() {
+        // SHRC, SHLC, SHR and SHL, the last two don't take a byte
+        let (mut cpu, mut bus) = setup(&[0x76, 0x7E, 0xF6, 0xFE, 0xFE]);
+        cpu.d = 0x81;
+        cpu.step(&mut bus);
+        assert_eq!((cpu.d, cpu.df), (0x40, true));
+        cpu.step(&mut bus);
+        assert_eq!((cpu.d, cpu.df), (0x81, false));
+        cpu.step(&mut bus);
+        assert_eq!((cpu.d, cpu.df), (0x40, true));
+        cpu.step(&mut bus);
+        assert_eq!((cpu.d, cpu.df), (0x80, false));
+        cpu.step(&mut bus);
+        assert_eq!((cpu.d, cpu.df), (0, true));
+        assert_eq!(cpu.r[3], 0x105);
+    }
+
+    #[test]
+    fn long_branches_and_skips() {
+        // Where every CN goes with Q, DF and IE set and D zero, and then
+        // with all of them the other way: branch to 1234, skip the next
+        // two bytes (+3) or go on (+1)
+        const BRANCH: u16 = 0x1234;
+        const SKIP: u16 = 0x103;
+        const ON: u16 = 0x101;
+        let taken = [BRANCH, BRANCH, BRANCH, BRANCH, ON, ON, ON, ON, SKIP, SKIP, SKIP, SKIP, SKIP, SKIP, SKIP, SKIP];
+        let not_taken = [BRANCH, SKIP, SKIP, SKIP, ON, SKIP, SKIP, SKIP, SKIP, BRANCH, BRANCH, BRANCH, ON, ON, ON, ON];
+        for (set, expected) in [(true, taken), (false, not_taken)] {
+            for (opcode, &next) in (0xC0..=0xCF).zip(expected.iter()) {
+                let (mut cpu, mut bus) = setup(&[opcode, 0x12, 0x34]);
+                cpu.q = set;
+                cpu.df = set;
+                cpu.ie = set;
+                cpu.d = if set { 0 } else { 1 };
+                assert_eq!(cpu.step(&mut bus), 3);
+                assert_eq!(cpu.r[3], next, "{:02X} with the flags {}", opcode, set);
+            }
+        }
+    }
+
+    #[test]
+    fn short_branches_stay_in_the_page_of_their_target_byte() {
+        let (mut cpu, mut bus) = setup(&[]);
+        cpu.r[3] = 0x1FF;
+        // BR with its byte on the next page
+        bus.memory[0x1FF] = 0x30;
+        bus.memory[0x200] = 0x40;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.r[3], 0x240);
+        // B4 not taken skips the byte
+        bus.memory[0x240] = 0x37;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.r[3], 0x242);
+        bus.flags[3] = true;
+        cpu.r[3] = 0x240;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.r[3], 0x200);
+    }
+
+    #[test]
+    fn interrupts_save_x_and_p_and_ret_restores_them() {
+        // The routine at 0300: DEC R2, SAV, RET
+        let (mut cpu, mut bus) = setup(&[]);
+        bus.memory[0x300..0x303].copy_from_slice(&[0x22, 0x78, 0x70]);
+        cpu.x = 5;
+        cpu.r[1] = 0x300;
+        cpu.r[2] = 0x0FF;
+        cpu.interrupt();
+        assert_eq!((cpu.t, cpu.x, cpu.p, cpu.ie), (0x53, 2, 1, false));
+        for _ in 0..3 {
+            cpu.step(&mut bus);
+        }
+        assert_eq!(bus.memory[0x0FE], 0x53);
+        assert_eq!((cpu.x, cpu.p, cpu.ie, cpu.r[2], cpu.r[3]), (5, 3, true, 0x0FF, 0x100));
+    }
+
+    #[test]
+    fn mark_pushes_x_and_p() {
+        let (mut cpu, mut bus) = setup(&[0x79]);
+        cpu.x = 5;
+        cpu.r[2] = 0x80;
+        cpu.step(&mut bus);
+        assert_eq!((cpu.t, bus.memory[0x80], cpu.x, cpu.r[2]), (0x53, 0x53, 3, 0x7F));
+    }
+
+    #[test]
+    fn inp_writes_memory_and_out_reads_it() {
+        // INP 1 and OUT 2
+        let (mut cpu, mut bus) = setup(&[0x69, 0x62]);
+        bus.inputs[1] = 0xAB;
+        cpu.step(&mut bus);
+        assert_eq!((cpu.d, bus.memory[0x200], cpu.r[2]), (0xAB, 0xAB, 0x200));
+        cpu.step(&mut bus);
+        assert_eq!((bus.outputs[0], cpu.r[2]), ((2, 0xAB), 0x201));
+    }
+
+    #[test]
+    fn idle_waits_for_dma() {
+        let (mut cpu, mut bus) = setup(&[0x00, 0x7B]);
+        bus.memory[0x400] = 0x55;
+        cpu.r[0] = 0x400;
+        cpu.step(&mut bus);
+        assert!(cpu.idle);
+        assert_eq!(cpu.step(&mut bus), 2);
+        assert_eq!(cpu.r[3], 0x101);
+        assert_eq!(cpu.dma_out(&mut bus), 0x55);
+        assert!(!cpu.idle);
+        assert_eq!(cpu.r[0], 0x401);
+        cpu.step(&mut bus);
+        assert!(cpu.q);
+    }
+
+}
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::cpu::Quirks;
+use crate::options::Options;
+use crate::palette::Palette;
+use crate::romdb;
+
+// The original keypad layout mapped to the left side of a QWERTY keyboard
+//   1 2 3 C        1 2 3 4
+//   4 5 6 D   ->   Q W E R
+//   7 8 9 E        A S D F
+//   A 0 B F        Z X C V
+pub const DEFAULT_KEYMAP: [&str; 16] = [
+    "X", "1", "2", "3", "Q", "W", "E", "A",
+    "S", "D", "Z", "C", "4", "R", "F", "V"
+];
+
+// Every setting is optional so a section only has to mention what it changes
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Section {
+    pub speed: Option<u32>,
+    pub scale: Option<u32>,
+    pub integer_scale: Option<bool>,
+    pub fullscreen: Option<bool>,
+    pub phosphor: Option<f32>,
+    pub frame_sync: Option<bool>,
+    pub screenshot_dir: Option<PathBuf>,
+    pub screenshot_scale: Option<u32>,
+    pub palette: PaletteConfig,
+    // CHIP-8 key ("0" to "F") to SDL key name
+    pub keymap: HashMap<String, String>,
+    pub quirks: QuirksConfig
+}
+
+// A built-in theme and then the colours that change from it
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct PaletteConfig {
+    pub theme: Option<String>,
+    // Up to 4 colours for XO-CHIP, background first
+    pub colours: Vec<String>,
+    pub foreground: Option<String>,
+    pub background: Option<String>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct QuirksConfig {
+    pub profile: Option<String>,
+    pub shift: Option<bool>,
+    pub memory: Option<bool>,
+    pub logic: Option<bool>,
+    pub wrap: Option<bool>,
+    pub timing: Option<bool>,
+    pub release: Option<bool>
+}
+
+// The config file: the defaults at the top level and then a [rom.<sha1>]
+// table for every game that needs something different, like:
+//
+//   speed = 10
+//   romdb = "/path/to/chip8-database.json"
+//   [rom.0123456789abcdef0123456789abcdef01234567]
+//   speed = 20
+//   quirks = { profile = "chip8" }
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    #[serde(flatten)]
+    pub defaults: Section,
+    pub rom: HashMap<String, Section>,
+    // See romdb::RomDb
+    pub romdb: Option<PathBuf>
+}
+
+impl Config {
+
+    // Usually ~/.config/rust8/config.toml
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rust8").join("config.toml"))
+    }
+
+    // An explicit path must exist but the default one is optional
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(Config::default())
+            }
+        };
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    pub fn rom_section(&self, sha1: &str) -> Option<&Section> {
+        self.rom.iter()
+            .find(|(hash, _)| hash.eq_ignore_ascii_case(sha1))
+            .map(|(_, section)| section)
+    }
+
+}
+
+// What the frontends actually use once the built-in defaults, the config
+// file, the ROM specific section and the command line have been merged
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub speed: u32,
+    // Initial window size as a multiple of the screen
+    pub scale: u32,
+    // Only scale the screen by whole numbers when the window is resized
+    pub integer_scale: bool,
+    pub fullscreen: bool,
+    // Fraction of the brightness a pixel keeps every frame after it's
+    // turned off, 0 disables the phosphor filter
+    pub phosphor: f32,
+    // Present the screen once per frame instead of after every draw
+    pub frame_sync: bool,
+    // Where F12 saves screenshots and F9 recordings and how big they are,
+    // 1 is the native resolution of the screen
+    pub screenshot_dir: PathBuf,
+    pub screenshot_scale: u32,
+    pub palette: Palette,
+    // Every CHIP-8 key can be bound to more than one SDL key name
+    pub keymap: [Vec<String>; 16],
+    pub quirks: Quirks
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            speed: 10,
+            scale: 10,
+            integer_scale: false,
+            fullscreen: false,
+            phosphor: 0.0,
+            frame_sync: false,
+            screenshot_dir: PathBuf::from("."),
+            screenshot_scale: 1,
+            palette: Palette::default(),
+            keymap: DEFAULT_KEYMAP.map(|name| vec![name.to_string()]),
+            quirks: Quirks::default()
+        }
+    }
+}
+
+impl Settings {
+
+    // The quirks guessed by the analyzer are the starting point, the ROM
+    // database entry sits between the defaults of the config file and the
+    // section written by the user for this very ROM
+    pub fn resolve(config: &Config, guessed: Quirks, entry: Option<&romdb::Entry>,
+                   sha1: &str, options: &Options) -> Result<Settings, String> {
+        let mut settings = Settings { quirks: guessed, ..Settings::default() };
+        settings.apply(&config.defaults)?;
+        if let Some(entry) = entry {
+            entry.apply(&mut settings)?;
+        }
+        if let Some(section) = config.rom_section(sha1) {
+            settings.apply(section)?;
+        }
+        if let Some(speed) = options.speed {
+            settings.speed = speed;
+        }
+        if let Some(scale) = options.scale {
+            settings.scale = scale;
+        }
+        if let Some(integer_scale) = options.integer_scale {
+            settings.integer_scale = integer_scale;
+        }
+        if let Some(fullscreen) = options.fullscreen {
+            settings.fullscreen = fullscreen;
+        }
+        if let Some(phosphor) = options.phosphor {
+            settings.phosphor = phosphor;
+        }
+        if let Some(frame_sync) = options.frame_sync {
+            settings.frame_sync = frame_sync;
+        }
+        if let Some(dir) = &options.screenshot_dir {
+            settings.screenshot_dir = dir.clone();
+        }
+        if let Some(scale) = options.screenshot_scale {
+            settings.screenshot_scale = scale;
+        }
+        if let Some(profile) = &options.quirks {
+            settings.quirks = quirks_profile(profile)?;
+        }
+        if let Some(theme) = &options.theme {
+            settings.palette = Palette::theme(theme)?;
+        }
+        if let Some(colour) = &options.foreground {
+            settings.palette.colours[1] = parse_colour(colour)?;
+        }
+        if let Some(colour) = &options.background {
+            settings.palette.colours[0] = parse_colour(colour)?;
+        }
+        if settings.speed == 0 || settings.scale == 0 || settings.screenshot_scale == 0 {
+            return Err("Speed and scales must be at least 1".to_string());
+        }
+        if !(0.0..1.0).contains(&settings.phosphor) {
+            return Err("Phosphor decay must be between 0 and 1".to_string());
+        }
+        Ok(settings)
+    }
+
+    pub fn apply(&mut self, section: &Section) -> Result<(), String> {
+        if let Some(speed) = section.speed {
+            self.speed = speed;
+        }
+        if let Some(scale) = section.scale {
+            self.scale = scale;
+        }
+        if let Some(integer_scale) = section.integer_scale {
+            self.integer_scale = integer_scale;
+        }
+        if let Some(fullscreen) = section.fullscreen {
+            self.fullscreen = fullscreen;
+        }
+        if let Some(phosphor) = section.phosphor {
+            self.phosphor = phosphor;
+        }
+        if let Some(frame_sync) = section.frame_sync {
+            self.frame_sync = frame_sync;
+        }
+        if let Some(dir) = &section.screenshot_dir {
+            self.screenshot_dir = dir.clone();
+        }
+        if let Some(scale) = section.screenshot_scale {
+            self.screenshot_scale = scale;
+        }
+        let palette = &section.palette;
+        if let Some(theme) = &palette.theme {
+            self.palette = Palette::theme(theme)?;
+        }
+        self.palette.set_colours(&palette.colours)?;
+        if let Some(colour) = &palette.foreground {
+            self.palette.colours[1] = parse_colour(colour)?;
+        }
+        if let Some(colour) = &palette.background {
+            self.palette.colours[0] = parse_colour(colour)?;
+        }
+        for (key, name) in &section.keymap {
+            let index = usize::from_str_radix(key, 16)
+                .ok()
+                .filter(|&index| index < 16)
+                .ok_or_else(|| format!("Invalid CHIP-8 key in keymap: {}", key))?;
+            self.keymap[index] = vec![name.clone()];
+        }
+        // The profile goes first so single quirks can be tweaked on top of it
+        let quirks = &section.quirks;
+        if let Some(profile) = &quirks.profile {
+            self.quirks = quirks_profile(profile)?;
+        }
+        if let Some(shift) = quirks.shift {
+            self.quirks.shift = shift;
+        }
+        if let Some(memory) = quirks.memory {
+            self.quirks.memory = memory;
+        }
+        if let Some(logic) = quirks.logic {
+            self.quirks.logic = logic;
+        }
+        if let Some(wrap) = quirks.wrap {
+            self.quirks.wrap = wrap;
+        }
+        if let Some(timing) = quirks.timing {
+            self.quirks.timing = timing;
+        }
+        if let Some(release) = quirks.release {
+            self.quirks.release = release;
+        }
+        Ok(())
+    }
+
+}
+
+pub fn quirks_profile(name: &str) -> Result<Quirks, String> {
+    Quirks::from_profile(name).ok_or_else(|| {
+        format!("Unknown quirk profile {}, try one of: {}", name, Quirks::PROFILES.join(", "))
+    })
+}
+
+// Colours are written as "#RRGGBB"
+pub fn parse_colour(colour: &str) -> Result<[u8; 3], String> {
+    let hex = colour.trim_start_matches('#');
+    let value = u32::from_str_radix(hex, 16).ok().filter(|_| hex.len() == 6);
+    match value {
+        Some(value) => Ok([(value >> 16) as u8, (value >> 8) as u8, value as u8]),
+        None => Err(format!("Invalid colour {}, expected #RRGGBB", colour))
+    }
+}
+
+// ROMs are identified by the SHA-1 of their contents in lowercase hex
+pub fn rom_sha1(rom: &[u8]) -> String {
+    Sha1::digest(rom).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn resolve(config: &str, args: &[&str]) -> Settings {
+        let config: Config = toml::from_str(config).unwrap();
+        let args: Vec<String> = ["system8"].iter().chain(args).chain(&["rom.ch8"]).map(|arg| arg.to_string()).collect();
+        let options = Options::parse(&args).unwrap();
+        Settings::resolve(&config, Quirks::default(), None, "", &options).unwrap()
+    }
+
+    #[test]
+    fn flags_turn_off_what_the_config_turns_on() {
+        let config = "integer_scale = true\nfullscreen = true\nframe_sync = true";
+        let settings = resolve(config, &[]);
+        assert!(settings.integer_scale && settings.fullscreen && settings.frame_sync);
+        let settings = resolve(config, &["--no-integer-scale", "--no-fullscreen", "--no-frame-sync"]);
+        assert!(!settings.integer_scale && !settings.fullscreen && !settings.frame_sync);
+        let settings = resolve("", &["--fullscreen"]);
+        assert!(settings.fullscreen && !settings.integer_scale);
+    }
+
+}
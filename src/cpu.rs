@@ -1,48 +1,352 @@
 extern crate rand;
 
-use rand::Rng;
+use std::fmt;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::instruction::{DecodeCache, Instruction};
+use crate::timing;
+
+// Behaviours that changed between CHIP-8 interpreters over the years, every
+// game expects one flavour or another so we need to be able to toggle them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift VX in place and ignore VY (SCHIP)
+    pub shift: bool,
+    // FX55 and FX65 leave I pointing after the last register (COSMAC VIP)
+    pub memory: bool,
+    // 8XY1, 8XY2 and 8XY3 reset VF to zero (COSMAC VIP)
+    pub logic: bool,
+    // Sprites wrap around the screen edges instead of being clipped
+    pub wrap: bool,
+    // Instructions take as long as they did and DXYN waits for the next
+    // frame, the speed doesn't matter then (COSMAC VIP)
+    pub timing: bool,
+    // FX0A waits for the key to be released and not just pressed, so a key
+    // still held after the last FX0A doesn't count again (COSMAC VIP)
+    pub release: bool
+}
+
+impl Quirks {
+
+    // The names used by the config file and the --quirks flag
+    pub const PROFILES: [&'static str; 5] = ["rust8", "chip8", "schip", "xochip", "vip"];
+
+    pub fn from_profile(name: &str) -> Option<Quirks> {
+        match name {
+            "rust8" => Some(Quirks::default()),
+            "chip8" => Some(Quirks { shift: false, memory: true, logic: true, wrap: false, timing: false, release: true }),
+            "schip" => Some(Quirks { shift: true, memory: false, logic: false, wrap: false, timing: false, release: true }),
+            "xochip" => Some(Quirks { shift: false, memory: true, logic: false, wrap: true, timing: false, release: true }),
+            // chip8 running as slow as it did
+            "vip" => Some(Quirks { shift: false, memory: true, logic: true, wrap: false, timing: true, release: true }),
+            _ => None
+        }
+    }
+
+    // One bit per quirk, for save states and netplay
+    pub fn bits(&self) -> u8 {
+        self.shift as u8 | (self.memory as u8) << 1 | (self.logic as u8) << 2 | (self.wrap as u8) << 3 |
+            (self.timing as u8) << 4 | (self.release as u8) << 5
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift: bits & 1 != 0,
+            memory: bits & 2 != 0,
+            logic: bits & 4 != 0,
+            wrap: bits & 8 != 0,
+            timing: bits & 16 != 0,
+            release: bits & 32 != 0
+        }
+    }
+
+}
+
+impl Default for Quirks {
+    // What this emulator always did, it passes the BC test ROM
+    fn default() -> Quirks {
+        Quirks { shift: true, memory: false, logic: false, wrap: true, timing: false, release: true }
+    }
+}
+
+// The CHIP-8 fontset
+pub static CHIP8_FONTSET: [u8; 80] =
+[
+    0xF0, 0x90, 0x90, 0x90, 0xF0, //0
+    0x20, 0x60, 0x20, 0x20, 0x70, //1
+    0xF0, 0x10, 0xF0, 0x80, 0xF0, //2
+    0xF0, 0x10, 0xF0, 0x10, 0xF0, //3
+    0x90, 0x90, 0xF0, 0x10, 0x10, //4
+    0xF0, 0x80, 0xF0, 0x10, 0xF0, //5
+    0xF0, 0x80, 0xF0, 0x90, 0xF0, //6
+    0xF0, 0x10, 0x20, 0x40, 0x40, //7
+    0xF0, 0x90, 0xF0, 0x90, 0xF0, //8
+    0xF0, 0x90, 0xF0, 0x10, 0xF0, //9
+    0xF0, 0x90, 0xF0, 0x90, 0x90, //A
+    0xE0, 0x90, 0xE0, 0x90, 0xE0, //B
+    0xF0, 0x80, 0x80, 0x80, 0xF0, //C
+    0xE0, 0x90, 0x90, 0x90, 0xE0, //D
+    0xF0, 0x80, 0xF0, 0x80, 0xF0, //E
+    0xF0, 0x80, 0xF0, 0x80, 0x80  //F
+];
+
+// A fresh ChaCha8Rng underflows in get_word_pos until it has filled its
+// buffer once, which panics in debug builds, setting the position makes
+// it do that without changing the numbers that come out
+pub fn seeded_rng(seed: u64) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_word_pos(0);
+    rng
+}
+
+// CHIP-8 has 4KB, XO-CHIP would make it 64KB and addresses 16 bits
+pub const MEMORY_SIZE: usize = 4096;
+
+// An address in memory, 12 bits for the 4KB of CHIP-8. It can't point
+// outside memory, adding to it wraps around the end like on the VIP
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Address(u16);
+
+impl Address {
+
+    pub fn new(address: usize) -> Address {
+        Address((address % MEMORY_SIZE) as u16)
+    }
+
+    pub fn get(self) -> u16 {
+        self.0
+    }
+
+    // For indexing memory
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+
+    pub fn offset(self, bytes: usize) -> Address {
+        Address::new(self.index() + bytes)
+    }
+
+}
+
+impl fmt::LowerHex for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+// The 16 keys of the hex keypad, one bit each and key 0 is the lowest
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Keypad(u16);
+
+impl Keypad {
+
+    pub fn new(keys: u16) -> Keypad {
+        Keypad(keys)
+    }
+
+    pub fn bits(self) -> u16 {
+        self.0
+    }
+
+    // Only the low nibble picks the key, like EX9E and EXA1 on the VIP
+    pub fn pressed(self, key: u8) -> bool {
+        (self.0 >> (key & 0xF)) & 1 != 0
+    }
+
+    pub fn set(&mut self, key: u8, pressed: bool) {
+        let bit = 1 << (key & 0xF);
+        if pressed {
+            self.0 |= bit;
+        } else {
+            self.0 &= !bit;
+        }
+    }
+
+    // The highest key held, if any
+    pub fn highest(self) -> Option<u8> {
+        (0..16).rev().find(|&key| self.pressed(key))
+    }
+
+}
 
+// Every register has the width it has on real hardware, V and the timers
+// are bytes and the addresses wrap around memory, so no opcode can leave
+// anything out of range
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
 pub struct CPU {
-    pub opcode: usize,
-    pub pc: usize,
-    pub memory: [u8; 4096],
-    pub v: [usize; 16],
-    pub i: usize,
+    pub opcode: u16,
+    pub pc: Address,
+    // Only written through write_memory so the cache stays in sync
+    memory: [u8; MEMORY_SIZE],
+    pub v: [u8; 16],
+    pub i: Address,
     pub gfx: [u8; 2048], // 64 * 32 pixels
-    pub delay_timer: usize,
-    pub sound_timer: usize,
-    pub stack: [usize; 16],
-    pub key: [usize; 16],
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    pub stack: [Address; 16],
+    pub key: Keypad,
+    // The key FX0A saw held, with the release quirk it waits for it to go up
+    pub key_wait: Option<u8>,
+    // FX0A has no key yet, running it again this frame would change nothing
+    pub waiting: bool,
     pub sp: usize,
     pub draw: bool,
-    pub breakexe: bool
+    pub breakexe: bool,
+    // Why it stopped, the library never prints so the frontends show it
+    pub halted: Option<String>,
+    pub quirks: Quirks,
+    // Machine cycles left until the next interrupt with the timing quirk,
+    // below zero when the last instruction ran past it
+    pub cycles: i32,
+    // CXNN draws from a seeded generator so runs can be replayed exactly
+    pub seed: u64,
+    pub rng: ChaCha8Rng,
+    pub cache: DecodeCache
 }
 
 impl CPU {
 
     pub fn fetch_opcode(&mut self) {
-        self.opcode = ((self.memory[self.pc] as u16) << 8 |
-                                        self.memory[self.pc+1] as u16) as usize;
-        self.decode_opcode();
+        if let Some((opcode, instruction)) = self.cache.get(self.pc) {
+            self.opcode = opcode;
+            return self.execute(instruction);
+        }
+        self.opcode = (self.memory[self.pc.index()] as u16) << 8 |
+                                        self.memory[self.pc.offset(1).index()] as u16;
+        match Instruction::decode(self.opcode) {
+            Ok(instruction) => {
+                self.cache.insert(self.pc, self.opcode, instruction);
+                self.execute(instruction)
+            },
+            Err(_) => self.unknown()
+        }
         // There was a stack printer here but now it's gone
     }
 
+    // The same decoding every time like before the cache, the benchmark
+    // and the fuzz targets compare both
+    pub fn fetch_uncached(&mut self) {
+        self.opcode = (self.memory[self.pc.index()] as u16) << 8 |
+                                        self.memory[self.pc.offset(1).index()] as u16;
+        self.decode_opcode();
+    }
+
     pub fn initialize() -> CPU {
-        return CPU {
+        let seed = rand::thread_rng().gen();
+        CPU {
             opcode: 0,
-            pc: 0x200,
-            v: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            memory: [0u8; 4096],
+            pc: Address::new(0x200),
+            v: [0; 16],
+            memory: [0u8; MEMORY_SIZE],
             gfx: [0; 2048],
-            i: 0,
+            i: Address::default(),
             delay_timer: 0,
             sound_timer: 0,
-            stack: [0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
-            key:[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0],
+            stack: [Address::default(); 16],
+            key: Keypad::default(),
+            key_wait: None,
+            waiting: false,
             sp: 0,
             draw: false,
-            breakexe: false
-        };
+            breakexe: false,
+            halted: None,
+            quirks: Quirks::default(),
+            cycles: timing::BUDGET,
+            seed,
+            rng: seeded_rng(seed),
+            cache: DecodeCache::new()
+        }
+    }
+
+    // Restarts the random numbers from a known seed
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng = seeded_rng(seed);
+    }
+
+    pub fn load_fontset(&mut self) {
+        self.write_memory(0, &CHIP8_FONTSET);
+    }
+
+    // Programs start at 0x200, below that was the interpreter itself
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), String> {
+        if rom.len() > MEMORY_SIZE - 0x200 {
+            return Err("The ROM is too big to fit in memory".to_string());
+        }
+        self.write_memory(0x200, rom);
+        Ok(())
+    }
+
+    pub fn memory(&self) -> &[u8; MEMORY_SIZE] {
+        &self.memory
+    }
+
+    // For libretro frontends, which write RAM through a pointer for cheats.
+    // Nothing decoded can be trusted after that so the cache is emptied
+    // now and has to be again before running if anything was written
+    pub fn memory_ptr(&mut self) -> *mut u8 {
+        self.cache.clear();
+        self.memory.as_mut_ptr()
+    }
+
+    // Copies the bytes in, wrapping around the end of memory, and forgets
+    // the decoded instructions they overwrote
+    pub fn write_memory(&mut self, address: usize, data: &[u8]) {
+        for (offset, &byte) in data.iter().enumerate() {
+            self.memory[(address + offset) % MEMORY_SIZE] = byte;
+        }
+        self.cache.invalidate(Address::new(address), data.len());
+    }
+
+    // Runs one 60Hz frame worth of instructions and then ticks the timers,
+    // for frontends that don't need to present after every draw
+    pub fn run_frame(&mut self, speed: u32) {
+        let mut ran = 0;
+        while !self.frame_over(ran, speed) {
+            self.fetch_opcode();
+            ran += 1;
+        }
+        if !self.breakexe {
+            self.tick_timers();
+        }
+    }
+
+    // Whether a frame that already ran that many instructions is done,
+    // with the timing quirk it's when the cycles run out and not the speed.
+    // FX0A waiting for a key ends it early so it doesn't spin
+    pub fn frame_over(&self, ran: u32, speed: u32) -> bool {
+        self.breakexe || self.waiting || if self.quirks.timing { self.cycles <= 0 } else { ran >= speed }
+    }
+
+    // Both timers count down at 60Hz. On the VIP it's the interrupt that
+    // starts a frame so it also hands out the cycles of the next one, an
+    // instruction that ran past it takes its extra cycles from them. FX0A
+    // looks at the keypad again in the next frame
+    pub fn tick_timers(&mut self) {
+        self.waiting = false;
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+        if self.quirks.timing {
+            self.cycles = self.cycles.min(0) + timing::BUDGET;
+        }
+    }
+
+    // The 16 keys as a bitmask, key 0 is the lowest bit
+    pub fn keypad(&self) -> u16 {
+        self.key.bits()
+    }
+
+    pub fn set_keypad(&mut self, keys: u16) {
+        self.key = Keypad::new(keys);
+    }
+
+    // The resolution gfx is drawn at, a SCHIP hires mode would make this
+    // 128x64 so frontends shouldn't assume 64x32
+    pub fn display_size(&self) -> (usize, usize) {
+        (64, 32)
     }
 
     // Ye olde and simple stack printer
@@ -71,17 +375,30 @@ impl CPU {
     //  print!("\n");
     //}
 
-    pub fn get_nnn(&self) -> usize {
-        return self.opcode & 0x0FFF;
+    // What the types can't hold by themselves, the fuzz targets check it
+    // after every instruction
+    pub fn invariants(&self) -> Result<(), String> {
+        if self.sp > self.stack.len() {
+            return Err(format!("SP {} is out of the stack", self.sp));
+        }
+        Ok(())
+    }
+
+    // Skips the next instruction if the condition holds
+    fn skip_if(&mut self, condition: bool) {
+        self.pc = self.pc.offset(if condition { 4 } else { 2 });
     }
 
-    pub fn get_nn(&self) -> usize {
-        return self.opcode & 0x00FF;
+    fn next(&mut self) {
+        self.pc = self.pc.offset(2);
     }
 
     // OPCODES
 
     pub fn op_00ee(&mut self){
+        if self.sp == 0 || self.sp > self.stack.len() {
+            return self.halt("Return with an empty stack");
+        }
         self.sp -= 1;
         self.pc = self.stack[self.sp];
     }
@@ -91,97 +408,90 @@ impl CPU {
             self.gfx[y] = 0;
         }
         self.draw = true;
-        self.pc += 2;
+        self.next();
     }
 
-    pub fn op_1nnn(&mut self){
-        self.pc = self.get_nnn();
+    pub fn op_1nnn(&mut self, nnn: Address){
+        self.pc = nnn;
     }
 
     // This is one of those weird opcodes that everybody
     // implements in different ways, this seems to pass tests
-    pub fn op_2nnn(&mut self){
-        self.stack[self.sp] = self.pc + 2;
+    pub fn op_2nnn(&mut self, nnn: Address){
+        if self.sp >= self.stack.len() {
+            return self.halt("Stack overflow");
+        }
+        self.stack[self.sp] = self.pc.offset(2);
         self.sp += 1;
-        self.pc = self.get_nnn();
+        self.pc = nnn;
     }
 
-    pub fn op_3xnn(&mut self, x: usize){
-        if self.v[x] == self.get_nn() {
-            self.pc += 4
-        } else {
-            self.pc += 2
-        }
+    pub fn op_3xnn(&mut self, x: usize, nn: u8){
+        self.skip_if(self.v[x] == nn)
     }
 
-    pub fn op_4xnn(&mut self, x: usize){
-        if self.v[x] != self.get_nn() {
-            self.pc += 4
-        } else {
-            self.pc += 2
-        }
+    pub fn op_4xnn(&mut self, x: usize, nn: u8){
+        self.skip_if(self.v[x] != nn)
     }
 
     pub fn op_5xy0(&mut self, x: usize, y: usize){
-        if self.v[x] == self.v[y] {
-            self.pc += 4
-        } else {
-            self.pc += 2
-        }
+        self.skip_if(self.v[x] == self.v[y])
     }
 
-    pub fn op_annn(&mut self){
-        self.i = self.get_nnn();
-        self.pc += 2
+    pub fn op_annn(&mut self, nnn: Address){
+        self.i = nnn;
+        self.next()
     }
 
-    pub fn op_6xnn(&mut self, x: usize){
-        self.v[x] = self.get_nn();
-        self.pc += 2
+    pub fn op_6xnn(&mut self, x: usize, nn: u8){
+        self.v[x] = nn;
+        self.next()
     }
 
-    pub fn op_7xnn(&mut self, x: usize){
-        let vx = self.v[x] as u16;
-        let val = self.get_nn() as u16;
-        let result = (vx + val) as u8;
-        self.v[x] = result as usize;
-        self.pc += 2
+    pub fn op_7xnn(&mut self, x: usize, nn: u8){
+        self.v[x] = self.v[x].wrapping_add(nn);
+        self.next()
     }
 
     pub fn op_8xy0(&mut self, x: usize, y: usize){
         self.v[x] = self.v[y];
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_8xy1(&mut self, x: usize, y: usize){
         self.v[x] |= self.v[y];
-        self.pc += 2
+        if self.quirks.logic {
+            self.v[0xf] = 0;
+        }
+        self.next()
     }
 
     pub fn op_8xy2(&mut self, x: usize, y: usize){
-        self.v[x] = self.v[x] & self.v[y];
-        self.pc += 2
+        self.v[x] &= self.v[y];
+        if self.quirks.logic {
+            self.v[0xf] = 0;
+        }
+        self.next()
     }
 
     pub fn op_8xy3(&mut self, x: usize, y: usize){
         self.v[x] ^= self.v[y];
-        self.pc += 2
+        if self.quirks.logic {
+            self.v[0xf] = 0;
+        }
+        self.next()
     }
 
     pub fn op_8xy4(&mut self, x: usize, y: usize){
-        // Let's be careful when we add numbers as
-        // they can overflow and give an incorrect results
-        // same as 7XNN
-        let vx = self.v[x] as u16;
-        let vy = self.v[y] as u16;
-        let result = (vx + vy) as u8;
-        self.v[x] = result as usize;
+        // Registers are bytes so the sum wraps around like 7XNN, the
+        // carry is when it came out smaller than what was added
+        self.v[x] = self.v[x].wrapping_add(self.v[y]);
         if self.v[y] > self.v[x]{
             self.v[0xf] = 1;
         } else {
             self.v[0xf] = 0;
         }
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_8xy5(&mut self, x: usize, y: usize){
@@ -191,13 +501,16 @@ impl CPU {
             self.v[0xf] = 1;
         }
         self.v[x] = self.v[x].wrapping_sub(self.v[y]);
-        self.pc += 2
+        self.next()
     }
 
-    pub fn op_8xy6(&mut self, x: usize){
+    pub fn op_8xy6(&mut self, x: usize, y: usize){
+        if !self.quirks.shift {
+            self.v[x] = self.v[y];
+        }
         self.v[0x0f] = self.v[x] & 1;
         self.v[x] >>=1;
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_8xy7(&mut self, x: usize, y: usize){
@@ -207,134 +520,139 @@ impl CPU {
             self.v[0xf] = 0
         }
         self.v[x] = self.v[y].wrapping_sub(self.v[x]);
-        self.pc += 2
+        self.next()
     }
 
-    pub fn op_8x0e(&mut self, x: usize){
+    pub fn op_8x0e(&mut self, x: usize, y: usize){
+        if !self.quirks.shift {
+            self.v[x] = self.v[y];
+        }
         self.v[0x0f] = (self.v[x] & 0b10000000) >> 7;
         self.v[x] <<= 1;
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_9xy0(&mut self, x: usize, y: usize){
-        if self.v[x] != self.v[y] {
-            self.pc += 4;
-        } else {
-            self.pc += 2;
-        }
+        self.skip_if(self.v[x] != self.v[y])
     }
 
     pub fn op_exa1(&mut self, x: usize){
-        if self.key[self.v[x]] != 1 {
-            self.pc += 4;
-        } else {
-            self.pc += 2;
-        }
+        self.skip_if(!self.key.pressed(self.v[x]))
     }
 
-    pub fn op_cxnn(&mut self, x: usize){
-        // Let's be ultra careful with casting types here as they may not
-        // give the (random) result we want
-        let random_number: u8 = rand::thread_rng().gen();
-        self.v[x] = (random_number & (self.get_nn() as u8)) as usize;
-        self.pc += 2
+    pub fn op_cxnn(&mut self, x: usize, nn: u8){
+        let random_number: u8 = self.rng.gen();
+        self.v[x] = random_number & nn;
+        self.next()
     }
 
     pub fn op_ex9e(&mut self, x: usize){
-        if self.key[self.v[x]] == 1 {
-            self.pc += 4;
-        } else {
-            self.pc += 2;
-        }
+        self.skip_if(self.key.pressed(self.v[x]))
     }
 
+    // Waits for a key to be held and with the release quirk for it to go up
+    // again, like the VIP. The timers keep ticking meanwhile
     pub fn op_fx0a(&mut self, x: usize) {
-        let mut keypress = false;
-        for i in 0..16 {
-            if self.key[i] == 1 {
-                self.v[x] = i;
-                keypress = true;
-            }
-        }
-        if keypress == true {
-            self.pc += 2
+        match (self.key_wait, self.key.highest()) {
+            (Some(key), _) if !self.key.pressed(key) => {
+                self.key_wait = None;
+                self.v[x] = key;
+                self.next()
+            },
+            (None, Some(key)) if !self.quirks.release => {
+                self.v[x] = key;
+                self.next()
+            },
+            (None, Some(key)) => {
+                self.key_wait = Some(key);
+                self.waiting = true
+            },
+            _ => self.waiting = true
         }
     }
 
     pub fn op_fx1e(&mut self, x: usize) {
-        if self.i + self.v[x] > 0xfff {
+        // VF says if I went past the end of memory, like on the Amiga
+        let sum = self.i.index() + self.v[x] as usize;
+        if sum >= MEMORY_SIZE {
             self.v[0xf] = 1
         } else {
             self.v[0xf] = 0
         }
-        self.i += self.v[x];
-        self.pc += 2
+        self.i = Address::new(sum);
+        self.next()
     }
 
     pub fn op_fx07(&mut self, x: usize) {
         self.v[x] = self.delay_timer;
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_fx15(&mut self, x: usize) {
         self.delay_timer = self.v[x];
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_fx18(&mut self, x: usize) {
         self.sound_timer = self.v[x];
-        self.pc += 2
+        self.next()
     }
 
     pub fn op_fx29(&mut self, x: usize) {
-        self.i = self.v[x] * 0x5;
-        self.pc += 2
+        self.i = Address::new(self.v[x] as usize * 0x5);
+        self.next()
     }
 
     pub fn op_fx55(&mut self, x: usize) {
-        for m in 0..=x {
-            self.memory[self.i + m] = self.v[m] as u8;
-        }
+        let registers = self.v;
+        self.write_memory(self.i.index(), &registers[..=x]);
         // This guide github.com/mattmikolay/chip-8/wiki/CHIP‐8-Instruction-Set
         // says that the I register must be modified but tests won't pass and
-        // blinky won't work with this instruction added
-        //self.i += x + 1;
-        self.pc += 2
+        // blinky won't work with this instruction added, so it's a quirk
+        if self.quirks.memory {
+            self.i = self.i.offset(x + 1);
+        }
+        self.next()
     }
 
     pub fn op_fx65(&mut self, x: usize) {
         for m in 0..=x {
-            self.v[m] = (self.memory[self.i + m]) as usize ;
+            self.v[m] = self.memory[self.i.offset(m).index()];
         }
         // This guide github.com/mattmikolay/chip-8/wiki/CHIP‐8-Instruction-Set
         // says that the I register must be modified but tests won't pass and
-        // blinky won't work with this instruction added
-        //self.i += x + 1;
-        self.pc += 2
+        // blinky won't work with this instruction added, so it's a quirk
+        if self.quirks.memory {
+            self.i = self.i.offset(x + 1);
+        }
+        self.next()
     }
 
     // Very weird opcode
     // Decimal representation of a binary number stored in memory
     pub fn op_fx33(&mut self, x: usize) {
-        self.memory[self.i] = (self.v[x] / 100) as u8;
-        self.memory[self.i + 1] = ((self.v[x] / 10) % 10) as u8;
-        self.memory[self.i + 2] = (self.v[x] % 10) as u8;
-        self.pc += 2
+        let digits = [self.v[x] / 100, (self.v[x] / 10) % 10, self.v[x] % 10];
+        self.write_memory(self.i.index(), &digits);
+        self.next()
     }
 
     // This is the opcode that draws the screen
     pub fn op_dxyn(&mut self, x: usize, y: usize, height: usize){
         // We'll get the coordinates from where we will start drawing from
         // the v registers indicated by the opcode x and y values
-        let vx = self.v[x];
-        let vy = self.v[y];
+        let vx = self.v[x] as usize % 64;
+        let vy = self.v[y] as usize % 32;
         self.v[0xF] = 0;
         // We get the sprite height using the last nibble of our opcode
         for yline in 0..height {
-            let pixel = self.memory[self.i + yline];
+            let pixel = self.memory[self.i.offset(yline).index()];
             // Every sprite is 8 pixels in width
             for xline in 0..8 {
                 if pixel & (0x80 >> xline) != 0 {
+                    // Without the wrap quirk whatever goes past the edges is clipped
+                    if !self.quirks.wrap && (vx + xline >= 64 || vy + yline >= 32) {
+                        continue;
+                    }
                     // Our vram is linear we calculate the position from the xy coordinates
                     // Interesting to note: The sprite needs to wrap around the
                     // screen if we need to keep writing a sprite and we reach
@@ -352,65 +670,237 @@ impl CPU {
                 }
             }
         }
+        // The VIP waits for the interrupt before drawing so nothing else
+        // runs this frame
+        if self.quirks.timing {
+            self.cycles = self.cycles.min(0);
+        }
         self.draw = true;
-        self.pc += 2
+        self.next()
     }
 
     // Just in case we find an unknown opcode
     pub fn unknown(&mut self){
-        println!("Unknown instruction {:x}", self.opcode);
+        self.halted = Some(format!("Unknown instruction {:x}", self.opcode));
+        self.breakexe = true
+    }
+
+    // Programs that break the machine stop like on an unknown opcode
+    pub fn halt(&mut self, reason: &str){
+        self.halted = Some(format!("{} at {:x}", reason, self.pc));
         self.breakexe = true
     }
 
     pub fn decode_opcode(&mut self) {
+        match Instruction::decode(self.opcode) {
+            Ok(instruction) => self.execute(instruction),
+            Err(_) => self.unknown()
+        }
+    }
+
+    // Only the original CHIP-8 instructions, the SCHIP and XO-CHIP ones
+    // stop the machine like an unknown opcode
+    pub fn execute(&mut self, instruction: Instruction) {
+        let cycles = if self.quirks.timing { timing::cycles(self, &instruction) } else { 0 };
+        match instruction {
+            Instruction::Clear => self.op_00e0(),
+            Instruction::Return => self.op_00ee(),
+            Instruction::Jump(nnn) => self.op_1nnn(nnn),
+            Instruction::Call(nnn) => self.op_2nnn(nnn),
+            Instruction::SkipEqual(x, nn) => self.op_3xnn(x as usize, nn),
+            Instruction::SkipNotEqual(x, nn) => self.op_4xnn(x as usize, nn),
+            Instruction::SkipEqualRegisters(x, y) => self.op_5xy0(x as usize, y as usize),
+            Instruction::Load(x, nn) => self.op_6xnn(x as usize, nn),
+            Instruction::Add(x, nn) => self.op_7xnn(x as usize, nn),
+            Instruction::Move(x, y) => self.op_8xy0(x as usize, y as usize),
+            Instruction::Or(x, y) => self.op_8xy1(x as usize, y as usize),
+            Instruction::And(x, y) => self.op_8xy2(x as usize, y as usize),
+            Instruction::Xor(x, y) => self.op_8xy3(x as usize, y as usize),
+            Instruction::AddRegisters(x, y) => self.op_8xy4(x as usize, y as usize),
+            Instruction::Sub(x, y) => self.op_8xy5(x as usize, y as usize),
+            // Only uses the y value without the shift quirk
+            Instruction::ShiftRight(x, y) => self.op_8xy6(x as usize, y as usize),
+            Instruction::SubReversed(x, y) => self.op_8xy7(x as usize, y as usize),
+            // Has multiple definitions ¿? see the shift quirk
+            Instruction::ShiftLeft(x, y) => self.op_8x0e(x as usize, y as usize),
+            Instruction::SkipNotEqualRegisters(x, y) => self.op_9xy0(x as usize, y as usize),
+            Instruction::LoadI(nnn) => self.op_annn(nnn),
+            Instruction::Random(x, nn) => self.op_cxnn(x as usize, nn),
+            Instruction::Draw(x, y, n) => self.op_dxyn(x as usize, y as usize, n as usize),
+            Instruction::SkipPressed(x) => self.op_ex9e(x as usize),
+            Instruction::SkipNotPressed(x) => self.op_exa1(x as usize),
+            Instruction::GetDelay(x) => self.op_fx07(x as usize),
+            Instruction::WaitKey(x) => self.op_fx0a(x as usize),
+            Instruction::SetDelay(x) => self.op_fx15(x as usize),
+            Instruction::SetSound(x) => self.op_fx18(x as usize),
+            Instruction::AddI(x) => self.op_fx1e(x as usize),
+            Instruction::Font(x) => self.op_fx29(x as usize),
+            Instruction::Bcd(x) => self.op_fx33(x as usize),
+            Instruction::Store(x) => self.op_fx55(x as usize),
+            Instruction::Restore(x) => self.op_fx65(x as usize),
+            _ => self.unknown()
+        }
+        self.cycles = self.cycles.saturating_sub(cycles);
+    }
+
+}
 
-        let nibbles = (
-            (self.opcode & 0xF000) >> 12 as usize,
-            (self.opcode & 0x0F00) >> 8 as usize,
-            (self.opcode & 0x00F0) >> 4 as usize,
-            (self.opcode & 0x000F) as usize,
-        );
-        let x = nibbles.1 as usize;
-        let y = nibbles.2 as usize;
-        let n = nibbles.3 as usize;
-
-        match nibbles {
-            (0x00, 0x00 , 0x0e, 0x00) => self.op_00e0(),
-            (0x00, 0x00 , 0x0e, 0x0e) => self.op_00ee(),
-            (0x01, _ , _, _) => self.op_1nnn(),
-            (0x02, _ , _, _) => self.op_2nnn(),
-            (0x03, _ , _, _) => self.op_3xnn(x),
-            (0x04, _ , _, _) => self.op_4xnn(x),
-            (0x05, _ , _, 0x00) => self.op_5xy0(x, y),
-            (0x06, _ , _, _) => self.op_6xnn(x),
-            (0x07, _ , _, _) => self.op_7xnn(x),
-            (0x08, _ , _, 0x00) => self.op_8xy0(x,y),
-            (0x08, _ , _, 0x01) => self.op_8xy1(x,y),
-            (0x08, _ , _, 0x02) => self.op_8xy2(x,y),
-            (0x08, _ , _, 0x03) => self.op_8xy3(x,y),
-            (0x08, _ , _, 0x04) => self.op_8xy4(x,y),
-            (0x08, _ , _, 0x05) => self.op_8xy5(x,y),
-            (0x08, _ , _, 0x06) => self.op_8xy6(x), // It's 8XY6 in docs but doesn't use the y value
-            (0x08, _ , _, 0x07) => self.op_8xy7(x,y),
-            (0x08, _ , _, 0x0e) => self.op_8x0e(x), // Has multiple definitions ¿?
-            (0x09, _ , _, 0x00) => self.op_9xy0(x,y),
-            (0x0a, _ , _, _) => self.op_annn(),
-            (0x0c, _ , _, _) => self.op_cxnn(x),
-            (0x0d, _ , _, _) => self.op_dxyn(x,y,n),
-            (0x0e, _ , 0x09, 0x0e) => self.op_ex9e(x),
-            (0x0e, _ , 0x0a, 0x01) => self.op_exa1(x),
-            (0x0f, _ , 0x00, 0x07) => self.op_fx07(x),
-            (0x0f, _ , 0x00, 0x0a) => self.op_fx0a(x),
-            (0x0f, _ , 0x01, 0x05) => self.op_fx15(x),
-            (0x0f, _ , 0x01, 0x08) => self.op_fx18(x),
-            (0x0f, _ , 0x01, 0x0e) => self.op_fx1e(x),
-            (0x0f, _ , 0x02, 0x09) => self.op_fx29(x),
-            (0x0f, _ , 0x03, 0x03) => self.op_fx33(x),
-            (0x0f, _ , 0x05, 0x05) => self.op_fx55(x),
-            (0x0f, _ , 0x06, 0x05) => self.op_fx65(x),
-             _ => self.unknown(),
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn cpu_with(rom: &[u8]) -> CPU {
+        let mut cpu = CPU::initialize();
+        cpu.load_fontset();
+        cpu.load_rom(rom).unwrap();
+        cpu
+    }
+
+    #[test]
+    fn halting_keeps_the_reason_instead_of_printing_it() {
+        // 00EE with nothing on the stack
+        let mut cpu = cpu_with(&[0x00, 0xEE]);
+        cpu.fetch_opcode();
+        assert!(cpu.breakexe);
+        assert_eq!(cpu.halted.as_deref(), Some("Return with an empty stack at 200"));
+        let mut cpu = cpu_with(&[0xFF, 0xFF]);
+        cpu.fetch_opcode();
+        assert_eq!(cpu.halted.as_deref(), Some("Unknown instruction ffff"));
+    }
+
+    #[test]
+    fn code_rewritten_by_fx55_runs_after_being_cached() {
+        let mut cpu = cpu_with(&[
+            0xA2, 0x08, // I = 208
+            0x60, 0x73, // V0 = 73
+            0x61, 0x01, // V1 = 01
+            0x12, 0x08, // Jump to 208
+            0x72, 0x01, // V2 += 1, becomes V3 += 1
+            0xF1, 0x55, // Store V0 and V1 at 208
+            0x12, 0x08  // Jump to 208
+        ]);
+        for _ in 0..8 {
+            cpu.fetch_opcode();
         }
+        assert_eq!(cpu.memory()[0x208..0x20A], [0x73, 0x01]);
+        assert_eq!((cpu.v[2], cpu.v[3]), (1, 1));
+    }
 
+    // Runs a frame like the frontends do, the speed doesn't matter with the
+    // timing quirk. Returns how many instructions ran
+    fn timed_frame(cpu: &mut CPU) -> u32 {
+        let mut ran = 0;
+        while !cpu.frame_over(ran, 1) {
+            cpu.fetch_opcode();
+            ran += 1;
+        }
+        ran
+    }
+
+    #[test]
+    fn the_timing_quirk_carries_the_overrun_into_the_next_frame() {
+        // V0 += 1 and jump back, 50 + 52 cycles
+        let mut cpu = cpu_with(&[0x70, 0x01, 0x12, 0x00]);
+        cpu.quirks = Quirks::from_profile("vip").unwrap();
+        assert_eq!(cpu.cycles, timing::BUDGET);
+        // 17 loops leave 96 cycles, the 18th goes 6 past the interrupt
+        assert_eq!(timed_frame(&mut cpu), 36);
+        assert_eq!((cpu.v[0], cpu.cycles), (18, -6));
+        cpu.tick_timers();
+        assert_eq!(cpu.cycles, timing::BUDGET - 6);
+        assert_eq!(timed_frame(&mut cpu), 36);
+        assert_eq!((cpu.v[0], cpu.cycles), (36, -12));
+    }
+
+    #[test]
+    fn draws_wait_for_the_next_frame_with_the_timing_quirk() {
+        // The 0 of the font at 0,0 and then V1 += 1 forever
+        let mut cpu = cpu_with(&[0x60, 0x00, 0xA0, 0x00, 0xD0, 0x05, 0x71, 0x01, 0x12, 0x06]);
+        cpu.quirks = Quirks::from_profile("vip").unwrap();
+        // The sprite is drawn after the interrupt so it's paid by the next frame
+        assert_eq!(timed_frame(&mut cpu), 3);
+        assert_eq!((cpu.pc.get(), cpu.v[1]), (0x206, 0));
+        assert_eq!(cpu.cycles, -(40 + 26 + 46 * 5));
+        cpu.tick_timers();
+        assert_eq!(cpu.cycles, timing::BUDGET - 296);
+        assert!(timed_frame(&mut cpu) > 1);
+        assert!(cpu.v[1] > 0);
+    }
+
+    #[test]
+    fn frames_count_instructions_without_the_timing_quirk() {
+        let mut cpu = cpu_with(&[0x70, 0x01, 0x12, 0x00]);
+        cpu.run_frame(10);
+        assert_eq!((cpu.v[0], cpu.cycles), (5, timing::BUDGET));
+    }
+
+    // V3 = the key from FX0A, then spins at 202
+    const WAIT: [u8; 4] = [0xF3, 0x0A, 0x12, 0x02];
+
+    #[test]
+    fn fx0a_waits_for_the_held_key_to_be_released() {
+        let mut cpu = cpu_with(&WAIT);
+        assert!(cpu.quirks.release);
+        cpu.run_frame(10);
+        assert_eq!(cpu.pc.get(), 0x200);
+        cpu.key.set(5, true);
+        for _ in 0..3 {
+            cpu.run_frame(10);
+            assert_eq!((cpu.pc.get(), cpu.key_wait), (0x200, Some(5)));
+        }
+        cpu.key.set(5, false);
+        cpu.run_frame(10);
+        assert_eq!((cpu.pc.get(), cpu.key_wait, cpu.v[3]), (0x202, None, 5));
+    }
+
+    #[test]
+    fn fx0a_gives_the_released_key_and_not_the_highest() {
+        let mut cpu = cpu_with(&WAIT);
+        cpu.key.set(5, true);
+        cpu.run_frame(10);
+        cpu.key.set(9, true);
+        cpu.run_frame(10);
+        cpu.key.set(5, false);
+        cpu.run_frame(10);
+        assert_eq!((cpu.pc.get(), cpu.v[3]), (0x202, 5));
+    }
+
+    #[test]
+    fn fx0a_finishes_on_the_press_without_the_release_quirk() {
+        let mut cpu = cpu_with(&WAIT);
+        cpu.quirks.release = false;
+        cpu.key.set(5, true);
+        cpu.run_frame(10);
+        assert_eq!((cpu.pc.get(), cpu.key_wait, cpu.v[3]), (0x202, None, 5));
+    }
+
+    #[test]
+    fn timers_count_down_while_fx0a_waits() {
+        let mut cpu = cpu_with(&WAIT);
+        cpu.delay_timer = 10;
+        cpu.sound_timer = 2;
+        for _ in 0..3 {
+            cpu.run_frame(10);
+        }
+        assert_eq!(cpu.pc.get(), 0x200);
+        assert_eq!((cpu.delay_timer, cpu.sound_timer), (7, 0));
+    }
+
+    #[test]
+    fn version_2_save_states_load_without_a_key_wait() {
+        let mut cpu = cpu_with(&WAIT);
+        cpu.v[7] = 0x42;
+        // A version 2 state is a version 3 one without the last byte
+        let mut data = crate::savestate::save(&cpu);
+        data[8] = 2;
+        data.pop();
+        cpu.key.set(5, true);
+        cpu.run_frame(10);
+        assert_eq!(cpu.key_wait, Some(5));
+        crate::savestate::load(&mut cpu, &data).unwrap();
+        assert_eq!((cpu.key_wait, cpu.waiting, cpu.v[7]), (None, false, 0x42));
+        assert_eq!(cpu.pc.get(), 0x200);
     }
 
 }
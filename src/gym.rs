@@ -0,0 +1,161 @@
+use crate::cpu::{Quirks, CPU};
+
+// A reinforcement learning environment in the style of OpenAI Gym, no SDL
+// involved. Agents press keys for a number of frames and get the screen
+// back, games keep their score somewhere in memory so it can be read as
+// the reward:
+//
+//   let mut env = Env::new(&rom, EnvConfig::default())?;
+//   let mut observation = env.reset(None);
+//   loop {
+//       let (next, info) = env.step(1 << 5, None);
+//       ...
+//   }
+#[derive(Clone, Debug)]
+pub struct EnvConfig {
+    // Instructions per frame
+    pub speed: u32,
+    // How many frames every step lasts unless step is told otherwise
+    pub frame_skip: u32,
+    pub quirks: Quirks,
+    // Used on every reset that doesn't bring its own
+    pub seed: u64,
+    // Memory addresses copied into Info::values after every step
+    pub watch: Vec<usize>
+}
+
+impl Default for EnvConfig {
+    fn default() -> EnvConfig {
+        EnvConfig {
+            speed: 10,
+            frame_skip: 4,
+            quirks: Quirks::default(),
+            seed: 0,
+            watch: Vec::new()
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Info {
+    // Frames since the last reset
+    pub frame: u64,
+    // Whether the buzzer is on
+    pub sound: bool,
+    // The CPU found an unknown opcode and won't run anymore
+    pub halted: bool,
+    // The bytes at EnvConfig::watch
+    pub values: Vec<u8>
+}
+
+// Everything needed to go back to a previous point of the game
+#[derive(Clone)]
+pub struct Snapshot {
+    cpu: CPU,
+    frame: u64
+}
+
+pub struct Env {
+    rom: Vec<u8>,
+    config: EnvConfig,
+    cpu: CPU,
+    frame: u64
+}
+
+impl Env {
+
+    pub fn new(rom: &[u8], config: EnvConfig) -> Result<Env, String> {
+        let mut env = Env {
+            rom: rom.to_vec(),
+            config,
+            cpu: CPU::initialize(),
+            frame: 0
+        };
+        env.cpu.load_rom(rom)?;
+        env.reset(None);
+        Ok(env)
+    }
+
+    pub fn config(&self) -> &EnvConfig {
+        &self.config
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    // The CPU can be changed directly, for poking registers or memory with
+    // write_memory
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    // Starts the game again from power on
+    pub fn reset(&mut self, seed: Option<u64>) -> Vec<u8> {
+        self.cpu = CPU::initialize();
+        self.cpu.quirks = self.config.quirks;
+        self.cpu.reseed(seed.unwrap_or(self.config.seed));
+        self.cpu.load_fontset();
+        // It already fit when the environment was created
+        let _ = self.cpu.load_rom(&self.rom);
+        self.frame = 0;
+        self.observation()
+    }
+
+    // Holds the keys (a bitmask, key 0 is the lowest bit) for the given
+    // number of frames or for EnvConfig::frame_skip frames
+    pub fn step(&mut self, keys: u16, frames: Option<u32>) -> (Vec<u8>, Info) {
+        self.cpu.set_keypad(keys);
+        for _ in 0..frames.unwrap_or(self.config.frame_skip) {
+            if self.cpu.breakexe {
+                break;
+            }
+            self.cpu.run_frame(self.config.speed);
+            self.frame += 1;
+        }
+        (self.observation(), self.info())
+    }
+
+    // The framebuffer, one byte per pixel row by row
+    pub fn observation(&self) -> Vec<u8> {
+        self.cpu.gfx.to_vec()
+    }
+
+    pub fn info(&self) -> Info {
+        Info {
+            frame: self.frame,
+            sound: self.cpu.sound_timer > 0,
+            halted: self.cpu.breakexe,
+            values: self.config.watch.iter().map(|&address| self.read_u8(address)).collect()
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot { cpu: self.cpu.clone(), frame: self.frame }
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.cpu = snapshot.cpu.clone();
+        self.frame = snapshot.frame;
+    }
+
+    // Reward helpers, addresses wrap around the 4K of memory
+
+    pub fn read_u8(&self, address: usize) -> u8 {
+        self.cpu.memory()[address & 0xFFF]
+    }
+
+    pub fn read_u16(&self, address: usize) -> u16 {
+        (self.read_u8(address) as u16) << 8 | self.read_u8(address + 1) as u16
+    }
+
+    // Scores drawn with FX33 are stored as one decimal digit per byte
+    pub fn read_bcd(&self, address: usize, digits: usize) -> u32 {
+        (0..digits).fold(0, |value, digit| value * 10 + self.read_u8(address + digit) as u32)
+    }
+
+    pub fn register(&self, x: usize) -> u8 {
+        self.cpu.v[x & 0xF]
+    }
+
+}
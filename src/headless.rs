@@ -0,0 +1,28 @@
+use crate::session::Session;
+
+// Runs a number of frames as fast as they go without a window or a
+// terminal, for scripts and CI. The settings, movies, recordings and the
+// RPC server work like with the other frontends, the keypad only changes
+// through movies, netplay or RPC
+pub fn run(session: &mut Session, frames: u64) -> Result<(), String> {
+    for _ in 0..frames {
+        session.start_frame();
+
+        if session.running() {
+            let mut ran = 0;
+            while !session.frame_over(ran) {
+                ran += session.run(ran);
+            }
+        }
+        if session.over() {
+            break
+        }
+        session.cpu.draw = false;
+
+        if let Some(notice) = session.end_frame() {
+            println!("{}", notice);
+        }
+    }
+
+    Ok(())
+}
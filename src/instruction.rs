@@ -0,0 +1,453 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::cpu::{Address, MEMORY_SIZE};
+
+// Every opcode the CHIP-8 family knows, with its operands already pulled out
+// of the nibbles. The CPU only runs the original CHIP-8 ones, the SCHIP and
+// XO-CHIP extensions are here so the analyzer and anything printing code can
+// tell them apart. Registers (X and Y) and N are 4 bits, NN is a byte
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Sys(Address),                   // 0NNN, a machine code routine
+    Clear,                          // 00E0
+    Return,                         // 00EE
+    ScrollDown(u8),                 // 00CN
+    ScrollUp(u8),                   // 00DN
+    ScrollRight,                    // 00FB
+    ScrollLeft,                     // 00FC
+    Exit,                           // 00FD
+    Lores,                          // 00FE
+    Hires,                          // 00FF
+    Jump(Address),                  // 1NNN
+    Call(Address),                  // 2NNN
+    SkipEqual(u8, u8),              // 3XNN
+    SkipNotEqual(u8, u8),           // 4XNN
+    SkipEqualRegisters(u8, u8),     // 5XY0
+    SaveRange(u8, u8),              // 5XY2
+    LoadRange(u8, u8),              // 5XY3
+    Load(u8, u8),                   // 6XNN
+    Add(u8, u8),                    // 7XNN
+    Move(u8, u8),                   // 8XY0
+    Or(u8, u8),                     // 8XY1
+    And(u8, u8),                    // 8XY2
+    Xor(u8, u8),                    // 8XY3
+    AddRegisters(u8, u8),           // 8XY4
+    Sub(u8, u8),                    // 8XY5
+    ShiftRight(u8, u8),             // 8XY6
+    SubReversed(u8, u8),            // 8XY7
+    ShiftLeft(u8, u8),              // 8XYE
+    SkipNotEqualRegisters(u8, u8),  // 9XY0
+    LoadI(Address),                 // ANNN
+    JumpV0(Address),                // BNNN
+    Random(u8, u8),                 // CXNN
+    Draw(u8, u8, u8),               // DXYN
+    SkipPressed(u8),                // EX9E
+    SkipNotPressed(u8),             // EXA1
+    LongI,                          // F000 NNNN, the address is the next word
+    Plane(u8),                      // FN01
+    Audio,                          // F002
+    GetDelay(u8),                   // FX07
+    WaitKey(u8),                    // FX0A
+    SetDelay(u8),                   // FX15
+    SetSound(u8),                   // FX18
+    AddI(u8),                       // FX1E
+    Font(u8),                       // FX29
+    BigFont(u8),                    // FX30
+    Bcd(u8),                        // FX33
+    Pitch(u8),                      // FX3A
+    Store(u8),                      // FX55
+    Restore(u8),                    // FX65
+    SaveFlags(u8),                  // FX75
+    LoadFlags(u8)                   // FX85
+}
+
+use self::Instruction::*;
+
+impl Instruction {
+
+    pub fn decode(opcode: u16) -> Result<Instruction, String> {
+        let x = ((opcode >> 8) & 0xF) as u8;
+        let y = ((opcode >> 4) & 0xF) as u8;
+        let n = (opcode & 0xF) as u8;
+        let nn = (opcode & 0xFF) as u8;
+        let nnn = Address::new((opcode & 0xFFF) as usize);
+        let unknown = || format!("Unknown instruction {:04X}", opcode);
+        let instruction = match opcode >> 12 {
+            0x0 => match opcode & 0xFFF {
+                0x0E0 => Clear,
+                0x0EE => Return,
+                0x0FB => ScrollRight,
+                0x0FC => ScrollLeft,
+                0x0FD => Exit,
+                0x0FE => Lores,
+                0x0FF => Hires,
+                _ if x == 0 && y == 0xC => ScrollDown(n),
+                _ if x == 0 && y == 0xD => ScrollUp(n),
+                _ => Sys(nnn)
+            },
+            0x1 => Jump(nnn),
+            0x2 => Call(nnn),
+            0x3 => SkipEqual(x, nn),
+            0x4 => SkipNotEqual(x, nn),
+            0x5 => match n {
+                0x0 => SkipEqualRegisters(x, y),
+                0x2 => SaveRange(x, y),
+                0x3 => LoadRange(x, y),
+                _ => return Err(unknown())
+            },
+            0x6 => Load(x, nn),
+            0x7 => Add(x, nn),
+            0x8 => match n {
+                0x0 => Move(x, y),
+                0x1 => Or(x, y),
+                0x2 => And(x, y),
+                0x3 => Xor(x, y),
+                0x4 => AddRegisters(x, y),
+                0x5 => Sub(x, y),
+                0x6 => ShiftRight(x, y),
+                0x7 => SubReversed(x, y),
+                0xE => ShiftLeft(x, y),
+                _ => return Err(unknown())
+            },
+            0x9 if n == 0 => SkipNotEqualRegisters(x, y),
+            0xA => LoadI(nnn),
+            0xB => JumpV0(nnn),
+            0xC => Random(x, nn),
+            0xD => Draw(x, y, n),
+            0xE => match nn {
+                0x9E => SkipPressed(x),
+                0xA1 => SkipNotPressed(x),
+                _ => return Err(unknown())
+            },
+            0xF => match nn {
+                0x00 if x == 0 => LongI,
+                0x01 => Plane(x),
+                0x02 if x == 0 => Audio,
+                0x07 => GetDelay(x),
+                0x0A => WaitKey(x),
+                0x15 => SetDelay(x),
+                0x18 => SetSound(x),
+                0x1E => AddI(x),
+                0x29 => Font(x),
+                0x30 => BigFont(x),
+                0x33 => Bcd(x),
+                0x3A => Pitch(x),
+                0x55 => Store(x),
+                0x65 => Restore(x),
+                0x75 => SaveFlags(x),
+                0x85 => LoadFlags(x),
+                _ => return Err(unknown())
+            },
+            _ => return Err(unknown())
+        };
+        Ok(instruction)
+    }
+
+    // The opcode back, operands wider than their nibbles are cut down
+    pub fn encode(&self) -> u16 {
+        let xy = |high: u16, x: u8, y: u8, low: u8| {
+            high << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | (low as u16 & 0xF)
+        };
+        let xnn = |high: u16, x: u8, nn: u8| high << 12 | (x as u16 & 0xF) << 8 | nn as u16;
+        match *self {
+            Sys(nnn) => nnn.get(),
+            Clear => 0x00E0,
+            Return => 0x00EE,
+            ScrollDown(n) => xy(0x0, 0x0, 0xC, n),
+            ScrollUp(n) => xy(0x0, 0x0, 0xD, n),
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Lores => 0x00FE,
+            Hires => 0x00FF,
+            Jump(nnn) => 0x1000 | nnn.get(),
+            Call(nnn) => 0x2000 | nnn.get(),
+            SkipEqual(x, nn) => xnn(0x3, x, nn),
+            SkipNotEqual(x, nn) => xnn(0x4, x, nn),
+            SkipEqualRegisters(x, y) => xy(0x5, x, y, 0x0),
+            SaveRange(x, y) => xy(0x5, x, y, 0x2),
+            LoadRange(x, y) => xy(0x5, x, y, 0x3),
+            Load(x, nn) => xnn(0x6, x, nn),
+            Add(x, nn) => xnn(0x7, x, nn),
+            Move(x, y) => xy(0x8, x, y, 0x0),
+            Or(x, y) => xy(0x8, x, y, 0x1),
+            And(x, y) => xy(0x8, x, y, 0x2),
+            Xor(x, y) => xy(0x8, x, y, 0x3),
+            AddRegisters(x, y) => xy(0x8, x, y, 0x4),
+            Sub(x, y) => xy(0x8, x, y, 0x5),
+            ShiftRight(x, y) => xy(0x8, x, y, 0x6),
+            SubReversed(x, y) => xy(0x8, x, y, 0x7),
+            ShiftLeft(x, y) => xy(0x8, x, y, 0xE),
+            SkipNotEqualRegisters(x, y) => xy(0x9, x, y, 0x0),
+            LoadI(nnn) => 0xA000 | nnn.get(),
+            JumpV0(nnn) => 0xB000 | nnn.get(),
+            Random(x, nn) => xnn(0xC, x, nn),
+            Draw(x, y, n) => xy(0xD, x, y, n),
+            SkipPressed(x) => xnn(0xE, x, 0x9E),
+            SkipNotPressed(x) => xnn(0xE, x, 0xA1),
+            LongI => 0xF000,
+            Plane(n) => xnn(0xF, n, 0x01),
+            Audio => 0xF002,
+            GetDelay(x) => xnn(0xF, x, 0x07),
+            WaitKey(x) => xnn(0xF, x, 0x0A),
+            SetDelay(x) => xnn(0xF, x, 0x15),
+            SetSound(x) => xnn(0xF, x, 0x18),
+            AddI(x) => xnn(0xF, x, 0x1E),
+            Font(x) => xnn(0xF, x, 0x29),
+            BigFont(x) => xnn(0xF, x, 0x30),
+            Bcd(x) => xnn(0xF, x, 0x33),
+            Pitch(x) => xnn(0xF, x, 0x3A),
+            Store(x) => xnn(0xF, x, 0x55),
+            Restore(x) => xnn(0xF, x, 0x65),
+            SaveFlags(x) => xnn(0xF, x, 0x75),
+            LoadFlags(x) => xnn(0xF, x, 0x85)
+        }
+    }
+
+    // The opcode pattern it belongs to, like "8XY6", SCHIP's big sprites
+    // get their own "DXY0"
+    pub fn family(&self) -> &'static str {
+        match self {
+            Sys(_) => "0NNN",
+            Clear => "00E0",
+            Return => "00EE",
+            ScrollDown(_) => "00CN",
+            ScrollUp(_) => "00DN",
+            ScrollRight => "00FB",
+            ScrollLeft => "00FC",
+            Exit => "00FD",
+            Lores => "00FE",
+            Hires => "00FF",
+            Jump(_) => "1NNN",
+            Call(_) => "2NNN",
+            SkipEqual(..) => "3XNN",
+            SkipNotEqual(..) => "4XNN",
+            SkipEqualRegisters(..) => "5XY0",
+            SaveRange(..) => "5XY2",
+            LoadRange(..) => "5XY3",
+            Load(..) => "6XNN",
+            Add(..) => "7XNN",
+            Move(..) => "8XY0",
+            Or(..) => "8XY1",
+            And(..) => "8XY2",
+            Xor(..) => "8XY3",
+            AddRegisters(..) => "8XY4",
+            Sub(..) => "8XY5",
+            ShiftRight(..) => "8XY6",
+            SubReversed(..) => "8XY7",
+            ShiftLeft(..) => "8XYE",
+            SkipNotEqualRegisters(..) => "9XY0",
+            LoadI(_) => "ANNN",
+            JumpV0(_) => "BNNN",
+            Random(..) => "CXNN",
+            Draw(_, _, 0) => "DXY0",
+            Draw(..) => "DXYN",
+            SkipPressed(_) => "EX9E",
+            SkipNotPressed(_) => "EXA1",
+            LongI => "F000 NNNN",
+            Plane(_) => "FN01",
+            Audio => "F002",
+            GetDelay(_) => "FX07",
+            WaitKey(_) => "FX0A",
+            SetDelay(_) => "FX15",
+            SetSound(_) => "FX18",
+            AddI(_) => "FX1E",
+            Font(_) => "FX29",
+            BigFont(_) => "FX30",
+            Bcd(_) => "FX33",
+            Pitch(_) => "FX3A",
+            Store(_) => "FX55",
+            Restore(_) => "FX65",
+            SaveFlags(_) => "FX75",
+            LoadFlags(_) => "FX85"
+        }
+    }
+
+    // Bytes it takes in memory, F000 carries a whole address after it
+    pub fn length(&self) -> usize {
+        if *self == LongI { 4 } else { 2 }
+    }
+
+}
+
+// Mnemonics like in Cowgod's reference, the XO-CHIP ones like Octo
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Sys(nnn) => write!(f, "SYS 0x{:03X}", nnn.get()),
+            Clear => write!(f, "CLS"),
+            Return => write!(f, "RET"),
+            ScrollDown(n) => write!(f, "SCD {}", n),
+            ScrollUp(n) => write!(f, "SCU {}", n),
+            ScrollRight => write!(f, "SCR"),
+            ScrollLeft => write!(f, "SCL"),
+            Exit => write!(f, "EXIT"),
+            Lores => write!(f, "LOW"),
+            Hires => write!(f, "HIGH"),
+            Jump(nnn) => write!(f, "JP 0x{:03X}", nnn.get()),
+            Call(nnn) => write!(f, "CALL 0x{:03X}", nnn.get()),
+            SkipEqual(x, nn) => write!(f, "SE V{:X}, 0x{:02X}", x, nn),
+            SkipNotEqual(x, nn) => write!(f, "SNE V{:X}, 0x{:02X}", x, nn),
+            SkipEqualRegisters(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            SaveRange(x, y) => write!(f, "SAVE V{:X} - V{:X}", x, y),
+            LoadRange(x, y) => write!(f, "LOAD V{:X} - V{:X}", x, y),
+            Load(x, nn) => write!(f, "LD V{:X}, 0x{:02X}", x, nn),
+            Add(x, nn) => write!(f, "ADD V{:X}, 0x{:02X}", x, nn),
+            Move(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            AddRegisters(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            ShiftRight(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            SubReversed(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            ShiftLeft(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            SkipNotEqualRegisters(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            LoadI(nnn) => write!(f, "LD I, 0x{:03X}", nnn.get()),
+            JumpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn.get()),
+            Random(x, nn) => write!(f, "RND V{:X}, 0x{:02X}", x, nn),
+            Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            SkipPressed(x) => write!(f, "SKP V{:X}", x),
+            SkipNotPressed(x) => write!(f, "SKNP V{:X}", x),
+            LongI => write!(f, "LD I, LONG"),
+            Plane(n) => write!(f, "PLANE {}", n),
+            Audio => write!(f, "AUDIO"),
+            GetDelay(x) => write!(f, "LD V{:X}, DT", x),
+            WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Font(x) => write!(f, "LD F, V{:X}", x),
+            BigFont(x) => write!(f, "LD HF, V{:X}", x),
+            Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Store(x) => write!(f, "LD [I], V{:X}", x),
+            Restore(x) => write!(f, "LD V{:X}, [I]", x),
+            SaveFlags(x) => write!(f, "LD R, V{:X}", x),
+            LoadFlags(x) => write!(f, "LD V{:X}, R", x)
+        }
+    }
+}
+
+// Decoded instructions by the address they start at, so loops don't decode
+// the same opcodes over and over. CHIP-8 programs rewrite their own code
+// all the time so whatever writes memory has to invalidate what it touched
+#[derive(Clone)]
+pub struct DecodeCache {
+    entries: Vec<Option<(u16, Instruction)>>,
+    generation: u64
+}
+
+// Shared by every cache so no two invalidations get the same generation
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+fn next_generation() -> u64 {
+    GENERATIONS.fetch_add(1, Ordering::Relaxed) + 1
+}
+
+impl DecodeCache {
+
+    pub fn new() -> DecodeCache {
+        DecodeCache { entries: vec![None; MEMORY_SIZE], generation: 0 }
+    }
+
+    // The opcode and what it decoded to
+    pub fn get(&self, address: Address) -> Option<(u16, Instruction)> {
+        self.entries[address.index()]
+    }
+
+    pub fn insert(&mut self, address: Address, opcode: u16, instruction: Instruction) {
+        self.entries[address.index()] = Some((opcode, instruction));
+    }
+
+    // Every byte belongs to the instruction starting there and to the one
+    // starting right before it
+    pub fn invalidate(&mut self, address: Address, length: usize) {
+        self.generation = next_generation();
+        let start = address.index() + MEMORY_SIZE - 1;
+        for offset in 0..=length.min(MEMORY_SIZE) {
+            self.entries[(start + offset) % MEMORY_SIZE] = None;
+        }
+    }
+
+    // Changes on every invalidation and never repeats, not even in another
+    // CPU, so the JIT knows when its blocks have to be checked
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn clear(&mut self) {
+        self.generation = next_generation();
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+    }
+
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Written from the opcode tables and not from decode, the gaps in
+    // 5XYN, 8XYN, 9XYN, EXNN and FXNN
+    fn undefined(opcode: u16) -> bool {
+        let x = (opcode >> 8) & 0xF;
+        let n = opcode & 0xF;
+        let nn = opcode & 0xFF;
+        match opcode >> 12 {
+            0x5 => ![0x0, 0x2, 0x3].contains(&n),
+            0x8 => ![0x0, 0x1, 0x2, 0x3, 0x4, 0x5, 0x6, 0x7, 0xE].contains(&n),
+            0x9 => n != 0,
+            0xE => nn != 0x9E && nn != 0xA1,
+            0xF => match nn {
+                0x00 | 0x02 => x != 0,
+                0x01 | 0x07 | 0x0A | 0x15 | 0x18 | 0x1E | 0x29 | 0x30 | 0x33 | 0x3A | 0x55 | 0x65 | 0x75 |
+                0x85 => false,
+                _ => true
+            },
+            _ => false
+        }
+    }
+
+    #[test]
+    fn every_opcode_round_trips() {
+        for opcode in 0..=0xFFFF {
+            match Instruction::decode(opcode) {
+                Ok(instruction) => {
+                    assert!(!undefined(opcode), "{:04X} decodes to {:?}", opcode, instruction);
+                    assert_eq!(instruction.encode(), opcode, "{:?}", instruction);
+                },
+                Err(_) => assert!(undefined(opcode), "{:04X} doesn't decode", opcode)
+            }
+        }
+    }
+
+    #[test]
+    fn mnemonics() {
+        let show = |opcode| Instruction::decode(opcode).unwrap().to_string();
+        assert_eq!(show(0x00E0), "CLS");
+        assert_eq!(show(0x0123), "SYS 0x123");
+        assert_eq!(show(0x1ABC), "JP 0xABC");
+        assert_eq!(show(0x3A0F), "SE VA, 0x0F");
+        assert_eq!(show(0x8AB6), "SHR VA, VB");
+        assert_eq!(show(0xD125), "DRW V1, V2, 5");
+        assert_eq!(show(0xF30A), "LD V3, K");
+        assert_eq!(show(0xF555), "LD [I], V5");
+        assert_eq!(show(0xF000), "LD I, LONG");
+    }
+
+    #[test]
+    fn families() {
+        assert_eq!(Instruction::decode(0xD120).unwrap().family(), "DXY0");
+        assert_eq!(Instruction::decode(0xD12F).unwrap().family(), "DXYN");
+        assert_eq!(Instruction::decode(0xF000).unwrap().length(), 4);
+    }
+
+}
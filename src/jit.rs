@@ -0,0 +1,598 @@
+use std::mem;
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, InstBuilder, MemFlagsData, Type, Value};
+use cranelift_codegen::Context;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, Module};
+
+use crate::cpu::{Address, Quirks, CPU, MEMORY_SIZE};
+use crate::instruction::Instruction;
+use crate::savestate;
+
+// Translates CHIP-8 code to native code with Cranelift a basic block at a
+// time, for batch runs where the interpreter is what takes the time. A block
+// runs from an address to the first jump or skip, which end it, or to the
+// first instruction it can't do natively (drawing, calls, random numbers,
+// waiting for keys and writing memory), which the interpreter runs before
+// going back to compiled code. Blocks are thrown away when the memory they
+// came from is written or the quirks change
+
+// Longest block, so the budget of a frame rarely cuts one short
+const MAX_BLOCK: usize = 32;
+
+// Code rewritten this many times at the same address is left to the
+// interpreter, compiling it again every time would take longer
+const MAX_REWRITES: u8 = 4;
+
+// Compiled code can't be freed a block at a time so once this many compiled
+// blocks were thrown away the whole module goes, self-modifying code would make it
+// grow forever otherwise
+const MAX_STALE: usize = 1024;
+
+// What compiled code reads and writes, copied in and out of the CPU around
+// every block
+#[repr(C)]
+struct Registers {
+    v: [u8; 16],
+    i: u16,
+    delay_timer: u8,
+    sound_timer: u8,
+    keypad: u16
+}
+
+// Where the fields of Registers are
+const V: i32 = 0;
+const I: i32 = 16;
+const DELAY_TIMER: i32 = 18;
+const SOUND_TIMER: i32 = 19;
+const KEYPAD: i32 = 20;
+
+// Takes the registers and the memory, returns the address of the next
+// instruction
+type Code = unsafe extern "C" fn(*mut Registers, *const u8) -> u32;
+
+struct Block {
+    // The bytes it was compiled from
+    source: Vec<u8>,
+    // The instructions it runs and the opcode of the last one, which is what
+    // the opcode register is left with
+    length: u32,
+    last_opcode: u16,
+    // None when the first instruction needs the interpreter or the code
+    // there keeps changing
+    code: Option<Code>,
+    // The generation of the decode cache the source was last compared at
+    checked: u64
+}
+
+pub struct Jit {
+    module: JITModule,
+    context: Context,
+    builder: FunctionBuilderContext,
+    // By the address they start at
+    blocks: Vec<Option<Block>>,
+    rewrites: Vec<u8>,
+    quirks: u8,
+    stale: usize,
+    // Blocks of a single instruction checked against the interpreter
+    check: bool,
+    // What the check found, the CPU halts on the first mismatch and it's up
+    // to the caller to report it
+    pub mismatch: Option<String>,
+    // Instructions run by compiled code and by the interpreter
+    pub native: u64,
+    pub interpreted: u64
+}
+
+impl Jit {
+
+    // With check every instruction compiled is also run by the interpreter
+    // on a copy of the CPU and both have to end up the same
+    pub fn new(check: bool) -> Result<Jit, String> {
+        let module = new_module()?;
+        Ok(Jit {
+            context: module.make_context(),
+            module,
+            builder: FunctionBuilderContext::new(),
+            blocks: (0..MEMORY_SIZE).map(|_| None).collect(),
+            rewrites: vec![0; MEMORY_SIZE],
+            quirks: Quirks::default().bits(),
+            stale: 0,
+            check,
+            mismatch: None,
+            native: 0,
+            interpreted: 0
+        })
+    }
+
+    // Runs up to count instructions as if fetch_opcode was called that many
+    // times, stopping early when the CPU halts or something new is drawn so
+    // frontends can show every draw. Returns how many ran
+    pub fn run(&mut self, cpu: &mut CPU, count: u32) -> u32 {
+        self.forget_stale(cpu);
+        let drawn = cpu.draw;
+        let mut ran = 0;
+        while ran < count && !cpu.breakexe && !cpu.waiting && (drawn || !cpu.draw) {
+            let pc = cpu.pc.index();
+            self.check_source(cpu);
+            if self.blocks[pc].is_none() {
+                self.blocks[pc] = Some(self.compile(cpu));
+            }
+            let (code, length, last_opcode) = match &self.blocks[pc] {
+                Some(block) => (block.code, block.length, block.last_opcode),
+                None => (None, 0, 0)
+            };
+            match code {
+                // Compiled code doesn't count cycles for the timing quirk
+                Some(code) if length <= count - ran && !cpu.quirks.timing => {
+                    if self.check {
+                        self.run_checked(cpu, code, length, last_opcode);
+                    } else {
+                        execute(cpu, code, last_opcode);
+                    }
+                    ran += length;
+                    self.native += length as u64;
+                },
+                _ => {
+                    cpu.fetch_opcode();
+                    ran += 1;
+                    self.interpreted += 1;
+                }
+            }
+        }
+        ran
+    }
+
+    fn run_checked(&mut self, cpu: &mut CPU, code: Code, length: u32, last_opcode: u16) {
+        let mut expected = cpu.clone();
+        let (address, opcode) = (cpu.pc, opcode_at(cpu, cpu.pc));
+        for _ in 0..length {
+            expected.fetch_uncached();
+        }
+        execute(cpu, code, last_opcode);
+        if savestate::save(cpu) != savestate::save(&expected) {
+            let instruction = Instruction::decode(opcode).map(|i| i.to_string()).unwrap_or_default();
+            let message = format!("The JIT and the interpreter disagree on {:04X} ({}) at {:x}: {}",
+                                  opcode, instruction, address, differences(&expected, cpu));
+            cpu.breakexe = true;
+            self.mismatch = Some(message);
+        }
+    }
+
+    // Blocks are only compared with memory when they are about to run and
+    // something was written since the last time, so writes to data don't
+    // cost anything for code that doesn't run
+    fn check_source(&mut self, cpu: &CPU) {
+        let start = cpu.pc.index();
+        let generation = cpu.cache.generation();
+        let unchanged = match &mut self.blocks[start] {
+            Some(block) if block.checked != generation => {
+                block.checked = generation;
+                block.source.iter().enumerate()
+                    .all(|(offset, &byte)| cpu.memory()[(start + offset) % MEMORY_SIZE] == byte)
+            },
+            _ => true
+        };
+        if !unchanged {
+            if self.blocks[start].take().is_some_and(|block| block.code.is_some()) {
+                self.stale += 1;
+            }
+            self.rewrites[start] = self.rewrites[start].saturating_add(1);
+        }
+    }
+
+    // Drops every block if the quirks changed and the whole module once
+    // enough of it is dead code
+    fn forget_stale(&mut self, cpu: &CPU) {
+        let quirks = cpu.quirks.bits();
+        if quirks != self.quirks {
+            self.quirks = quirks;
+            self.clear();
+        }
+        if self.stale > MAX_STALE {
+            if let Ok(module) = new_module() {
+                self.clear();
+                self.stale = 0;
+                let old = mem::replace(&mut self.module, module);
+                // Safe because the only pointers into it were in the blocks
+                unsafe { old.free_memory() };
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        for block in &mut self.blocks {
+            if block.take().is_some_and(|block| block.code.is_some()) {
+                self.stale += 1;
+            }
+        }
+    }
+
+    fn compile(&mut self, cpu: &CPU) -> Block {
+        let limit = if self.rewrites[cpu.pc.index()] >= MAX_REWRITES {
+            0
+        } else if self.check {
+            1
+        } else {
+            MAX_BLOCK
+        };
+        let mut instructions = Vec::new();
+        let mut address = cpu.pc;
+        while instructions.len() < limit {
+            let opcode = opcode_at(cpu, address);
+            let instruction = match Instruction::decode(opcode) {
+                Ok(instruction) if native(&instruction) => instruction,
+                _ => break
+            };
+            instructions.push((address, opcode, instruction));
+            address = address.offset(2);
+            if ends_block(&instruction) {
+                break;
+            }
+        }
+        // Even a block left to the interpreter has to notice when its
+        // instruction changes
+        let source = (0..(2 * instructions.len()).max(2))
+            .map(|offset| cpu.memory()[cpu.pc.offset(offset).index()])
+            .collect();
+        let code = if instructions.is_empty() {
+            None
+        } else {
+            // Whatever Cranelift doesn't like is left to the interpreter
+            self.translate(&instructions, address, Quirks::from_bits(self.quirks)).ok()
+        };
+        Block {
+            source,
+            length: instructions.len() as u32,
+            last_opcode: instructions.last().map_or(0, |&(_, opcode, _)| opcode),
+            code,
+            checked: cpu.cache.generation()
+        }
+    }
+
+    fn translate(&mut self, instructions: &[(Address, u16, Instruction)], next: Address,
+                 quirks: Quirks) -> Result<Code, String> {
+        let target = self.module.target_config();
+        let pointer = target.pointer_type();
+        let mut signature = self.module.make_signature();
+        signature.params.push(AbiParam::new(pointer));
+        signature.params.push(AbiParam::new(pointer));
+        signature.returns.push(AbiParam::new(types::I32));
+        let id = self.module.declare_anonymous_function(&signature).map_err(|e| e.to_string())?;
+        self.context.func.signature = signature;
+
+        let mut builder = FunctionBuilder::new(&mut self.context.func, &mut self.builder);
+        let entry = builder.create_block();
+        builder.append_block_params_for_function_params(entry);
+        builder.switch_to_block(entry);
+        builder.seal_block(entry);
+        let (registers, memory) = (builder.block_params(entry)[0], builder.block_params(entry)[1]);
+        let mut emitter = Emitter { builder, registers, memory, pointer, quirks };
+        let mut pc = None;
+        for &(address, _, instruction) in instructions {
+            pc = emitter.emit(instruction, address);
+        }
+        let pc = pc.unwrap_or_else(|| emitter.builder.ins().iconst(types::I32, next.get() as i64));
+        emitter.builder.ins().return_(&[pc]);
+        emitter.builder.finalize(target);
+
+        let defined = self.module.define_function(id, &mut self.context).map_err(|e| e.to_string());
+        self.module.clear_context(&mut self.context);
+        defined?;
+        self.module.finalize_definitions().map_err(|e| e.to_string())?;
+        let code = self.module.get_finalized_function(id);
+        // Safe because the function was built with the signature of Code
+        Ok(unsafe { mem::transmute::<*const u8, Code>(code) })
+    }
+
+}
+
+fn new_module() -> Result<JITModule, String> {
+    let builder = JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names())
+        .map_err(|e| format!("Can't start the JIT: {}", e))?;
+    Ok(JITModule::new(builder))
+}
+
+fn opcode_at(cpu: &CPU, address: Address) -> u16 {
+    (cpu.memory()[address.index()] as u16) << 8 | cpu.memory()[address.offset(1).index()] as u16
+}
+
+// Only touches registers and reads memory
+fn native(instruction: &Instruction) -> bool {
+    matches!(instruction,
+        Instruction::Jump(_) | Instruction::SkipEqual(..) | Instruction::SkipNotEqual(..) |
+        Instruction::SkipEqualRegisters(..) | Instruction::Load(..) | Instruction::Add(..) |
+        Instruction::Move(..) | Instruction::Or(..) | Instruction::And(..) | Instruction::Xor(..) |
+        Instruction::AddRegisters(..) | Instruction::Sub(..) | Instruction::ShiftRight(..) |
+        Instruction::SubReversed(..) | Instruction::ShiftLeft(..) |
+        Instruction::SkipNotEqualRegisters(..) | Instruction::LoadI(_) | Instruction::SkipPressed(_) |
+        Instruction::SkipNotPressed(_) | Instruction::GetDelay(_) | Instruction::SetDelay(_) |
+        Instruction::SetSound(_) | Instruction::AddI(_) | Instruction::Font(_) |
+        Instruction::Restore(_))
+}
+
+fn ends_block(instruction: &Instruction) -> bool {
+    matches!(instruction, Instruction::Jump(_) | Instruction::SkipEqual(..) | Instruction::SkipNotEqual(..) |
+                          Instruction::SkipEqualRegisters(..) | Instruction::SkipNotEqualRegisters(..) |
+                          Instruction::SkipPressed(_) | Instruction::SkipNotPressed(_))
+}
+
+fn execute(cpu: &mut CPU, code: Code, last_opcode: u16) {
+    let mut registers = Registers {
+        v: cpu.v,
+        i: cpu.i.get(),
+        delay_timer: cpu.delay_timer,
+        sound_timer: cpu.sound_timer,
+        keypad: cpu.keypad()
+    };
+    // Safe because compiled code only writes the registers and only reads
+    // memory at addresses masked to 12 bits
+    let pc = unsafe { code(&mut registers, cpu.memory().as_ptr()) };
+    cpu.v = registers.v;
+    cpu.i = Address::new(registers.i as usize);
+    cpu.delay_timer = registers.delay_timer;
+    cpu.sound_timer = registers.sound_timer;
+    cpu.pc = Address::new(pc as usize);
+    cpu.opcode = last_opcode;
+}
+
+// The registers that came out different, for the check
+fn differences(expected: &CPU, actual: &CPU) -> String {
+    let mut differences = Vec::new();
+    let mut compare = |name: String, expected: usize, actual: usize| {
+        if expected != actual {
+            differences.push(format!("{} is {:#x} instead of {:#x}", name, actual, expected));
+        }
+    };
+    compare("PC".to_string(), expected.pc.index(), actual.pc.index());
+    compare("I".to_string(), expected.i.index(), actual.i.index());
+    for x in 0..16 {
+        compare(format!("V{:X}", x), expected.v[x] as usize, actual.v[x] as usize);
+    }
+    compare("the delay timer".to_string(), expected.delay_timer as usize, actual.delay_timer as usize);
+    compare("the sound timer".to_string(), expected.sound_timer as usize, actual.sound_timer as usize);
+    if differences.is_empty() {
+        differences.push("something besides the registers changed".to_string());
+    }
+    differences.join(", ")
+}
+
+// Writes the IR of one instruction after another, every statement of the
+// interpreter is a load and a store here too so aliased registers (VF as X
+// or Y) come out the same
+struct Emitter<'a> {
+    builder: FunctionBuilder<'a>,
+    registers: Value,
+    memory: Value,
+    pointer: Type,
+    quirks: Quirks
+}
+
+impl<'a> Emitter<'a> {
+
+    fn v(&mut self, x: u8) -> Value {
+        self.builder.ins().load(types::I8, MemFlagsData::trusted(), self.registers, V + x as i32)
+    }
+
+    fn set_v(&mut self, x: u8, value: Value) {
+        self.builder.ins().store(MemFlagsData::trusted(), value, self.registers, V + x as i32);
+    }
+
+    fn load(&mut self, kind: Type, offset: i32) -> Value {
+        self.builder.ins().load(kind, MemFlagsData::trusted(), self.registers, offset)
+    }
+
+    fn store(&mut self, value: Value, offset: i32) {
+        self.builder.ins().store(MemFlagsData::trusted(), value, self.registers, offset);
+    }
+
+    fn constant(&mut self, kind: Type, value: usize) -> Value {
+        self.builder.ins().iconst(kind, value as i64)
+    }
+
+    // The address of the next instruction for the ones that end blocks
+    fn emit(&mut self, instruction: Instruction, address: Address) -> Option<Value> {
+        let next = address.offset(2).get() as usize;
+        let skip = address.offset(4).get() as usize;
+        let condition = match instruction {
+            Instruction::Jump(nnn) => return Some(self.constant(types::I32, nnn.index())),
+            Instruction::SkipEqual(x, nn) | Instruction::SkipNotEqual(x, nn) => {
+                let (vx, nn) = (self.v(x), self.constant(types::I8, nn as usize));
+                let cc = if let Instruction::SkipEqual(..) = instruction { IntCC::Equal } else { IntCC::NotEqual };
+                self.builder.ins().icmp(cc, vx, nn)
+            },
+            Instruction::SkipEqualRegisters(x, y) | Instruction::SkipNotEqualRegisters(x, y) => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let cc = if let Instruction::SkipEqualRegisters(..) = instruction { IntCC::Equal } else { IntCC::NotEqual };
+                self.builder.ins().icmp(cc, vx, vy)
+            },
+            Instruction::SkipPressed(x) | Instruction::SkipNotPressed(x) => {
+                let keypad = self.load(types::I16, KEYPAD);
+                let vx = self.v(x);
+                let key = self.builder.ins().band_imm_u(vx, 0xF);
+                let key = self.builder.ins().uextend(types::I16, key);
+                let bits = self.builder.ins().ushr(keypad, key);
+                let bit = self.builder.ins().band_imm_u(bits, 1);
+                let cc = if let Instruction::SkipPressed(_) = instruction { IntCC::NotEqual } else { IntCC::Equal };
+                self.builder.ins().icmp_imm_u(cc, bit, 0)
+            },
+            _ => {
+                self.emit_straight(instruction);
+                return None;
+            }
+        };
+        let (skip, next) = (self.constant(types::I32, skip), self.constant(types::I32, next));
+        Some(self.builder.ins().select(condition, skip, next))
+    }
+
+    fn emit_straight(&mut self, instruction: Instruction) {
+        match instruction {
+            Instruction::Load(x, nn) => {
+                let nn = self.constant(types::I8, nn as usize);
+                self.set_v(x, nn);
+            },
+            Instruction::Add(x, nn) => {
+                let (vx, nn) = (self.v(x), self.constant(types::I8, nn as usize));
+                let sum = self.builder.ins().iadd(vx, nn);
+                self.set_v(x, sum);
+            },
+            Instruction::Move(x, y) => {
+                let vy = self.v(y);
+                self.set_v(x, vy);
+            },
+            Instruction::Or(x, y) | Instruction::And(x, y) | Instruction::Xor(x, y) => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let result = match instruction {
+                    Instruction::Or(..) => self.builder.ins().bor(vx, vy),
+                    Instruction::And(..) => self.builder.ins().band(vx, vy),
+                    _ => self.builder.ins().bxor(vx, vy)
+                };
+                self.set_v(x, result);
+                if self.quirks.logic {
+                    let zero = self.constant(types::I8, 0);
+                    self.set_v(0xF, zero);
+                }
+            },
+            Instruction::AddRegisters(x, y) => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let sum = self.builder.ins().iadd(vx, vy);
+                self.set_v(x, sum);
+                let (vx, vy) = (self.v(x), self.v(y));
+                let carry = self.builder.ins().icmp(IntCC::UnsignedGreaterThan, vy, vx);
+                self.set_v(0xF, carry);
+            },
+            Instruction::Sub(x, y) => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let borrow = self.builder.ins().icmp(IntCC::UnsignedGreaterThan, vy, vx);
+                let flag = self.builder.ins().bxor_imm_u(borrow, 1);
+                self.set_v(0xF, flag);
+                let (vx, vy) = (self.v(x), self.v(y));
+                let difference = self.builder.ins().isub(vx, vy);
+                self.set_v(x, difference);
+            },
+            Instruction::SubReversed(x, y) => {
+                let (vx, vy) = (self.v(x), self.v(y));
+                let flag = self.builder.ins().icmp(IntCC::UnsignedGreaterThan, vy, vx);
+                self.set_v(0xF, flag);
+                let (vx, vy) = (self.v(x), self.v(y));
+                let difference = self.builder.ins().isub(vy, vx);
+                self.set_v(x, difference);
+            },
+            Instruction::ShiftRight(x, y) | Instruction::ShiftLeft(x, y) => {
+                if !self.quirks.shift {
+                    let vy = self.v(y);
+                    self.set_v(x, vy);
+                }
+                let right = matches!(instruction, Instruction::ShiftRight(..));
+                let vx = self.v(x);
+                let flag = if right {
+                    self.builder.ins().band_imm_u(vx, 1)
+                } else {
+                    self.builder.ins().ushr_imm_u(vx, 7)
+                };
+                self.set_v(0xF, flag);
+                let vx = self.v(x);
+                let shifted = if right {
+                    self.builder.ins().ushr_imm_u(vx, 1)
+                } else {
+                    self.builder.ins().ishl_imm_u(vx, 1)
+                };
+                self.set_v(x, shifted);
+            },
+            Instruction::LoadI(nnn) => {
+                let nnn = self.constant(types::I16, nnn.index());
+                self.store(nnn, I);
+            },
+            Instruction::AddI(x) => {
+                let i = self.load(types::I16, I);
+                let i = self.builder.ins().uextend(types::I32, i);
+                let vx = self.v(x);
+                let vx = self.builder.ins().uextend(types::I32, vx);
+                let sum = self.builder.ins().iadd(i, vx);
+                let over = self.builder.ins().icmp_imm_u(IntCC::UnsignedGreaterThanOrEqual, sum, MEMORY_SIZE as i64);
+                self.set_v(0xF, over);
+                let wrapped = self.builder.ins().band_imm_u(sum, MEMORY_SIZE as i64 - 1);
+                let wrapped = self.builder.ins().ireduce(types::I16, wrapped);
+                self.store(wrapped, I);
+            },
+            Instruction::Font(x) => {
+                let vx = self.v(x);
+                let vx = self.builder.ins().uextend(types::I16, vx);
+                let address = self.builder.ins().imul_imm_u(vx, 5);
+                self.store(address, I);
+            },
+            Instruction::GetDelay(x) => {
+                let timer = self.load(types::I8, DELAY_TIMER);
+                self.set_v(x, timer);
+            },
+            Instruction::SetDelay(x) | Instruction::SetSound(x) => {
+                let vx = self.v(x);
+                let timer = if let Instruction::SetDelay(_) = instruction { DELAY_TIMER } else { SOUND_TIMER };
+                self.store(vx, timer);
+            },
+            Instruction::Restore(x) => {
+                let i = self.load(types::I16, I);
+                let i = self.builder.ins().uextend(self.pointer, i);
+                for m in 0..=x {
+                    let address = self.builder.ins().iadd_imm_u(i, m as i64);
+                    let address = self.builder.ins().band_imm_u(address, MEMORY_SIZE as i64 - 1);
+                    let byte = self.builder.ins().iadd(self.memory, address);
+                    let byte = self.builder.ins().load(types::I8, MemFlagsData::trusted(), byte, 0);
+                    self.set_v(m, byte);
+                }
+                if self.quirks.memory {
+                    let after = self.builder.ins().iadd_imm_u(i, x as i64 + 1);
+                    let after = self.builder.ins().band_imm_u(after, MEMORY_SIZE as i64 - 1);
+                    let after = self.builder.ins().ireduce(types::I16, after);
+                    self.store(after, I);
+                }
+            },
+            // native() only lets the ones above through
+            _ => unreachable!()
+        }
+    }
+
+}
+
+#[cfg(all(test, feature = "jit"))]
+mod tests {
+
+    use super::*;
+
+    // The programs of the benchmark
+    mod programs {
+        include!(concat!(env!("CARGO_MANIFEST_DIR"), "/benches/programs/mod.rs"));
+    }
+
+    fn check(program: &[u16]) {
+        let rom: Vec<u8> = program.iter().flat_map(|opcode| opcode.to_be_bytes()).collect();
+        let mut cpu = CPU::initialize();
+        cpu.reseed(0);
+        cpu.load_fontset();
+        cpu.load_rom(&rom).unwrap();
+        let mut jit = Jit::new(true).unwrap();
+        for _ in 0..100 {
+            let mut ran = 0;
+            while ran < 1000 && !cpu.breakexe {
+                ran += jit.run(&mut cpu, 1000 - ran);
+            }
+            cpu.tick_timers();
+        }
+        assert_eq!(jit.mismatch, None);
+        assert!(!cpu.breakexe);
+        assert!(jit.native > 0, "Nothing was compiled");
+    }
+
+    #[test]
+    fn mixed_and_self_modifying_code_matches_the_interpreter() {
+        check(&programs::ROM);
+    }
+
+    #[test]
+    fn arithmetic_matches_the_interpreter() {
+        check(&programs::ALU);
+    }
+
+}
@@ -0,0 +1,33 @@
+// The emulator core and everything around it that doesn't need SDL, the
+// SDL frontend lives in main.rs
+
+pub mod analyzer;
+pub mod broadcast;
+pub mod buzzer;
+pub mod capi;
+pub mod cdp1802;
+pub mod config;
+pub mod cpu;
+pub mod gym;
+pub mod instruction;
+pub mod movie;
+pub mod netplay;
+pub mod options;
+pub mod palette;
+pub mod phosphor;
+pub mod recorder;
+pub mod romdb;
+pub mod rpc;
+pub mod savestate;
+pub mod screenshot;
+pub mod timing;
+pub mod vip;
+
+#[cfg(feature = "jit")]
+pub mod jit;
+
+#[cfg(feature = "libretro")]
+pub mod libretro;
+
+#[cfg(feature = "python")]
+pub mod python;
@@ -0,0 +1,463 @@
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+
+use crate::analyzer;
+use crate::buzzer::{Buzzer, SAMPLE_RATE};
+use crate::cpu::{Quirks, CPU};
+use crate::palette::Palette;
+use crate::savestate;
+
+// A libretro core, built with `cargo build --release --features libretro`
+// and installed as system8_libretro.so (.dylib, .dll) in the cores folder of
+// RetroArch or any other frontend. The frontend calls the retro_* functions
+// below from one thread, the core keeps its state in CORE between calls
+
+// The bits of libretro.h this core needs
+const ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const ENVIRONMENT_GET_VARIABLE: c_uint = 15;
+const ENVIRONMENT_SET_VARIABLES: c_uint = 16;
+const ENVIRONMENT_GET_VARIABLE_UPDATE: c_uint = 17;
+const PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const DEVICE_JOYPAD: c_uint = 1;
+const DEVICE_KEYBOARD: c_uint = 3;
+const MEMORY_SYSTEM_RAM: c_uint = 2;
+const REGION_NTSC: c_uint = 0;
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct SystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool
+}
+
+#[repr(C)]
+pub struct GameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32
+}
+
+#[repr(C)]
+pub struct SystemTiming {
+    fps: f64,
+    sample_rate: f64
+}
+
+#[repr(C)]
+pub struct SystemAvInfo {
+    geometry: GameGeometry,
+    timing: SystemTiming
+}
+
+#[repr(C)]
+pub struct GameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char
+}
+
+#[repr(C)]
+struct Variable {
+    key: *const c_char,
+    value: *const c_char
+}
+
+// The core options, the first value of each is the default. auto uses the
+// quirks the analyzer guesses from the ROM
+const QUIRKS_OPTION: &[u8] = b"rust8_quirks\0";
+const SPEED_OPTION: &[u8] = b"rust8_speed\0";
+const PALETTE_OPTION: &[u8] = b"rust8_palette\0";
+const VARIABLES: [(&[u8], &[u8]); 3] = [
+    (QUIRKS_OPTION, b"Quirk profile; auto|rust8|chip8|schip|xochip|vip\0"),
+    (SPEED_OPTION, b"Instructions per frame; 10|5|7|8|12|15|20|30|50|100|200|500|1000\0"),
+    (PALETTE_OPTION, b"Palette; default|green|amber|lcd|octo|hotdog|gray|cga0|cga1\0")
+];
+
+// CHIP-8 key for every RetroPad button in libretro order (B, Y, Select,
+// Start, Up, Down, Left, Right, A, X, L, R, L2, R2, L3, R3). Most games
+// move with 2, 4, 6 and 8 and use 5 to act
+const JOYPAD: [usize; 16] = [0x0, 0xB, 0x1, 0x3, 0x2, 0x8, 0x4, 0x6, 0x5, 0xA, 0xC, 0xD, 0xE, 0xF, 0x7, 0x9];
+
+// The keyboard uses the same 1234/QWER/ASDF/ZXCV layout as the SDL
+// frontend, libretro key codes are lowercase ASCII
+const KEYBOARD: [u8; 16] = *b"x123qweasdzc4rfv";
+
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>
+}
+
+struct Game {
+    cpu: Box<CPU>,
+    rom: Vec<u8>,
+    guessed: Quirks,
+    speed: u32,
+    palette: Palette,
+    buzzer: Buzzer,
+    rgb: Vec<u8>,
+    xrgb: Vec<u32>
+}
+
+struct Core {
+    callbacks: Callbacks,
+    game: Option<Game>
+}
+
+static CORE: Mutex<Core> = Mutex::new(Core {
+    callbacks: Callbacks {
+        environment: None,
+        video_refresh: None,
+        audio_sample_batch: None,
+        input_poll: None,
+        input_state: None
+    },
+    game: None
+});
+
+fn core() -> std::sync::MutexGuard<'static, Core> {
+    // A panic while holding the lock doesn't leave anything half done
+    CORE.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl Core {
+
+    unsafe fn environment(&self, cmd: c_uint, data: *mut c_void) -> bool {
+        match self.callbacks.environment {
+            Some(environment) => environment(cmd, data),
+            None => false
+        }
+    }
+
+    unsafe fn variable(&self, key: &[u8]) -> Option<String> {
+        let mut variable = Variable { key: key.as_ptr() as *const c_char, value: ptr::null() };
+        if !self.environment(ENVIRONMENT_GET_VARIABLE, &mut variable as *mut _ as *mut c_void)
+            || variable.value.is_null() {
+            return None;
+        }
+        CStr::from_ptr(variable.value).to_str().ok().map(|value| value.to_string())
+    }
+
+    // Reads the core options again, they can change while the game runs
+    unsafe fn apply_options(&mut self) {
+        let quirks = self.variable(QUIRKS_OPTION);
+        let speed = self.variable(SPEED_OPTION).and_then(|speed| speed.parse().ok());
+        let palette = self.variable(PALETTE_OPTION).and_then(|theme| Palette::theme(&theme).ok());
+        if let Some(game) = &mut self.game {
+            game.cpu.quirks = match quirks.as_deref() {
+                Some("auto") | None => game.guessed,
+                Some(profile) => Quirks::from_profile(profile).unwrap_or(game.guessed)
+            };
+            game.speed = speed.unwrap_or(10);
+            game.palette = palette.unwrap_or_default();
+        }
+    }
+
+    unsafe fn poll_keypad(&self) -> u16 {
+        let (poll, state) = match (self.callbacks.input_poll, self.callbacks.input_state) {
+            (Some(poll), Some(state)) => (poll, state),
+            _ => return 0
+        };
+        poll();
+        let mut keys = 0;
+        for (button, &key) in JOYPAD.iter().enumerate() {
+            if state(0, DEVICE_JOYPAD, 0, button as c_uint) != 0 {
+                keys |= 1 << key;
+            }
+        }
+        for (key, &code) in KEYBOARD.iter().enumerate() {
+            if state(0, DEVICE_KEYBOARD, 0, code as c_uint) != 0 {
+                keys |= 1 << key;
+            }
+        }
+        keys
+    }
+
+}
+
+impl Game {
+
+    fn new(rom: &[u8], seed: u64) -> Result<Game, String> {
+        let mut game = Game {
+            cpu: Box::new(CPU::initialize()),
+            rom: rom.to_vec(),
+            guessed: analyzer::analyze(rom).quirks,
+            speed: 10,
+            palette: Palette::default(),
+            buzzer: Buzzer::new(),
+            rgb: Vec::new(),
+            xrgb: Vec::new()
+        };
+        game.cpu.quirks = game.guessed;
+        game.cpu.reseed(seed);
+        game.cpu.load_fontset();
+        game.cpu.load_rom(rom)?;
+        Ok(game)
+    }
+
+    // Back to power on keeping the options, the memory stays where it is
+    // because the frontend may hold a pointer to it
+    fn reset(&mut self) {
+        let mut cpu = CPU::initialize();
+        cpu.quirks = self.cpu.quirks;
+        cpu.reseed(self.cpu.seed);
+        cpu.load_fontset();
+        // It already fit when the game was loaded
+        let _ = cpu.load_rom(&self.rom);
+        *self.cpu = cpu;
+    }
+
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    1
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_set_environment(environment: EnvironmentFn) {
+    let mut core = core();
+    core.callbacks.environment = Some(environment);
+    let mut variables: Vec<Variable> = VARIABLES.iter()
+        .map(|(key, value)| Variable { key: key.as_ptr() as *const c_char, value: value.as_ptr() as *const c_char })
+        .collect();
+    variables.push(Variable { key: ptr::null(), value: ptr::null() });
+    core.environment(ENVIRONMENT_SET_VARIABLES, variables.as_mut_ptr() as *mut c_void);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(video_refresh: VideoRefreshFn) {
+    core().callbacks.video_refresh = Some(video_refresh);
+}
+
+// Everything goes through the batch callback
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_audio_sample: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(audio_sample_batch: AudioSampleBatchFn) {
+    core().callbacks.audio_sample_batch = Some(audio_sample_batch);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(input_poll: InputPollFn) {
+    core().callbacks.input_poll = Some(input_poll);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(input_state: InputStateFn) {
+    core().callbacks.input_state = Some(input_state);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    core().game = None;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut SystemInfo) {
+    if let Some(info) = info.as_mut() {
+        *info = SystemInfo {
+            library_name: b"rust8\0".as_ptr() as *const c_char,
+            library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+            valid_extensions: b"ch8|c8\0".as_ptr() as *const c_char,
+            need_fullpath: false,
+            block_extract: false
+        };
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut SystemAvInfo) {
+    let (width, height) = match &core().game {
+        Some(game) => game.cpu.display_size(),
+        None => (64, 32)
+    };
+    if let Some(info) = info.as_mut() {
+        *info = SystemAvInfo {
+            geometry: GameGeometry {
+                base_width: width as c_uint,
+                base_height: height as c_uint,
+                // Room for SCHIP hires
+                max_width: 128,
+                max_height: 64,
+                aspect_ratio: 2.0
+            },
+            timing: SystemTiming { fps: 60.0, sample_rate: SAMPLE_RATE as f64 }
+        };
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(game) = &mut core().game {
+        game.reset();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_run() {
+    let mut core = core();
+    let mut updated = false;
+    if core.environment(ENVIRONMENT_GET_VARIABLE_UPDATE, &mut updated as *mut bool as *mut c_void) && updated {
+        core.apply_options();
+    }
+    let keys = core.poll_keypad();
+    let (video_refresh, audio_sample_batch) = (core.callbacks.video_refresh, core.callbacks.audio_sample_batch);
+    let game = match &mut core.game {
+        Some(game) => game,
+        None => return
+    };
+
+    game.cpu.set_keypad(keys);
+    // The frontend writes memory through memory_ptr behind our back
+    // (cheats, the memory viewer) so nothing decoded can be trusted from
+    // one frame to the next
+    game.cpu.cache.clear();
+    if !game.cpu.breakexe {
+        game.cpu.run_frame(game.speed);
+    }
+
+    let (width, height) = game.cpu.display_size();
+    game.rgb.resize(width * height * 3, 0);
+    game.palette.render(&game.cpu.gfx, &mut game.rgb);
+    game.xrgb = game.rgb.chunks_exact(3)
+        .map(|rgb| (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32)
+        .collect();
+    if let Some(video_refresh) = video_refresh {
+        video_refresh(game.xrgb.as_ptr() as *const c_void, width as c_uint, height as c_uint, width * 4);
+    }
+
+    // The batch is stereo, the buzzer goes to both sides
+    let samples: Vec<i16> = game.buzzer.frame(game.cpu.sound_timer > 0).iter()
+        .flat_map(|&sample| [sample, sample])
+        .collect();
+    if let Some(audio_sample_batch) = audio_sample_batch {
+        let mut sent = 0;
+        while sent < samples.len() / 2 {
+            let frames = audio_sample_batch(samples[sent * 2..].as_ptr(), samples.len() / 2 - sent);
+            if frames == 0 {
+                break;
+            }
+            sent += frames;
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match &core().game {
+        Some(game) => savestate::save(&game.cpu).len(),
+        None => 0
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    if let Some(game) = &core().game {
+        let state = savestate::save(&game.cpu);
+        if !data.is_null() && size >= state.len() {
+            ptr::copy_nonoverlapping(state.as_ptr(), data as *mut u8, state.len());
+            return true;
+        }
+    }
+    false
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    match &mut core().game {
+        Some(game) if !data.is_null() => {
+            savestate::load(&mut game.cpu, slice::from_raw_parts(data as *const u8, size)).is_ok()
+        },
+        _ => false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(info: *const GameInfo) -> bool {
+    let info = match info.as_ref() {
+        Some(info) if !info.data.is_null() => info,
+        _ => return false
+    };
+    let mut core = core();
+    let mut format = PIXEL_FORMAT_XRGB8888;
+    if !core.environment(ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+        return false;
+    }
+    let rom = slice::from_raw_parts(info.data as *const u8, info.size);
+    // Frontends expect the same game to play the same, so the seed is fixed
+    match Game::new(rom, 0) {
+        Ok(game) => {
+            core.game = Some(game);
+            core.apply_options();
+            true
+        },
+        Err(_) => false
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const GameInfo,
+                                          _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    core().game = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    REGION_NTSC
+}
+
+// Lets frontends show the 4K of RAM in their memory viewers and search it
+// for cheats
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match &mut core().game {
+        Some(game) if id == MEMORY_SYSTEM_RAM => game.cpu.memory_ptr() as *mut c_void,
+        _ => ptr::null_mut()
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match &core().game {
+        Some(game) if id == MEMORY_SYSTEM_RAM => game.cpu.memory().len(),
+        _ => 0
+    }
+}
@@ -1,245 +1,232 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
-use std::time::Duration;
-use std::thread;
 use std::env;
+use std::path::PathBuf;
+use std::process;
 
-extern crate sdl2;
-
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::PixelFormatEnum;
-use sdl2::rect::Rect;
-
-mod cpu;
-
-// The CHIP-8 fontset
-static CHIP8_FONTSET: [u8; 80] =
-[
-    0xF0, 0x90, 0x90, 0x90, 0xF0, //0
-    0x20, 0x60, 0x20, 0x20, 0x70, //1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, //2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, //3
-    0x90, 0x90, 0xF0, 0x10, 0x10, //4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, //5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, //6
-    0xF0, 0x10, 0x20, 0x40, 0x40, //7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, //8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, //9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, //A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, //B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, //C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, //D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, //E
-    0xF0, 0x80, 0xF0, 0x80, 0x80  //F
-];
-
-fn read_rom(romfile: &String) -> std::io::Result<Vec<u8>> {
+use system8::{analyzer, broadcast, config, cpu, movie, netplay, options, recorder, romdb, rpc, vip};
+
+#[cfg(feature = "sdl")]
+mod screen;
+#[cfg(feature = "sdl")]
+mod sdl;
+mod headless;
+mod session;
+mod tui;
+
+fn read_rom(romfile: &str) -> std::io::Result<Vec<u8>> {
     let mut file = File::open(romfile)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
-    return Ok(data);
+    Ok(data)
 }
 
-fn main() -> Result<(), String>  {
+// The window unless the terminal was asked for or there's no SDL
+#[cfg(feature = "sdl")]
+fn run_frontend(session: &mut session::Session, options: &options::Options) -> Result<(), String> {
+    if options.tui {
+        tui::run(session, options.braille)
+    } else {
+        sdl::run(session)
+    }
+}
 
-    // APP INIT ------
+#[cfg(not(feature = "sdl"))]
+fn run_frontend(session: &mut session::Session, options: &options::Options) -> Result<(), String> {
+    tui::run(session, options.braille)
+}
 
-    // Get console arguments
-    // args[1]: The rom filename
-    let args: Vec<String> = env::args().collect();
+// Spectators get the same frontend choice as players
+#[cfg(feature = "sdl")]
+fn run_viewer(viewer: &mut broadcast::Viewer, settings: &config::Settings, title: &str,
+              options: &options::Options) -> Result<(), String> {
+    if options.tui {
+        tui::watch(viewer, &settings.palette, options.braille, title)
+    } else {
+        sdl::watch(viewer, settings, title)
+    }
+}
 
-    // Helpers and SDL2 graphics initialization
-    let sdl_context = sdl2::init()?;
-    let video_subsystem = sdl_context.video()?;
-    let window = video_subsystem.window("Rust 8", 640, 320)
-        .position_centered()
-        .opengl()
-        .build()
-        .map_err(|e| e.to_string())?;
-    let mut pixels: [u8; (64 * 32) * 3] = [0; (64 * 32) * 3];
-    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-        .create_texture_streaming(PixelFormatEnum::RGB24, 64, 32)
-        .map_err(|e| e.to_string())?;
-    canvas.clear();
-    canvas.copy(&texture, None, Some(Rect::new(0, 0, 640, 320)))?;
-    canvas.present();
-
-    // SDL2 Keyboard event pump
-    let mut event_pump = sdl_context.event_pump().unwrap();
+#[cfg(not(feature = "sdl"))]
+fn run_viewer(viewer: &mut broadcast::Viewer, settings: &config::Settings, title: &str,
+              options: &options::Options) -> Result<(), String> {
+    tui::watch(viewer, &settings.palette, options.braille, title)
+}
 
-    // Initialize the cpu system
-    let mut cpu = cpu::CPU::initialize();
+// Shows somebody else's game, there's no ROM or CPU here, only the screen
+fn watch(options: &options::Options) -> Result<(), String> {
+    let config = config::Config::load(options.config.as_deref())?;
+    let settings = config::Settings::resolve(&config, cpu::Quirks::default(), None, "", options)?;
+    let mut viewer = broadcast::Viewer::connect(&options.rom)?;
+    let title = format!("Rust 8 - watching {}", options.rom);
+    run_viewer(&mut viewer, &settings, &title, options)
+}
 
-    // CHIP-8 INIT ------
+fn main() {
+    if let Err(error) = run() {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String>  {
+
+    // APP INIT ------
+
+    // Get console arguments, see options::USAGE
+    let args: Vec<String> = env::args().collect();
+    let options = options::Options::parse(&args)?;
+    if options.command == options::Command::Watch {
+        return watch(&options);
+    }
 
     // Read the ROM
-    let rom = match read_rom(&args[1]) {
-        Ok(value) => value,
-        Err(_error) => Vec::new() // TODO: Not let this silently fail!
+    let rom = read_rom(&options.rom)
+        .map_err(|e| format!("Can't read ROM {}: {}", options.rom, e))?;
+
+    let config = config::Config::load(options.config.as_deref())?;
+    let romdb = romdb::RomDb::load(options.romdb.as_deref().or(config.romdb.as_deref()))?;
+    let sha1 = config::rom_sha1(&rom);
+    let entry = romdb.lookup(&sha1);
+    let report = analyzer::analyze(&rom);
+
+    if options.command == options::Command::Info {
+        println!("ROM: {}", options.rom);
+        println!("SHA-1: {}", sha1);
+        println!("Size: {} bytes", rom.len());
+        if let Some(description) = entry.and_then(|entry| entry.description()) {
+            println!("Title: {}", description);
+        }
+        print!("{}", report);
+        return Ok(());
+    }
+
+    // Settings from the analyzer guess, the config file, the ROM database,
+    // the ROM specific section and the command line in that order
+    let mut settings = config::Settings::resolve(&config, report.quirks, entry, &sha1, &options)?;
+
+    // A movie brings the settings it was recorded with
+    let player = match &options.play {
+        Some(path) => {
+            let movie = movie::Movie::load(path)?;
+            if movie.rom_sha1 != sha1 {
+                return Err(format!("The movie was recorded with ROM {} but {} is {}",
+                                   movie.rom_sha1, options.rom, sha1));
+            }
+            settings.speed = movie.speed;
+            settings.quirks = movie.quirks;
+            Some(movie::Player::new(movie))
+        },
+        None => None
+    };
+    let title = match entry.and_then(|entry| entry.description()) {
+        Some(description) => format!("Rust 8 - {}", description),
+        None => "Rust 8".to_string()
     };
 
-    // Load the chip8 font
-    for i in 0..80 {
-        cpu.memory[i] = CHIP8_FONTSET[i];
+    let recorder = match &options.record {
+        Some(path) => {
+            let scale = settings.screenshot_scale as usize;
+            Some(recorder::Recorder::create(path, (64, 32), &settings.palette, scale)?)
+        },
+        None => None
+    };
+
+    // Initialize the cpu system
+    let mut cpu = cpu::CPU::initialize();
+    cpu.quirks = settings.quirks;
+    if let Some(seed) = player.as_ref().map(|p| p.movie().seed).or(options.seed) {
+        cpu.reseed(seed);
     }
 
-    // Load ROM into the memory
-    for (x, &val) in rom.iter().enumerate() {
-        cpu.memory[0x200 + x] = val;
+    // Both netplay sides need the same speed, quirks and seed, the host's
+    let netplay = match (options.host, &options.join) {
+        (Some(port), _) => {
+            eprintln!("Waiting for the other player on UDP port {}", port);
+            let game = netplay::Game { rom_sha1: sha1.clone(), seed: cpu.seed, speed: settings.speed,
+                                       quirks: settings.quirks };
+            Some(netplay::Netplay::host(port, game)?)
+        },
+        (None, Some(address)) => {
+            let netplay = netplay::Netplay::join(address, &sha1)?;
+            let game = netplay.game();
+            settings.speed = game.speed;
+            settings.quirks = game.quirks;
+            cpu.quirks = game.quirks;
+            cpu.reseed(game.seed);
+            Some(netplay)
+        },
+        _ => None
+    };
+    if let Some(netplay) = &netplay {
+        eprintln!("Playing with {}", netplay.peer());
     }
+    let movie = options.movie.as_ref()
+        .map(|_| movie::Movie::new(&sha1, cpu.seed, settings.speed, settings.quirks));
 
-    // MAIN LOOP ----
+    // CHIP-8 INIT ------
 
-    'mainloop: loop {
-        // TODO: Maybe make this block of code cleaner?
-        for event in event_pump.poll_iter() {
-            match event {
-                Event::KeyDown { keycode: Some(Keycode::X), ..} => {
-                    cpu.key[0] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::Num1), ..} => {
-                    cpu.key[1] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::Num2), ..} => {
-                    cpu.key[2] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::Num3), ..} => {
-                    cpu.key[3] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::Q), ..} => {
-                    cpu.key[4] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::W), ..} => {
-                    cpu.key[5] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::E), ..} => {
-                    cpu.key[6] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::A), ..} => {
-                    cpu.key[7] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::S), ..} => {
-                    cpu.key[8] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::D), ..} => {
-                    cpu.key[9] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::Z), ..} => {
-                    cpu.key[10] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::C), ..} => {
-                    cpu.key[11] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::Num4), ..} => {
-                    cpu.key[12] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::R), ..} => {
-                    cpu.key[13] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::F), ..} => {
-                    cpu.key[14] = 1
-                },
-                Event::KeyDown { keycode: Some(Keycode::V), ..} => {
-                    cpu.key[15] = 1
-                },
-                Event::KeyUp { keycode: Some(Keycode::X), ..} => {
-                    cpu.key[0] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::Num1), ..} => {
-                    cpu.key[1] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::Num2), ..} => {
-                    cpu.key[2] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::Num3), ..} => {
-                    cpu.key[3] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::Q), ..} => {
-                    cpu.key[4] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::W), ..} => {
-                    cpu.key[5] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::E), ..} => {
-                    cpu.key[6] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::A), ..} => {
-                    cpu.key[7] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::S), ..} => {
-                    cpu.key[8] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::D), ..} => {
-                    cpu.key[9] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::Z), ..} => {
-                    cpu.key[10] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::C), ..} => {
-                    cpu.key[11] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::Num4), ..} => {
-                    cpu.key[12] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::R), ..} => {
-                    cpu.key[13] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::F), ..} => {
-                    cpu.key[14] = 0
-                },
-                Event::KeyUp { keycode: Some(Keycode::V), ..} => {
-                    cpu.key[15] = 0
-                },
-                Event::KeyDown { keycode: Some(Keycode::Escape), .. } |
-                Event::Quit { .. } => break 'mainloop,
-                _ => {}
-            }
-        }
+    // Load the chip8 font and the ROM into the memory
+    cpu.load_fontset();
+    cpu.load_rom(&rom).map_err(|e| format!("{}: {}", options.rom, e))?;
 
-        // Fetch opcode
-        cpu.fetch_opcode();
+    // MAIN LOOP ----
 
-        // Decrease delay timer
-        if cpu.delay_timer > 0 {
-            cpu.delay_timer -= 1;
-        }
+    let rpc = match &options.rpc {
+        Some(address) => {
+            let server = rpc::Server::bind(address)?;
+            eprintln!("Listening for JSON-RPC on {}", server.address());
+            Some(server)
+        },
+        None => None
+    };
 
-        // If sound timer reaches 0 let's beep
-        if cpu.sound_timer > 0 { 
-            if cpu.sound_timer == 1 {
-                // TODO: Some real sound but looks difficult in SDL2 :(
-                println!("BEEEP");
-            }
-            cpu.sound_timer -= 1
-        }
+    let vip = match (&options.vip, &options.vip_interpreter) {
+        (Some(monitor), Some(interpreter)) => {
+            let read = |path: &PathBuf| fs::read(path)
+                .map_err(|e| format!("Can't read {}: {}", path.display(), e));
+            Some(vip::Vip::new(&read(monitor)?, &read(interpreter)?, &rom)?)
+        },
+        _ => None
+    };
 
-        // Draw the screen
-        if cpu.draw {
-            for (y, &val) in cpu.gfx.iter().enumerate() {
-                let offset = y * 3;
-                let bitval = 255 * val;
-                pixels[offset] = bitval;
-                pixels[offset + 1] = bitval;
-                pixels[offset + 2] = bitval;
-            }
-            texture.update(None, &pixels, 64 * 3).map_err(|e| e.to_string())?;
-            canvas.clear();
-            canvas.copy(&texture, None, Some(Rect::new(0, 0, 640, 320)))?;
-            canvas.present();
-            cpu.draw = false;
-        }
+    #[cfg(feature = "jit")]
+    let jit = if options.jit { Some(system8::jit::Jit::new(options.jit_check)?) } else { None };
+    #[cfg(not(feature = "jit"))]
+    if options.jit {
+        return Err("This build has no JIT, rebuild it with --features jit".to_string());
+    }
 
-        // Just a flag to break the loop if an unknown instruction is found
-        if cpu.breakexe {
-            break
-        }
+    let broadcaster = match &options.broadcast {
+        Some(address) => {
+            let broadcaster = broadcast::Broadcaster::bind(address)?;
+            eprintln!("Broadcasting on {}", broadcaster.address());
+            Some(broadcaster)
+        },
+        None => None
+    };
 
-        // Let's sleep for a while instead of fetching the next instruction
-        thread::sleep(Duration::from_millis(2))
+    let mut session = session::Session {
+        cpu,
+        settings,
+        title,
+        player,
+        movie,
+        recorder,
+        rpc,
+        netplay,
+        broadcaster,
+        #[cfg(feature = "jit")]
+        jit,
+        vip,
+        paused: false,
+        frame: 0
+    };
+    match options.frames {
+        Some(frames) => headless::run(&mut session, frames)?,
+        None => run_frontend(&mut session, &options)?
     }
+    session.finish(options.movie.as_deref())?;
 
     Ok(())
 
@@ -0,0 +1,206 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use crate::cpu::{Quirks, CPU};
+
+// Everything needed to replay a game exactly: the ROM, the random seed, the
+// quirks and speed it ran with and every change of the keypad by frame.
+// It's saved as text so it can be read and attached to bug reports:
+//
+//   rust8 movie 1
+//   rom 0123456789abcdef0123456789abcdef01234567
+//   seed 42
+//   speed 10
+//   quirks shift=true memory=false logic=false wrap=true timing=false release=true
+//   input 120 0020
+//   input 128 0000
+//   end 600 89abcdef0123456789abcdef0123456789abcdef
+pub struct Movie {
+    pub rom_sha1: String,
+    pub seed: u64,
+    pub speed: u32,
+    pub quirks: Quirks,
+    // Frame number and the 16 keys as a bitmask, key 0 is the lowest bit
+    pub inputs: Vec<(u64, u16)>,
+    // How many frames were recorded and the state hash after the last one
+    pub end: Option<(u64, String)>
+}
+
+impl Movie {
+
+    pub fn new(rom_sha1: &str, seed: u64, speed: u32, quirks: Quirks) -> Movie {
+        Movie {
+            rom_sha1: rom_sha1.to_string(),
+            seed,
+            speed,
+            quirks,
+            inputs: Vec::new(),
+            end: None
+        }
+    }
+
+    // Call at the start of every frame once the keypad is up to date
+    pub fn record(&mut self, frame: u64, cpu: &CPU) {
+        let keys = cpu.keypad();
+        let last = self.inputs.last().map_or(0, |&(_, keys)| keys);
+        if keys != last {
+            self.inputs.push((frame, keys));
+        }
+    }
+
+    // Call after the last frame has run
+    pub fn finish(&mut self, frames: u64, cpu: &CPU) {
+        self.end = Some((frames, state_hash(cpu)));
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let mut text = String::new();
+        let q = &self.quirks;
+        // Writing to a String can't fail
+        let _ = writeln!(text, "rust8 movie 1");
+        let _ = writeln!(text, "rom {}", self.rom_sha1);
+        let _ = writeln!(text, "seed {}", self.seed);
+        let _ = writeln!(text, "speed {}", self.speed);
+        let _ = writeln!(text, "quirks shift={} memory={} logic={} wrap={} timing={} release={}",
+                         q.shift, q.memory, q.logic, q.wrap, q.timing, q.release);
+        for (frame, keys) in &self.inputs {
+            let _ = writeln!(text, "input {} {:04x}", frame, keys);
+        }
+        if let Some((frames, hash)) = &self.end {
+            let _ = writeln!(text, "end {} {}", frames, hash);
+        }
+        fs::write(path, text).map_err(|e| format!("Can't write {}: {}", path.display(), e))
+    }
+
+    pub fn load(path: &Path) -> Result<Movie, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let invalid = |line: &str| format!("Invalid movie {}: {}", path.display(), line);
+        let mut lines = text.lines();
+        if lines.next() != Some("rust8 movie 1") {
+            return Err(invalid("not a rust8 movie"));
+        }
+        let mut movie = Movie::new("", 0, 10, Quirks::default());
+        // Movies from before the release quirk finished FX0A on the press
+        movie.quirks.release = false;
+        for line in lines.filter(|line| !line.trim().is_empty()) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                ["rom", sha1] => movie.rom_sha1 = sha1.to_string(),
+                ["seed", seed] => movie.seed = seed.parse().map_err(|_| invalid(line))?,
+                ["speed", speed] => movie.speed = speed.parse().map_err(|_| invalid(line))?,
+                ["quirks", quirks @ ..] => {
+                    for quirk in quirks {
+                        let (name, value) = quirk.split_once('=').ok_or_else(|| invalid(line))?;
+                        let value = value.parse().map_err(|_| invalid(line))?;
+                        match name {
+                            "shift" => movie.quirks.shift = value,
+                            "memory" => movie.quirks.memory = value,
+                            "logic" => movie.quirks.logic = value,
+                            "wrap" => movie.quirks.wrap = value,
+                            "timing" => movie.quirks.timing = value,
+                            "release" => movie.quirks.release = value,
+                            _ => return Err(invalid(line))
+                        }
+                    }
+                },
+                ["input", frame, keys] => {
+                    let frame = frame.parse().map_err(|_| invalid(line))?;
+                    let keys = u16::from_str_radix(keys, 16).map_err(|_| invalid(line))?;
+                    movie.inputs.push((frame, keys));
+                },
+                ["end", frames, hash] => {
+                    let frames = frames.parse().map_err(|_| invalid(line))?;
+                    movie.end = Some((frames, hash.to_string()));
+                },
+                _ => return Err(invalid(line))
+            }
+        }
+        Ok(movie)
+    }
+
+}
+
+// Feeds the keypad from a movie instead of the keyboard
+pub struct Player {
+    movie: Movie,
+    next: usize
+}
+
+impl Player {
+
+    pub fn new(movie: Movie) -> Player {
+        Player { movie, next: 0 }
+    }
+
+    pub fn movie(&self) -> &Movie {
+        &self.movie
+    }
+
+    // Call at the start of every frame, sets the keys the movie had then
+    pub fn apply(&mut self, frame: u64, cpu: &mut CPU) {
+        while let Some(&(at, keys)) = self.movie.inputs.get(self.next) {
+            if at > frame {
+                break;
+            }
+            cpu.set_keypad(keys);
+            self.next += 1;
+        }
+    }
+
+    // Whether every recorded frame has been played
+    pub fn finished(&self, frames: u64) -> bool {
+        match &self.movie.end {
+            Some((end, _)) => frames >= *end,
+            None => self.next >= self.movie.inputs.len()
+        }
+    }
+
+    // Compares the state at the end with the one recorded
+    pub fn verify(&self, cpu: &CPU) -> Result<(), String> {
+        match &self.movie.end {
+            Some((_, expected)) => {
+                let actual = state_hash(cpu);
+                if *expected == actual {
+                    Ok(())
+                } else {
+                    Err(format!("Desync: the movie ended with state {} but playback got {}",
+                                expected, actual))
+                }
+            },
+            None => Err("The movie has no end state to compare with".to_string())
+        }
+    }
+
+}
+
+// SHA-1 of everything that decides what the CPU does next
+pub fn state_hash(cpu: &CPU) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(&cpu.memory()[..]);
+    hasher.update(&cpu.gfx[..]);
+    // As 64 bits like when the registers were usize, so old movies still match
+    let special = [cpu.pc.get() as u64, cpu.i.get() as u64, cpu.sp as u64,
+                   cpu.delay_timer as u64, cpu.sound_timer as u64];
+    let registers = cpu.v.iter().map(|&v| v as u64)
+        .chain(cpu.stack.iter().map(|address| address.get() as u64))
+        .chain(special.iter().copied());
+    for register in registers {
+        hasher.update(register.to_le_bytes());
+    }
+    hasher.update(cpu.keypad().to_le_bytes());
+    hasher.update(cpu.rng.get_word_pos().to_le_bytes());
+    // Only with the timing quirk, the cycles don't do anything without it
+    // and movies from before it have to match
+    if cpu.quirks.timing {
+        hasher.update(cpu.cycles.to_le_bytes());
+    }
+    // Same for a key FX0A waits to be released
+    if let Some(key) = cpu.key_wait {
+        hasher.update([key]);
+    }
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
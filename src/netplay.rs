@@ -0,0 +1,488 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::cpu::{Quirks, CPU};
+use crate::movie::state_hash;
+use crate::savestate;
+
+// Two player netplay over UDP for games like Pong that put both players on
+// one keypad. Both sides run the same game from the same seed and only send
+// each other their keypads, which are ORed together every frame, so each
+// player uses their half of the keys.
+//
+// Waiting for the other side's keys on every frame would make the game as
+// slow as the connection, so when they haven't arrived the last ones are
+// assumed and the game goes on. A save state is kept for every frame that
+// was run on a guess, and when the real keys turn out to be different the
+// game goes back to the first wrong frame and runs again up to the present
+// (rollback). Local keys are only used INPUT_DELAY frames after they're
+// pressed, which hides most of the round trip so rollbacks are rare.
+//
+// Every HASH_INTERVAL frames both sides hash the state once every key up to
+// then is known and compare, a different hash means the games diverged.
+//
+// Every packet starts with MAGIC and a type byte, numbers are little endian:
+//
+//   HELLO    the ROM SHA-1 (40 hex digits), from the player joining
+//   WELCOME  ROM SHA-1, seed u64, speed u32 and quirks u8, from the host
+//   REJECT   why, as UTF-8
+//   INPUT    first frame u64, frames of ours received u64, count u8 and
+//            count keypads u16
+//   HASH     frame u64 and the state hash (40 hex digits)
+
+const MAGIC: &[u8; 4] = b"R8NP";
+const HELLO: u8 = 1;
+const WELCOME: u8 = 2;
+const REJECT: u8 = 3;
+const INPUT: u8 = 4;
+const HASH: u8 = 5;
+
+// Frames between pressing a key and the game seeing it
+pub const INPUT_DELAY: u64 = 2;
+// How far the game can run ahead of the other side's keys before it waits
+pub const MAX_ROLLBACK: u64 = 10;
+// Once a second
+pub const HASH_INTERVAL: u64 = 60;
+
+// The most keypads sent in one packet
+const MAX_INPUTS: usize = 64;
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+const RESEND_INTERVAL: Duration = Duration::from_millis(100);
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What both sides need to agree on for the same keys to play the same game,
+// the host decides and the player joining takes it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Game {
+    pub rom_sha1: String,
+    pub seed: u64,
+    pub speed: u32,
+    pub quirks: Quirks
+}
+
+pub struct Netplay {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    game: Game,
+    host: bool,
+    // The frame about to run
+    frame: u64,
+    // Keypads by frame, the local ones go INPUT_DELAY frames ahead
+    local: Vec<u16>,
+    remote: Vec<u16>,
+    // How many of our keypads the other side has
+    acked: u64,
+    // What was assumed for remote keypads that haven't arrived yet
+    guesses: BTreeMap<u64, u16>,
+    // Save states from the start of every frame that may be run again
+    snapshots: BTreeMap<u64, Vec<u8>>,
+    // The next frame to hash and the hashes waiting for the other side's
+    next_hash: u64,
+    hashes: BTreeMap<u64, String>,
+    remote_hashes: BTreeMap<u64, String>,
+    // The last hash we computed, sent with every frame in case it's lost
+    last_hash: Option<(u64, String)>,
+    // What the keyboard says, the keypad gets it back after every frame
+    keys: u16,
+    stalled: bool,
+    last_heard: Instant,
+    silent: bool,
+    notice: Option<String>,
+    pub rollbacks: u64
+}
+
+impl Netplay {
+
+    // Waits on the UDP port until someone with the same ROM joins
+    pub fn host(port: u16, game: Game) -> Result<Netplay, String> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))
+            .map_err(|e| format!("Can't listen on port {}: {}", port, e))?;
+        let mut buffer = [0; 1500];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(e) => return Err(e.to_string())
+            };
+            if let Some((HELLO, body)) = parse(&buffer[..len]) {
+                if body == game.rom_sha1.as_bytes() {
+                    send(&socket, from, WELCOME, &welcome(&game));
+                    return Netplay::new(socket, from, game, true);
+                }
+                send(&socket, from, REJECT, b"The host is playing a different ROM");
+            }
+        }
+    }
+
+    // Asks the host at HOST:PORT for the game, the ROM has to be the same
+    pub fn join(address: &str, rom_sha1: &str) -> Result<Netplay, String> {
+        let peer = address.to_socket_addrs()
+            .map_err(|e| format!("Can't resolve {}: {}", address, e))?
+            .next()
+            .ok_or_else(|| format!("Can't resolve {}", address))?;
+        let local = if peer.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+        let socket = UdpSocket::bind(local).map_err(|e| e.to_string())?;
+        socket.set_read_timeout(Some(RESEND_INTERVAL)).map_err(|e| e.to_string())?;
+        let started = Instant::now();
+        let mut buffer = [0; 1500];
+        while started.elapsed() < JOIN_TIMEOUT {
+            send(&socket, peer, HELLO, rom_sha1.as_bytes());
+            let len = match socket.recv_from(&mut buffer) {
+                Ok((len, from)) if from == peer => len,
+                _ => continue
+            };
+            match parse(&buffer[..len]) {
+                Some((WELCOME, body)) => {
+                    let game = parse_welcome(body).ok_or("Invalid answer from the host")?;
+                    return Netplay::new(socket, peer, game, false);
+                },
+                Some((REJECT, body)) => return Err(String::from_utf8_lossy(body).to_string()),
+                _ => {}
+            }
+        }
+        Err(format!("Nobody answered at {}", address))
+    }
+
+    fn new(socket: UdpSocket, peer: SocketAddr, game: Game, host: bool) -> Result<Netplay, String> {
+        socket.set_nonblocking(true).map_err(|e| e.to_string())?;
+        Ok(Netplay {
+            socket,
+            peer,
+            game,
+            host,
+            frame: 0,
+            // Nobody can press anything in time for the first frames
+            local: vec![0; INPUT_DELAY as usize],
+            remote: vec![0; INPUT_DELAY as usize],
+            acked: INPUT_DELAY,
+            guesses: BTreeMap::new(),
+            snapshots: BTreeMap::new(),
+            next_hash: 0,
+            hashes: BTreeMap::new(),
+            remote_hashes: BTreeMap::new(),
+            last_hash: None,
+            keys: 0,
+            stalled: false,
+            last_heard: Instant::now(),
+            silent: false,
+            notice: None,
+            rollbacks: 0
+        })
+    }
+
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    // Waiting for the other side, the frontend shouldn't run this frame
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    // Call at the start of every frame once the keypad has the keyboard.
+    // Rolls back if the other side's keys came in different from what was
+    // assumed and leaves the keypad with both players' keys
+    pub fn start_frame(&mut self, cpu: &mut CPU) {
+        self.keys = cpu.keypad();
+        // The same frame comes back here while stalled
+        if self.local.len() as u64 <= self.frame + INPUT_DELAY {
+            self.local.push(self.keys);
+        }
+
+        if let Some(frame) = self.receive() {
+            self.rollback(cpu, frame);
+        }
+
+        self.stalled = self.frame >= self.remote.len() as u64 + MAX_ROLLBACK;
+        if self.stalled {
+            if !self.silent && self.last_heard.elapsed() > SILENCE_TIMEOUT {
+                self.notice = Some(format!("Nothing from {} for a while, waiting", self.peer));
+                self.silent = true;
+            }
+        } else {
+            self.prepare(cpu, self.frame);
+            self.check_hashes(cpu);
+        }
+        self.send_inputs();
+    }
+
+    // Call after the frame has run, gives the keypad back to the keyboard
+    pub fn end_frame(&mut self, cpu: &mut CPU) -> Option<String> {
+        if !self.stalled {
+            self.frame += 1;
+        }
+        cpu.set_keypad(self.keys);
+        self.notice.take()
+    }
+
+    // Handles every packet waiting, returns the first frame that has to run
+    // again if a guess was wrong
+    fn receive(&mut self) -> Option<u64> {
+        let mut rollback = None;
+        let mut buffer = [0; 1500];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                // Like ICMP port unreachable when the other side isn't up yet
+                Err(_) => continue
+            };
+            if from != self.peer {
+                if self.host && parse(&buffer[..len]).is_some() {
+                    send(&self.socket, from, REJECT, b"Someone else is already playing");
+                }
+                continue;
+            }
+            self.last_heard = Instant::now();
+            self.silent = false;
+            match parse(&buffer[..len]) {
+                // Our welcome was lost
+                Some((HELLO, _)) if self.host => {
+                    send(&self.socket, self.peer, WELCOME, &welcome(&self.game));
+                },
+                Some((INPUT, body)) if body.len() >= 17 => {
+                    let start = u64_at(body, 0);
+                    self.acked = self.acked.max(u64_at(body, 8));
+                    let inputs = body[17..].chunks_exact(2).take(body[16] as usize);
+                    for (frame, keys) in (start..).zip(inputs) {
+                        let keys = u16::from_le_bytes([keys[0], keys[1]]);
+                        if frame > self.remote.len() as u64 {
+                            break;
+                        }
+                        if frame < self.remote.len() as u64 {
+                            continue;
+                        }
+                        self.remote.push(keys);
+                        if self.guesses.remove(&frame).is_some_and(|guess| guess != keys) {
+                            rollback.get_or_insert(frame);
+                        }
+                    }
+                },
+                Some((HASH, body)) if body.len() == 48 => {
+                    let frame = u64_at(body, 0);
+                    let hash = String::from_utf8_lossy(&body[8..]).to_string();
+                    self.remote_hashes.insert(frame, hash);
+                    self.compare_hashes();
+                },
+                _ => {}
+            }
+        }
+        rollback
+    }
+
+    // Loads the state from the start of the frame and runs every frame up to
+    // the present again with the keys known now
+    fn rollback(&mut self, cpu: &mut CPU, from: u64) {
+        let snapshot = match self.snapshots.get(&from) {
+            Some(snapshot) => snapshot,
+            None => return
+        };
+        if let Err(error) = savestate::load(cpu, snapshot) {
+            self.notice = Some(format!("Rollback failed: {}", error));
+            return;
+        }
+        for frame in from..self.frame {
+            self.prepare(cpu, frame);
+            // The same as the frontends do in a frame
+            let mut ran = 0;
+            while !cpu.frame_over(ran, self.game.speed) {
+                cpu.fetch_opcode();
+                ran += 1;
+            }
+            cpu.tick_timers();
+        }
+        // What's on screen came from the wrong keys
+        cpu.draw = true;
+        self.rollbacks += 1;
+    }
+
+    // Saves the state and sets the keypad for the frame, remembering what
+    // was assumed for the other side
+    fn prepare(&mut self, cpu: &mut CPU, frame: u64) {
+        self.snapshots.insert(frame, savestate::save(cpu));
+        let remote = match self.remote.get(frame as usize) {
+            Some(&keys) => keys,
+            None => {
+                let guess = self.remote.last().copied().unwrap_or(0);
+                self.guesses.insert(frame, guess);
+                guess
+            }
+        };
+        cpu.set_keypad(self.local[frame as usize] | remote);
+    }
+
+    // Hashes the frames every key is known for and forgets the save states
+    // that can't be needed again
+    fn check_hashes(&mut self, cpu: &CPU) {
+        let confirmed = self.frame.min(self.remote.len() as u64);
+        while self.next_hash <= confirmed {
+            if let Some(snapshot) = self.snapshots.get(&self.next_hash) {
+                let mut state = cpu.clone();
+                if savestate::load(&mut state, snapshot).is_ok() {
+                    // Between frames the keypad only has the local keys
+                    state.set_keypad(0);
+                    let hash = state_hash(&state);
+                    self.hashes.insert(self.next_hash, hash.clone());
+                    self.last_hash = Some((self.next_hash, hash));
+                }
+            }
+            self.next_hash += HASH_INTERVAL;
+        }
+        self.compare_hashes();
+        self.snapshots = self.snapshots.split_off(&confirmed);
+    }
+
+    fn compare_hashes(&mut self) {
+        let frames: Vec<u64> = self.hashes.keys()
+            .filter(|frame| self.remote_hashes.contains_key(frame))
+            .copied()
+            .collect();
+        for frame in frames {
+            let (local, remote) = (&self.hashes[&frame], &self.remote_hashes[&frame]);
+            if local != remote {
+                self.notice = Some(format!("Desync at frame {}: state {} here but {} on the other side",
+                                           frame, local, remote));
+            }
+            self.hashes.remove(&frame);
+        }
+        // Hashes older than the ones compared won't be needed
+        if let Some(&oldest) = self.hashes.keys().next() {
+            self.remote_hashes = self.remote_hashes.split_off(&oldest);
+        } else {
+            self.remote_hashes.clear();
+        }
+    }
+
+    // Sends every keypad the other side hasn't got yet and the last hash,
+    // UDP loses packets so they go again every frame until they get there
+    fn send_inputs(&self) {
+        let start = self.acked.min(self.local.len() as u64);
+        let inputs = &self.local[start as usize..];
+        let inputs = &inputs[..inputs.len().min(MAX_INPUTS)];
+        let mut body = Vec::new();
+        body.extend_from_slice(&start.to_le_bytes());
+        body.extend_from_slice(&(self.remote.len() as u64).to_le_bytes());
+        body.push(inputs.len() as u8);
+        for keys in inputs {
+            body.extend_from_slice(&keys.to_le_bytes());
+        }
+        send(&self.socket, self.peer, INPUT, &body);
+        if let Some((frame, hash)) = &self.last_hash {
+            let mut body = frame.to_le_bytes().to_vec();
+            body.extend_from_slice(hash.as_bytes());
+            send(&self.socket, self.peer, HASH, &body);
+        }
+    }
+
+}
+
+// Lost packets are sent again anyway, so errors are ignored
+fn send(socket: &UdpSocket, to: SocketAddr, kind: u8, body: &[u8]) {
+    let mut packet = MAGIC.to_vec();
+    packet.push(kind);
+    packet.extend_from_slice(body);
+    let _ = socket.send_to(&packet, to);
+}
+
+// The type and the body of a packet, if it's one of ours
+fn parse(packet: &[u8]) -> Option<(u8, &[u8])> {
+    match packet.strip_prefix(&MAGIC[..]) {
+        Some([kind, body @ ..]) => Some((*kind, body)),
+        _ => None
+    }
+}
+
+fn welcome(game: &Game) -> Vec<u8> {
+    let mut body = game.rom_sha1.as_bytes().to_vec();
+    body.extend_from_slice(&game.seed.to_le_bytes());
+    body.extend_from_slice(&game.speed.to_le_bytes());
+    body.push(game.quirks.bits());
+    body
+}
+
+fn parse_welcome(body: &[u8]) -> Option<Game> {
+    if body.len() != 53 {
+        return None;
+    }
+    let mut speed = [0; 4];
+    speed.copy_from_slice(&body[48..52]);
+    Some(Game {
+        rom_sha1: String::from_utf8(body[..40].to_vec()).ok()?,
+        seed: u64_at(body, 40),
+        speed: u32::from_le_bytes(speed),
+        quirks: Quirks::from_bits(body[52])
+    })
+}
+
+fn u64_at(data: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::thread;
+
+    use super::*;
+
+    // Counts in V3 the frames key 1 is held and in V4 key C
+    const ROM: [u8; 14] = [0x60, 0x01, 0x62, 0x0C, 0xE0, 0xA1, 0x73, 0x01, 0xE2, 0xA1, 0x74, 0x01, 0x12, 0x04];
+    const FRAMES: u64 = 300;
+    // Nobody presses anything at the end so the last guesses are right
+    const QUIET: u64 = 40;
+
+    // Plays the whole game pressing key every period frames and sleeping
+    // some milliseconds per frame, returns the final state and rollbacks
+    fn play(mut netplay: Netplay, key: u8, period: u64, sleep: u64) -> (String, u64) {
+        let game = netplay.game().clone();
+        let mut cpu = CPU::initialize();
+        cpu.quirks = game.quirks;
+        cpu.reseed(game.seed);
+        cpu.load_fontset();
+        cpu.load_rom(&ROM).unwrap();
+        let started = Instant::now();
+        let mut frame = 0;
+        while frame < FRAMES + QUIET {
+            assert!(started.elapsed() < Duration::from_secs(20), "The other side stopped answering");
+            let held = frame < FRAMES && (frame / period) % 2 == 1;
+            cpu.set_keypad(if held { 1 << key } else { 0 });
+            netplay.start_frame(&mut cpu);
+            if !netplay.stalled() {
+                let mut ran = 0;
+                while !cpu.frame_over(ran, game.speed) {
+                    cpu.fetch_opcode();
+                    ran += 1;
+                }
+                cpu.tick_timers();
+                frame += 1;
+            }
+            netplay.end_frame(&mut cpu);
+            thread::sleep(Duration::from_millis(sleep));
+        }
+        cpu.set_keypad(0);
+        (state_hash(&cpu), netplay.rollbacks)
+    }
+
+    #[test]
+    fn both_sides_end_the_same_on_loopback() {
+        let port = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let game = Game { rom_sha1: "0".repeat(40), seed: 42, speed: 10, quirks: Quirks::default() };
+        let host = thread::spawn(move || play(Netplay::host(port, game).unwrap(), 1, 7, 0));
+        let joined = Netplay::join(&format!("127.0.0.1:{}", port), &"0".repeat(40)).unwrap();
+        assert_eq!(joined.game().seed, 42);
+        // The slower side makes the host run on guesses
+        let (joined_hash, joined_rollbacks) = play(joined, 0xC, 5, 2);
+        let (host_hash, host_rollbacks) = host.join().unwrap();
+        assert!(host_rollbacks + joined_rollbacks > 0, "Nothing was rolled back");
+        assert_eq!(host_hash, joined_hash);
+    }
+
+}
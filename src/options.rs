@@ -0,0 +1,204 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+pub const USAGE: &str = "Usage: system8 [OPTIONS] ROM
+       system8 info ROM
+       system8 watch [OPTIONS] ADDRESS
+
+Commands:
+    info              Analyze the ROM and print what it needs without running it
+    watch             Show the game broadcast at a TCP port or HOST:PORT
+
+Options:
+    --config FILE     Read the settings from FILE instead of the default config
+    --romdb FILE      Look up known ROMs in the JSON database FILE
+    --speed N         Instructions executed per frame (60 frames per second)
+    --scale N         Initial window size as a multiple of the screen
+    --integer-scale   Only scale the screen by whole numbers
+    --fullscreen      Start in fullscreen, F11 toggles it while playing
+    --phosphor DECAY  Fade pixels out over several frames to hide flicker,
+                      DECAY is the brightness kept per frame (0 to 1)
+    --frame-sync      Present the screen once per frame, not on every draw
+    --no-integer-scale, --no-fullscreen, --no-frame-sync
+                      Turn those off when the config file turns them on
+    --tui             Play in the terminal instead of a window, the only
+                      frontend when built without the sdl feature
+    --braille         Draw the terminal screen with braille dots, smaller
+                      than half blocks but only in two colours
+    --frames N        Run N frames as fast as possible with no window or
+                      terminal and quit
+    --screenshot-dir DIR
+                      Where F12 saves PNG screenshots and F9 GIF recordings
+    --screenshot-scale N
+                      Screenshot and recording size as a multiple of the screen
+    --record FILE     Record the game from the start to FILE, a .gif or a
+                      .y4m video with a .wav file for the buzzer
+    --movie FILE      Record every keypad change to the movie FILE
+    --play FILE       Play the movie FILE back and check it ends in the same state
+    --seed N          Seed for the random numbers of CXNN
+    --jit             Compile the game to native code, needs a build with
+                      the jit feature
+    --jit-check       Like --jit but also run every instruction in the
+                      interpreter and stop where they disagree
+    --rpc ADDRESS     Accept JSON-RPC commands on a local TCP port, HOST:PORT or
+                      unix:PATH, the game keeps running after unknown opcodes
+    --broadcast ADDRESS
+                      Stream the screen and the buzzer to spectators on a TCP
+                      port or HOST:PORT
+    --host PORT       Wait for a second player to join on the UDP PORT, both
+                      keypads are merged into one
+    --join ADDRESS    Play with the host at HOST:PORT, the ROM has to be the
+                      same and the host's speed, quirks and seed are used
+    --vip FILE        Emulate a whole COSMAC VIP with the monitor ROM in FILE
+                      running the original CHIP-8 interpreter
+    --vip-interpreter FILE
+                      The CHIP-8 interpreter the VIP loads at 0000
+    --quirks PROFILE  Quirk profile: rust8, chip8, schip, xochip or vip, which
+                      is chip8 with the timing of the COSMAC VIP
+    --theme NAME      Colour theme: default, green, amber, lcd, octo, hotdog,
+                      gray, cga0 or cga1
+    --foreground RGB  Colour of the lit pixels as #RRGGBB
+    --background RGB  Colour of the unlit pixels as #RRGGBB";
+
+#[derive(Debug, Default, PartialEq)]
+pub enum Command {
+    #[default]
+    Run,
+    Info,
+    Watch
+}
+
+// Command line options, everything but the ROM is optional and takes
+// precedence over whatever the config file says
+#[derive(Debug, Default)]
+pub struct Options {
+    pub command: Command,
+    // The address of the broadcast for watch
+    pub rom: String,
+    pub config: Option<PathBuf>,
+    pub romdb: Option<PathBuf>,
+    pub speed: Option<u32>,
+    pub scale: Option<u32>,
+    pub integer_scale: Option<bool>,
+    pub fullscreen: Option<bool>,
+    pub phosphor: Option<f32>,
+    pub frame_sync: Option<bool>,
+    pub tui: bool,
+    pub braille: bool,
+    pub frames: Option<u64>,
+    pub screenshot_dir: Option<PathBuf>,
+    pub screenshot_scale: Option<u32>,
+    pub record: Option<PathBuf>,
+    pub movie: Option<PathBuf>,
+    pub play: Option<PathBuf>,
+    pub seed: Option<u64>,
+    pub jit: bool,
+    pub jit_check: bool,
+    pub rpc: Option<String>,
+    pub broadcast: Option<String>,
+    pub host: Option<u16>,
+    pub join: Option<String>,
+    pub vip: Option<PathBuf>,
+    pub vip_interpreter: Option<PathBuf>,
+    pub quirks: Option<String>,
+    pub theme: Option<String>,
+    pub foreground: Option<String>,
+    pub background: Option<String>
+}
+
+impl Options {
+
+    // args[0] is the executable name so it's skipped
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut rom = None;
+        let mut args = args.iter().skip(1).peekable();
+        match args.peek().map(|arg| arg.as_str()) {
+            Some("info") => options.command = Command::Info,
+            Some("watch") => options.command = Command::Watch,
+            _ => {}
+        }
+        if options.command != Command::Run {
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => options.config = Some(value(&mut args, arg)?),
+                "--romdb" => options.romdb = Some(value(&mut args, arg)?),
+                "--speed" => options.speed = Some(value(&mut args, arg)?),
+                "--scale" => options.scale = Some(value(&mut args, arg)?),
+                "--integer-scale" => options.integer_scale = Some(true),
+                "--no-integer-scale" => options.integer_scale = Some(false),
+                "--fullscreen" => options.fullscreen = Some(true),
+                "--no-fullscreen" => options.fullscreen = Some(false),
+                "--phosphor" => options.phosphor = Some(value(&mut args, arg)?),
+                "--frame-sync" => options.frame_sync = Some(true),
+                "--no-frame-sync" => options.frame_sync = Some(false),
+                "--tui" => options.tui = true,
+                "--braille" => {
+                    options.tui = true;
+                    options.braille = true;
+                },
+                "--frames" => options.frames = Some(value(&mut args, arg)?),
+                "--screenshot-dir" => options.screenshot_dir = Some(value(&mut args, arg)?),
+                "--screenshot-scale" => options.screenshot_scale = Some(value(&mut args, arg)?),
+                "--record" => options.record = Some(value(&mut args, arg)?),
+                "--movie" => options.movie = Some(value(&mut args, arg)?),
+                "--play" => options.play = Some(value(&mut args, arg)?),
+                "--seed" => options.seed = Some(value(&mut args, arg)?),
+                "--jit" => options.jit = true,
+                "--jit-check" => {
+                    options.jit = true;
+                    options.jit_check = true;
+                },
+                "--rpc" => options.rpc = Some(value(&mut args, arg)?),
+                "--broadcast" => options.broadcast = Some(value(&mut args, arg)?),
+                "--host" => options.host = Some(value(&mut args, arg)?),
+                "--join" => options.join = Some(value(&mut args, arg)?),
+                "--vip" => options.vip = Some(value(&mut args, arg)?),
+                "--vip-interpreter" => options.vip_interpreter = Some(value(&mut args, arg)?),
+                "--quirks" => options.quirks = Some(value(&mut args, arg)?),
+                "--theme" => options.theme = Some(value(&mut args, arg)?),
+                "--foreground" => options.foreground = Some(value(&mut args, arg)?),
+                "--background" => options.background = Some(value(&mut args, arg)?),
+                flag if flag.starts_with("--") => {
+                    return Err(format!("Unknown option {}\n\n{}", flag, USAGE))
+                },
+                _ if rom.is_some() => {
+                    return Err(format!("Only one ROM can be loaded\n\n{}", USAGE))
+                },
+                _ => rom = Some(arg.clone())
+            }
+        }
+        options.rom = rom.ok_or_else(|| USAGE.to_string())?;
+        if options.movie.is_some() && options.play.is_some() {
+            return Err("A movie can't be recorded while another one is played".to_string());
+        }
+        if options.host.is_some() && options.join.is_some() {
+            return Err("Netplay can either host or join, not both".to_string());
+        }
+        // Rollbacks rewrite frames that were already recorded or controlled
+        if (options.host.is_some() || options.join.is_some()) &&
+            (options.movie.is_some() || options.play.is_some() || options.rpc.is_some()) {
+            return Err("Netplay can't be combined with movies or the RPC server".to_string());
+        }
+        // Everything else only knows about the CPU struct, the VIP is just
+        // shown through it
+        if options.vip.is_some() != options.vip_interpreter.is_some() {
+            return Err("--vip and --vip-interpreter go together".to_string());
+        }
+        if options.vip.is_some() && (options.host.is_some() || options.join.is_some() || options.movie.is_some() ||
+                                     options.play.is_some() || options.rpc.is_some() || options.jit) {
+            return Err("The VIP can't be combined with netplay, movies, the RPC server or the JIT".to_string());
+        }
+        Ok(options)
+    }
+
+}
+
+// Takes the value that follows a flag
+fn value<'a, T, I>(args: &mut I, flag: &str) -> Result<T, String>
+    where T: FromStr, I: Iterator<Item = &'a String> {
+    let arg = args.next().ok_or_else(|| format!("Missing value for {}", flag))?;
+    arg.parse().map_err(|_| format!("Invalid value for {}: {}", flag, arg))
+}
@@ -0,0 +1,76 @@
+use crate::config::parse_colour;
+
+// Four colours like XO-CHIP: the background, the first plane, the second
+// plane and the pixels where both planes are set. Plain CHIP-8 only ever
+// uses the first two
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Palette {
+    pub colours: [[u8; 3]; 4]
+}
+
+// Name, background, plane 1, plane 2 and both planes
+const THEMES: [(&str, [u32; 4]); 9] = [
+    ("default", [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555]),
+    ("green", [0x0A1A0A, 0x33FF66, 0x1E9E3E, 0x0F4F1F]),
+    ("amber", [0x1A0F00, 0xFFB000, 0xA67200, 0x533900]),
+    ("lcd", [0xF9FFB3, 0x3D8026, 0xABCC47, 0x00131A]),
+    // The presets of the Octo IDE
+    ("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),
+    ("hotdog", [0x000000, 0xFF0000, 0xFFFF00, 0xFFFFFF]),
+    ("gray", [0xAAAAAA, 0x000000, 0xFFFFFF, 0x666666]),
+    ("cga0", [0x000000, 0x00FF00, 0xFF0000, 0xFFFF00]),
+    ("cga1", [0x000000, 0xFF00FF, 0x00FFFF, 0xFFFFFF])
+];
+
+impl Palette {
+
+    pub fn themes() -> impl Iterator<Item = &'static str> {
+        THEMES.iter().map(|&(name, _)| name)
+    }
+
+    pub fn theme(name: &str) -> Result<Palette, String> {
+        let (_, colours) = THEMES.iter()
+            .find(|&&(theme, _)| theme.eq_ignore_ascii_case(name))
+            .ok_or_else(|| {
+                let themes: Vec<&str> = Palette::themes().collect();
+                format!("Unknown theme {}, try one of: {}", name, themes.join(", "))
+            })?;
+        let mut palette = Palette::default();
+        for (colour, &rgb) in palette.colours.iter_mut().zip(colours.iter()) {
+            *colour = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+        }
+        Ok(palette)
+    }
+
+    // Replaces the first colours with the "#RRGGBB" ones given
+    pub fn set_colours(&mut self, colours: &[String]) -> Result<(), String> {
+        if colours.len() > 4 {
+            return Err("A palette has at most 4 colours".to_string());
+        }
+        for (colour, text) in self.colours.iter_mut().zip(colours) {
+            *colour = parse_colour(text)?;
+        }
+        Ok(())
+    }
+
+    // Every pixel of the framebuffer holds the planes it's lit in as bits
+    pub fn colour(&self, pixel: u8) -> [u8; 3] {
+        self.colours[(pixel & 0b11) as usize]
+    }
+
+    // Converts the framebuffer into RGB24 pixels for a texture
+    pub fn render(&self, gfx: &[u8], rgb: &mut [u8]) {
+        for (&pixel, out) in gfx.iter().zip(rgb.chunks_exact_mut(3)) {
+            out.copy_from_slice(&self.colour(pixel));
+        }
+    }
+
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            colours: [[0, 0, 0], [255, 255, 255], [170, 170, 170], [85, 85, 85]]
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// CHIP-8 games move sprites by erasing them with XOR and drawing them again,
+// on a real CRT the phosphor kept glowing for a while so it didn't flicker.
+// This filter does the same: lit pixels show up at once but pixels that go
+// dark keep a fraction (the decay) of their previous colour every frame
+pub struct Phosphor {
+    decay: f32,
+    // The RGB colours shown on the last frame
+    previous: Vec<f32>,
+    fading: bool
+}
+
+impl Phosphor {
+
+    // A decay of 0 disables the filter
+    pub fn new(decay: f32) -> Phosphor {
+        Phosphor { decay, previous: Vec::new(), fading: false }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.decay > 0.0
+    }
+
+    // Whether some pixel still hasn't reached its colour, the screen
+    // has to be presented again even if the CPU didn't draw anything
+    pub fn fading(&self) -> bool {
+        self.fading
+    }
+
+    // Meant to be called once per 60Hz frame on the RGB pixels rendered
+    // from the framebuffer, the ones that are off get blended
+    pub fn apply(&mut self, gfx: &[u8], rgb: &mut [u8]) {
+        if !self.enabled() {
+            return;
+        }
+        if self.previous.len() != rgb.len() {
+            self.previous = rgb.iter().map(|&c| c as f32).collect();
+        }
+        self.fading = false;
+        for ((&pixel, out), previous) in gfx.iter()
+            .zip(rgb.chunks_exact_mut(3))
+            .zip(self.previous.chunks_exact_mut(3)) {
+            for (channel, last) in out.iter_mut().zip(previous.iter_mut()) {
+                let target = *channel as f32;
+                if pixel == 0 {
+                    *last = target + (*last - target) * self.decay;
+                } else {
+                    *last = target;
+                }
+                if (*last - target).abs() >= 1.0 {
+                    self.fading = true;
+                }
+                *channel = last.round() as u8;
+            }
+        }
+    }
+
+}
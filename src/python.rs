@@ -0,0 +1,327 @@
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+use pyo3::exceptions::{PyBufferError, PyIndexError, PyValueError};
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use crate::cpu::{Address, Quirks, CPU, MEMORY_SIZE};
+use crate::savestate;
+
+// The Python extension module, built with `maturin develop --features python`
+// or `cargo build --release --features python` and renaming the library to
+// system8.so. It wraps the CPU with no frontend at all:
+//
+//   import numpy, system8
+//   chip8 = system8.Chip8(open("PONG", "rb").read(), quirks="chip8")
+//   chip8.press(5)
+//   chip8.run_frames(60)
+//   screen = numpy.asarray(chip8.framebuffer())   # uint8, 32 rows of 64
+//   state = chip8.snapshot()                     # bytes, can be pickled
+#[pymodule]
+fn system8(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Chip8>()?;
+    m.add_class::<Framebuffer>()?;
+    m.add("PROFILES", Quirks::PROFILES.to_vec())?;
+    Ok(())
+}
+
+fn value_error(error: String) -> PyErr {
+    PyValueError::new_err(error)
+}
+
+#[pyclass(module = "system8")]
+pub struct Chip8 {
+    cpu: CPU,
+    rom: Vec<u8>,
+    speed: u32
+}
+
+#[pymethods]
+impl Chip8 {
+
+    #[new]
+    #[pyo3(signature = (rom, quirks = "rust8", speed = 10, seed = None))]
+    fn new(rom: &[u8], quirks: &str, speed: u32, seed: Option<u64>) -> PyResult<Chip8> {
+        let quirks = Quirks::from_profile(quirks).ok_or_else(|| {
+            value_error(format!("Unknown quirks profile {}, use one of {}",
+                                quirks, Quirks::PROFILES.join(", ")))
+        })?;
+        let mut chip8 = Chip8 { cpu: CPU::initialize(), rom: Vec::new(), speed };
+        chip8.cpu.quirks = quirks;
+        if let Some(seed) = seed {
+            chip8.cpu.reseed(seed);
+        }
+        chip8.load_rom(rom)?;
+        Ok(chip8)
+    }
+
+    // Powers on again with another ROM, keeping the quirks and the seed
+    fn load_rom(&mut self, rom: &[u8]) -> PyResult<()> {
+        let mut cpu = CPU::initialize();
+        cpu.quirks = self.cpu.quirks;
+        cpu.reseed(self.cpu.seed);
+        cpu.load_fontset();
+        cpu.load_rom(rom).map_err(value_error)?;
+        self.cpu = cpu;
+        self.rom = rom.to_vec();
+        Ok(())
+    }
+
+    #[pyo3(signature = (seed = None))]
+    fn reset(&mut self, seed: Option<u64>) -> PyResult<()> {
+        if let Some(seed) = seed {
+            self.cpu.seed = seed;
+        }
+        let rom = self.rom.clone();
+        self.load_rom(&rom)
+    }
+
+    // Runs single instructions, the timers don't move. Returns how many ran
+    // which is less than asked if the CPU halted on an unknown opcode
+    #[pyo3(signature = (instructions = 1))]
+    fn step(&mut self, instructions: u32) -> u32 {
+        for ran in 0..instructions {
+            if self.cpu.breakexe {
+                return ran;
+            }
+            self.cpu.fetch_opcode();
+        }
+        instructions
+    }
+
+    // Runs whole 60Hz frames of `speed` instructions each
+    #[pyo3(signature = (frames = 1))]
+    fn run_frames(&mut self, frames: u32) {
+        for _ in 0..frames {
+            if self.cpu.breakexe {
+                break;
+            }
+            self.cpu.run_frame(self.speed);
+        }
+    }
+
+    fn press(&mut self, key: usize) -> PyResult<()> {
+        self.set_key(key, true)
+    }
+
+    fn release(&mut self, key: usize) -> PyResult<()> {
+        self.set_key(key, false)
+    }
+
+    // All 16 keys as a bitmask, key 0 is the lowest bit
+    #[getter]
+    fn keypad(&self) -> u16 {
+        self.cpu.keypad()
+    }
+
+    #[setter]
+    fn set_keypad(&mut self, keys: u16) {
+        self.cpu.set_keypad(keys);
+    }
+
+    #[pyo3(signature = (address, length = 1))]
+    fn read_memory<'py>(&self, py: Python<'py>, address: usize,
+                        length: usize) -> PyResult<Bound<'py, PyBytes>> {
+        let range = memory_range(address, length)?;
+        Ok(PyBytes::new(py, &self.cpu.memory()[range]))
+    }
+
+    fn write_memory(&mut self, address: usize, data: &[u8]) -> PyResult<()> {
+        memory_range(address, data.len())?;
+        self.cpu.write_memory(address, data);
+        Ok(())
+    }
+
+    fn get_register(&self, x: usize) -> PyResult<u8> {
+        self.cpu.v.get(x).copied().ok_or_else(|| PyIndexError::new_err("Registers go from V0 to VF"))
+    }
+
+    fn set_register(&mut self, x: usize, value: u8) -> PyResult<()> {
+        let register = self.cpu.v.get_mut(x)
+            .ok_or_else(|| PyIndexError::new_err("Registers go from V0 to VF"))?;
+        *register = value;
+        Ok(())
+    }
+
+    #[getter]
+    // A list, a Vec<u8> would come out as bytes
+    fn v(&self) -> Vec<u32> {
+        self.cpu.v.iter().map(|&v| v as u32).collect()
+    }
+
+    #[getter]
+    fn pc(&self) -> u16 {
+        self.cpu.pc.get()
+    }
+
+    #[setter]
+    fn set_pc(&mut self, pc: usize) -> PyResult<()> {
+        self.cpu.pc = Address::new(memory_range(pc, 2)?.start);
+        Ok(())
+    }
+
+    #[getter]
+    fn i(&self) -> u16 {
+        self.cpu.i.get()
+    }
+
+    #[setter]
+    fn set_i(&mut self, i: usize) -> PyResult<()> {
+        self.cpu.i = Address::new(memory_range(i, 1)?.start);
+        Ok(())
+    }
+
+    #[getter]
+    fn sp(&self) -> usize {
+        self.cpu.sp
+    }
+
+    #[getter]
+    fn delay_timer(&self) -> u8 {
+        self.cpu.delay_timer
+    }
+
+    #[setter]
+    fn set_delay_timer(&mut self, value: u8) {
+        self.cpu.delay_timer = value;
+    }
+
+    #[getter]
+    fn sound_timer(&self) -> u8 {
+        self.cpu.sound_timer
+    }
+
+    #[setter]
+    fn set_sound_timer(&mut self, value: u8) {
+        self.cpu.sound_timer = value;
+    }
+
+    #[getter]
+    fn halted(&self) -> bool {
+        self.cpu.breakexe
+    }
+
+    #[getter]
+    fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    #[setter]
+    fn set_speed(&mut self, speed: u32) {
+        self.speed = speed;
+    }
+
+    // A copy of the screen that numpy.asarray and memoryview understand
+    fn framebuffer(&self) -> Framebuffer {
+        let (width, height) = self.cpu.display_size();
+        Framebuffer {
+            pixels: self.cpu.gfx[..width * height].to_vec(),
+            shape: [height as isize, width as isize],
+            strides: [width as isize, 1]
+        }
+    }
+
+    // The whole state as bytes, see savestate.rs
+    fn snapshot<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &savestate::save(&self.cpu))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> PyResult<()> {
+        savestate::load(&mut self.cpu, state).map_err(value_error)
+    }
+
+}
+
+impl Chip8 {
+
+    fn set_key(&mut self, key: usize, pressed: bool) -> PyResult<()> {
+        if key > 0xF {
+            return Err(PyIndexError::new_err("Keys go from 0 to F"));
+        }
+        self.cpu.key.set(key as u8, pressed);
+        Ok(())
+    }
+
+}
+
+fn memory_range(address: usize, length: usize) -> PyResult<std::ops::Range<usize>> {
+    match address.checked_add(length) {
+        Some(end) if end <= MEMORY_SIZE => Ok(address..end),
+        _ => Err(PyIndexError::new_err("Memory goes from 0x000 to 0xFFF"))
+    }
+}
+
+// Read only pixels exposed through the buffer protocol, one byte per pixel
+// with the palette index, rows first
+#[pyclass(module = "system8", frozen)]
+pub struct Framebuffer {
+    pixels: Vec<u8>,
+    shape: [isize; 2],
+    strides: [isize; 2]
+}
+
+#[pymethods]
+impl Framebuffer {
+
+    #[getter]
+    fn width(&self) -> isize {
+        self.shape[1]
+    }
+
+    #[getter]
+    fn height(&self) -> isize {
+        self.shape[0]
+    }
+
+    fn __len__(&self) -> usize {
+        self.pixels.len()
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, &self.pixels)
+    }
+
+    // The pixels never change after the framebuffer is made so handing
+    // out pointers to them is fine while the object is alive, which
+    // view.obj makes sure of
+    unsafe fn __getbuffer__(slf: Bound<'_, Self>, view: *mut ffi::Py_buffer,
+                            flags: c_int) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("View is null"));
+        }
+        if flags & ffi::PyBUF_WRITABLE == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("The framebuffer is read only"));
+        }
+        let this = slf.get();
+        (*view).buf = this.pixels.as_ptr() as *mut c_void;
+        (*view).len = this.pixels.len() as isize;
+        (*view).readonly = 1;
+        (*view).itemsize = 1;
+        (*view).format = if flags & ffi::PyBUF_FORMAT == ffi::PyBUF_FORMAT {
+            b"B\0".as_ptr() as *mut c_char
+        } else {
+            ptr::null_mut()
+        };
+        if flags & ffi::PyBUF_ND == ffi::PyBUF_ND {
+            (*view).ndim = 2;
+            (*view).shape = this.shape.as_ptr() as *mut isize;
+        } else {
+            (*view).ndim = 1;
+            (*view).shape = ptr::null_mut();
+        }
+        (*view).strides = if flags & ffi::PyBUF_STRIDES == ffi::PyBUF_STRIDES {
+            this.strides.as_ptr() as *mut isize
+        } else {
+            ptr::null_mut()
+        };
+        (*view).suboffsets = ptr::null_mut();
+        (*view).internal = ptr::null_mut();
+        (*view).obj = slf.into_any().into_ptr();
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, _view: *mut ffi::Py_buffer) {}
+
+}
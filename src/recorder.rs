@@ -0,0 +1,206 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::buzzer::{Buzzer, SAMPLE_RATE};
+use crate::cpu::CPU;
+use crate::palette::Palette;
+use crate::screenshot::scale_pixels;
+
+// Records every 60Hz frame, as an animated GIF or as a Y4M video with a WAV
+// file next to it for the buzzer. The extension of the path picks which
+pub struct Recorder {
+    path: PathBuf,
+    display: (usize, usize),
+    scale: usize,
+    palette: Palette,
+    sink: Sink
+}
+
+enum Sink {
+    Gif(GifSink),
+    Video(VideoSink)
+}
+
+// The GIF palette is the emulator palette so colours are exact, and frames
+// that don't change are merged into one with a longer delay which keeps
+// the files tiny
+struct GifSink {
+    encoder: gif::Encoder<BufWriter<File>>,
+    // The frame waiting to be written and for how many 60Hz frames it lasts
+    pending: Option<(Vec<u8>, u64)>,
+    // 60Hz frames written so far, delays are rounded from it so they don't drift
+    frames: u64
+}
+
+struct VideoSink {
+    video: BufWriter<File>,
+    audio: WavWriter,
+    buzzer: Buzzer
+}
+
+impl Recorder {
+
+    pub fn create(path: &Path, display: (usize, usize), palette: &Palette,
+                  scale: usize) -> Result<Recorder, String> {
+        let scale = scale.max(1);
+        let (width, height) = (display.0 * scale, display.1 * scale);
+        let create = |path: &Path| {
+            File::create(path)
+                .map(BufWriter::new)
+                .map_err(|e| format!("Can't create {}: {}", path.display(), e))
+        };
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let sink = match extension.to_ascii_lowercase().as_str() {
+            "gif" => {
+                let colours: Vec<u8> = palette.colours.iter().flatten().copied().collect();
+                let mut encoder = gif::Encoder::new(create(path)?, width as u16, height as u16, &colours)
+                    .map_err(|e| e.to_string())?;
+                encoder.set_repeat(gif::Repeat::Infinite).map_err(|e| e.to_string())?;
+                Sink::Gif(GifSink { encoder, pending: None, frames: 0 })
+            },
+            "y4m" => {
+                let mut video = create(path)?;
+                // 4:4:4 so the sharp pixel edges keep their colours
+                writeln!(video, "YUV4MPEG2 W{} H{} F60:1 Ip A1:1 C444", width, height)
+                    .map_err(|e| e.to_string())?;
+                let audio = WavWriter::create(create(&path.with_extension("wav"))?)?;
+                Sink::Video(VideoSink { video, audio, buzzer: Buzzer::new() })
+            },
+            _ => return Err(format!("Can't record {}, use a .gif or .y4m file", path.display()))
+        };
+        Ok(Recorder { path: path.to_path_buf(), display, scale, palette: *palette, sink })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // Adds the current screen and buzzer state, meant to be called once
+    // per 60Hz frame
+    pub fn frame(&mut self, cpu: &CPU) -> Result<(), String> {
+        if cpu.display_size() != self.display {
+            return Err("The screen resolution changed while recording".to_string());
+        }
+        match &mut self.sink {
+            Sink::Gif(sink) => {
+                // The framebuffer values are already indices into the palette
+                let indices: Vec<u8> = cpu.gfx.iter().map(|&pixel| pixel & 0b11).collect();
+                let indices = scale_pixels(&indices, self.display, 1, self.scale);
+                match &mut sink.pending {
+                    Some((pending, count)) if *pending == indices => *count += 1,
+                    _ => {
+                        sink.flush(self.display, self.scale)?;
+                        sink.pending = Some((indices, 1));
+                    }
+                }
+            },
+            Sink::Video(sink) => {
+                let mut rgb = vec![0; self.display.0 * self.display.1 * 3];
+                self.palette.render(&cpu.gfx, &mut rgb);
+                let rgb = scale_pixels(&rgb, self.display, 3, self.scale);
+                sink.video.write_all(b"FRAME\n").map_err(|e| e.to_string())?;
+                sink.video.write_all(&rgb_to_yuv444(&rgb)).map_err(|e| e.to_string())?;
+
+                let samples = sink.buzzer.frame(cpu.sound_timer > 0);
+                sink.audio.write(&samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<(), String> {
+        match self.sink {
+            Sink::Gif(mut sink) => {
+                sink.flush(self.display, self.scale)?;
+                sink.encoder.into_inner().map_err(|e| e.to_string())?;
+            },
+            Sink::Video(mut sink) => {
+                sink.video.flush().map_err(|e| e.to_string())?;
+                sink.audio.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+}
+
+impl GifSink {
+
+    fn flush(&mut self, display: (usize, usize), scale: usize) -> Result<(), String> {
+        if let Some((indices, count)) = self.pending.take() {
+            // GIF delays are in hundredths of a second
+            let start = self.frames * 100 / 60;
+            self.frames += count;
+            let delay = (self.frames * 100 / 60 - start).min(u16::MAX as u64) as u16;
+            let frame = gif::Frame {
+                width: (display.0 * scale) as u16,
+                height: (display.1 * scale) as u16,
+                delay,
+                buffer: Cow::Owned(indices),
+                ..gif::Frame::default()
+            };
+            self.encoder.write_frame(&frame).map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+
+}
+
+// BT.601 with studio range, what every video player expects from Y4M
+fn rgb_to_yuv444(rgb: &[u8]) -> Vec<u8> {
+    let pixels = rgb.len() / 3;
+    let mut yuv = vec![0; pixels * 3];
+    for (i, pixel) in rgb.chunks_exact(3).enumerate() {
+        let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+        yuv[i] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+        yuv[pixels + i] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+        yuv[pixels * 2 + i] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+    }
+    yuv
+}
+
+// 16 bit mono PCM, the sizes in the header are written when it's finished
+struct WavWriter {
+    file: BufWriter<File>,
+    samples: u32
+}
+
+impl WavWriter {
+
+    fn create(mut file: BufWriter<File>) -> Result<WavWriter, String> {
+        let mut header = Vec::new();
+        header.extend_from_slice(b"RIFF\0\0\0\0WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&1u16.to_le_bytes()); // Mono
+        header.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+        header.extend_from_slice(&(SAMPLE_RATE * 2).to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(b"data\0\0\0\0");
+        file.write_all(&header).map_err(|e| e.to_string())?;
+        Ok(WavWriter { file, samples: 0 })
+    }
+
+    fn write(&mut self, samples: &[i16]) -> Result<(), String> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes()).map_err(|e| e.to_string())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        let data = self.samples * 2;
+        let mut fix = |offset: u64, value: u32| {
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&value.to_le_bytes())
+        };
+        fix(4, 36 + data).map_err(|e| e.to_string())?;
+        fix(40, data).map_err(|e| e.to_string())?;
+        self.file.flush().map_err(|e| e.to_string())
+    }
+
+}
@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::config::{self, Settings};
+
+// A local copy of a community ROM database, a JSON object keyed by the SHA-1
+// of every known ROM, with entries in the format of the CHIP-8 database at
+// github.com/chip-8/chip-8-database:
+//
+//   { "0123...": { "title": "Pong", "authors": ["Paul Vervalin"],
+//                  "platforms": ["originalChip8"], "tickrate": 15,
+//                  "quirks": { "logic": true }, "keys": { "up": 1 },
+//                  "colors": { "pixels": ["#000000", "#ffffff"] } } }
+#[derive(Debug, Default)]
+pub struct RomDb {
+    entries: HashMap<String, Entry>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Entry {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub platforms: Vec<String>,
+    // Differences from the quirks of the platform
+    pub quirks: HashMap<String, bool>,
+    // Instructions per frame
+    pub tickrate: Option<u32>,
+    pub colors: Colors,
+    // Hints like "up" or "a" to the CHIP-8 key the game uses for them
+    pub keys: HashMap<String, u8>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Colors {
+    // Background first and then the colour of every plane
+    pub pixels: Vec<String>
+}
+
+impl RomDb {
+
+    // Usually ~/.config/rust8/romdb.json
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rust8").join("romdb.json"))
+    }
+
+    // Like the config an explicit path must exist but the default one is optional
+    pub fn load(path: Option<&Path>) -> Result<RomDb, String> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => match RomDb::default_path() {
+                Some(path) if path.exists() => path,
+                _ => return Ok(RomDb::default())
+            }
+        };
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let entries: HashMap<String, Entry> = serde_json::from_str(&text)
+            .map_err(|e| format!("Invalid ROM database {}: {}", path.display(), e))?;
+        let entries = entries.into_iter()
+            .map(|(sha1, entry)| (sha1.to_ascii_lowercase(), entry))
+            .collect();
+        Ok(RomDb { entries })
+    }
+
+    pub fn lookup(&self, sha1: &str) -> Option<&Entry> {
+        self.entries.get(&sha1.to_ascii_lowercase())
+    }
+
+}
+
+impl Entry {
+
+    // "Pong by Paul Vervalin"
+    pub fn description(&self) -> Option<String> {
+        let title = self.title.as_ref()?;
+        if self.authors.is_empty() {
+            Some(title.clone())
+        } else {
+            Some(format!("{} by {}", title, self.authors.join(", ")))
+        }
+    }
+
+    pub fn apply(&self, settings: &mut Settings) -> Result<(), String> {
+        if let Some(tickrate) = self.tickrate {
+            settings.speed = tickrate;
+        }
+
+        // The first platform we know about decides the starting quirks
+        if let Some(profile) = self.platforms.iter().find_map(|p| platform_profile(p)) {
+            settings.quirks = config::quirks_profile(profile)?;
+        }
+        for (quirk, &value) in &self.quirks {
+            match quirk.as_str() {
+                "shift" => settings.quirks.shift = value,
+                "memoryLeaveIUnchanged" => settings.quirks.memory = !value,
+                "logic" => settings.quirks.logic = value,
+                "wrap" => settings.quirks.wrap = value,
+                // Waiting for the display only comes with the whole VIP timing
+                "vblank" => settings.quirks.timing = value,
+                // jump and memoryIncrementByX aren't emulated
+                _ => {}
+            }
+        }
+
+        let pixels = &self.colors.pixels;
+        settings.palette.set_colours(&pixels[..pixels.len().min(4)])?;
+
+        // The hinted keys also get the arrows and a couple of easy to reach
+        // buttons, the regular keypad keeps working as usual
+        for (hint, &key) in &self.keys {
+            let name = match hint.as_str() {
+                "up" => "Up",
+                "down" => "Down",
+                "left" => "Left",
+                "right" => "Right",
+                "a" => "Space",
+                "b" => "Left Shift",
+                _ => continue
+            };
+            if key < 16 {
+                settings.keymap[key as usize].push(name.to_string());
+            }
+        }
+        Ok(())
+    }
+
+}
+
+// Platform names used by the database to our quirk profiles
+fn platform_profile(platform: &str) -> Option<&'static str> {
+    match platform {
+        "originalChip8" | "hybridVIP" => Some("chip8"),
+        "chip48" | "superchip1" | "superchip" => Some("schip"),
+        "xochip" => Some("xochip"),
+        _ => None
+    }
+}
@@ -0,0 +1,496 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::env;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::cpu::{Address, CPU, MEMORY_SIZE};
+use crate::palette::Palette;
+use crate::savestate;
+use crate::screenshot;
+
+// A JSON-RPC 2.0 server so scripts can drive the emulator. Every request
+// and response is one line of JSON, over TCP ("PORT" or "HOST:PORT") or a
+// Unix socket ("unix:PATH"):
+//
+//   {"jsonrpc": "2.0", "id": 1, "method": "read_memory", "params": {"address": 512, "length": 2}}
+//   {"jsonrpc": "2.0", "id": 1, "result": [18, 0]}
+//
+// Connections are served on their own threads but calls are only handled
+// when the frontend polls, between frames, so they never see the CPU in
+// the middle of one. Nobody has to log in, so TCP only listens on this
+// machine and the files a client names have to be inside the directory the
+// emulator started in. Clients that subscribe get notifications with no id:
+//
+//   {"jsonrpc": "2.0", "method": "event", "params": {"event": "sound_start", "pc": 530}}
+
+// What can be subscribed to: the CPU stopping on an unknown opcode and the
+// buzzer going on and off
+pub const EVENTS: [&str; 3] = ["halted", "sound_start", "sound_stop"];
+
+// What the server can touch, the frontend lends it on every poll
+pub struct Controls<'a> {
+    pub cpu: &'a mut CPU,
+    pub paused: &'a mut bool,
+    pub palette: &'a Palette,
+    pub screenshot_dir: &'a Path,
+    pub screenshot_scale: usize,
+    // Instructions per frame, how far step can go
+    pub speed: u32
+}
+
+enum Message {
+    Connected(u64, Sender<String>),
+    Call(u64, String),
+    Disconnected(u64)
+}
+
+struct Client {
+    sender: Sender<String>,
+    events: HashSet<String>
+}
+
+pub struct Server {
+    address: String,
+    socket: Option<PathBuf>,
+    // Where the paths in the calls start from
+    root: PathBuf,
+    messages: Receiver<Message>,
+    clients: HashMap<u64, Client>,
+    // The last state seen so events only fire when it changes
+    halted: bool,
+    sound: bool
+}
+
+// A JSON-RPC error code and message
+struct Error(i64, String);
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+fn invalid_params(message: &str) -> Error {
+    Error(INVALID_PARAMS, message.to_string())
+}
+
+impl Server {
+
+    pub fn bind(address: &str) -> Result<Server, String> {
+        let (sender, messages) = mpsc::channel();
+        let mut socket = None;
+        // A bare port is only reachable from this machine
+        let address = if address.starts_with("unix:") || address.contains(':') {
+            address.to_string()
+        } else {
+            format!("127.0.0.1:{}", address)
+        };
+        let bind_error = |e: io::Error| format!("Can't listen on {}: {}", address, e);
+        let root = env::current_dir().and_then(fs::canonicalize).map_err(bind_error)?;
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            {
+                let listener = UnixListener::bind(path).map_err(bind_error)?;
+                socket = Some(PathBuf::from(path));
+                thread::spawn(move || accept(listener.incoming(), UnixStream::try_clone, sender));
+            }
+            #[cfg(not(unix))]
+            return Err(format!("Can't listen on {}: Unix sockets need a Unix", path));
+        } else {
+            let addresses: Vec<_> = address.to_socket_addrs().map_err(bind_error)?.collect();
+            if addresses.iter().any(|address| !address.ip().is_loopback()) {
+                return Err(format!("Can't listen on {}: anyone could control the emulator, use 127.0.0.1 or a Unix socket",
+                                   address));
+            }
+            let listener = TcpListener::bind(&addresses[..]).map_err(bind_error)?;
+            thread::spawn(move || accept(listener.incoming(), TcpStream::try_clone, sender));
+        }
+        Ok(Server {
+            address,
+            socket,
+            root,
+            messages,
+            clients: HashMap::new(),
+            halted: false,
+            sound: false
+        })
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    // Sends the events of the last frame and handles every call that
+    // arrived since the last poll, call it once per frame
+    pub fn poll(&mut self, controls: &mut Controls) {
+        let cpu = &controls.cpu;
+        if cpu.breakexe && !self.halted {
+            self.notify("halted", json!({ "pc": cpu.pc.get(), "opcode": cpu.opcode, "reason": cpu.halted }));
+        }
+        let sound = cpu.sound_timer > 0;
+        if sound != self.sound {
+            let event = if sound { "sound_start" } else { "sound_stop" };
+            self.notify(event, json!({ "pc": cpu.pc.get() }));
+        }
+        self.halted = cpu.breakexe;
+        self.sound = sound;
+
+        while let Ok(message) = self.messages.try_recv() {
+            match message {
+                Message::Connected(id, sender) => {
+                    self.clients.insert(id, Client { sender, events: HashSet::new() });
+                },
+                Message::Disconnected(id) => {
+                    self.clients.remove(&id);
+                },
+                Message::Call(id, line) => {
+                    if let Some(response) = self.handle(id, &line, controls) {
+                        if let Some(client) = self.clients.get(&id) {
+                            let _ = client.sender.send(response.to_string());
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn notify(&self, event: &str, mut params: Value) {
+        params["event"] = json!(event);
+        let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": params }).to_string();
+        for client in self.clients.values().filter(|client| client.events.contains(event)) {
+            let _ = client.sender.send(notification.clone());
+        }
+    }
+
+    // The response to a line, none for notifications
+    fn handle(&mut self, client: u64, line: &str, controls: &mut Controls) -> Option<Value> {
+        let request: Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Some(error_response(Value::Null, Error(PARSE_ERROR, e.to_string())))
+        };
+        let id = request.get("id").cloned();
+        let method = match request.get("method").and_then(Value::as_str) {
+            Some(method) if request.get("jsonrpc") == Some(&json!("2.0")) => method,
+            _ => {
+                let error = Error(INVALID_REQUEST, "Not a JSON-RPC 2.0 request".to_string());
+                return Some(error_response(id.unwrap_or(Value::Null), error));
+            }
+        };
+        let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+        let result = self.call(client, method, &params, controls);
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(error) => error_response(id, error)
+        })
+    }
+
+    fn call(&mut self, client: u64, method: &str, params: &Value,
+            controls: &mut Controls) -> Result<Value, Error> {
+        let cpu = &mut *controls.cpu;
+        match method {
+            // Powers on again with another ROM, keeping the quirks and the seed
+            "load_rom" => {
+                let path = self.path(string_param(params, "path")?)?;
+                let rom = fs::read(&path).map_err(|e| Error(SERVER_ERROR, format!("Can't read {}: {}", path.display(), e)))?;
+                let mut fresh = CPU::initialize();
+                fresh.quirks = cpu.quirks;
+                fresh.reseed(cpu.seed);
+                fresh.load_fontset();
+                fresh.load_rom(&rom).map_err(|e| Error(SERVER_ERROR, e))?;
+                fresh.draw = true;
+                *cpu = fresh;
+                Ok(json!({ "size": rom.len() }))
+            },
+            "pause" => {
+                *controls.paused = true;
+                Ok(json!(true))
+            },
+            "resume" => {
+                *controls.paused = false;
+                Ok(json!(true))
+            },
+            "status" => Ok(json!({ "paused": *controls.paused, "halted": cpu.breakexe, "pc": cpu.pc.get() })),
+            // Runs single instructions, meant for when the game is paused.
+            // The frontend waits meanwhile so it's at most a second of them
+            "step" => {
+                let count = optional_param(params, "count")?.unwrap_or(1);
+                let most = controls.speed.max(1) as u64 * 60;
+                if count > most {
+                    return Err(invalid_params(&format!("At most {} instructions can run in one step", most)));
+                }
+                let mut ran = 0;
+                while ran < count && !cpu.breakexe {
+                    cpu.fetch_opcode();
+                    ran += 1;
+                }
+                Ok(json!({ "instructions": ran, "pc": cpu.pc.get(), "halted": cpu.breakexe }))
+            },
+            "press" | "release" => {
+                let key = int_param(params, "key")?;
+                if key > 0xF {
+                    return Err(invalid_params("Keys go from 0 to 15"));
+                }
+                cpu.key.set(key as u8, method == "press");
+                Ok(json!(cpu.keypad()))
+            },
+            "read_memory" => {
+                let address = int_param(params, "address")? as usize;
+                let length = optional_param(params, "length")?.unwrap_or(1) as usize;
+                let range = memory_range(address, length)?;
+                Ok(json!(cpu.memory()[range].to_vec()))
+            },
+            "write_memory" => {
+                let address = int_param(params, "address")? as usize;
+                let data: Vec<u8> = params.get("data").cloned()
+                    .and_then(|data| serde_json::from_value(data).ok())
+                    .ok_or_else(|| invalid_params("data has to be an array of bytes"))?;
+                memory_range(address, data.len())?;
+                cpu.write_memory(address, &data);
+                Ok(json!(data.len()))
+            },
+            "get_registers" => Ok(json!({
+                "v": cpu.v.to_vec(),
+                "i": cpu.i.get(),
+                "pc": cpu.pc.get(),
+                "sp": cpu.sp,
+                "stack": cpu.stack.iter().map(|address| address.get()).collect::<Vec<_>>(),
+                "delay_timer": cpu.delay_timer,
+                "sound_timer": cpu.sound_timer,
+                "keypad": cpu.keypad()
+            })),
+            // Registers are v0 to vf, i, pc, delay_timer and sound_timer
+            "set_register" => {
+                let name = string_param(params, "register")?.to_ascii_lowercase();
+                let value = int_param(params, "value")?;
+                let too_big = || invalid_params("The value doesn't fit in the register");
+                let byte = || u8::try_from(value).map_err(|_| too_big());
+                let address = || match value {
+                    value if value < MEMORY_SIZE as u64 => Ok(Address::new(value as usize)),
+                    _ => Err(too_big())
+                };
+                match name.as_str() {
+                    "i" => cpu.i = address()?,
+                    "pc" => cpu.pc = address()?,
+                    "delay_timer" => cpu.delay_timer = byte()?,
+                    "sound_timer" => cpu.sound_timer = byte()?,
+                    v => *v.strip_prefix('v')
+                        .and_then(|x| usize::from_str_radix(x, 16).ok())
+                        .and_then(|x| cpu.v.get_mut(x))
+                        .ok_or_else(|| invalid_params("Unknown register"))? = byte()?
+                }
+                Ok(json!(value))
+            },
+            // Saves a PNG to the path, or with a new name in the screenshot
+            // directory when there's no path
+            "screenshot" => {
+                let path = match params.get("path").and_then(Value::as_str) {
+                    Some(path) => {
+                        let path = self.path(path)?;
+                        let file = File::create(&path)
+                            .map_err(|e| Error(SERVER_ERROR, format!("Can't create {}: {}", path.display(), e)))?;
+                        screenshot::encode_png(BufWriter::new(file), &cpu.gfx, cpu.display_size(),
+                                               controls.palette, controls.screenshot_scale)
+                            .map_err(|e| Error(SERVER_ERROR, e))?;
+                        path
+                    },
+                    None => screenshot::capture(cpu, controls.palette, controls.screenshot_scale,
+                                                controls.screenshot_dir)
+                        .map_err(|e| Error(SERVER_ERROR, e))?
+                };
+                Ok(json!({ "path": path }))
+            },
+            // To a file when there's a path, or back as a hex string
+            "save_state" => {
+                let state = savestate::save(cpu);
+                match params.get("path").and_then(Value::as_str) {
+                    Some(path) => {
+                        let path = self.path(path)?;
+                        fs::write(&path, &state)
+                            .map_err(|e| Error(SERVER_ERROR, format!("Can't write {}: {}", path.display(), e)))?;
+                        Ok(json!({ "path": path }))
+                    },
+                    None => Ok(json!({ "state": to_hex(&state) }))
+                }
+            },
+            "load_state" => {
+                let state = match (params.get("path").and_then(Value::as_str),
+                                   params.get("state").and_then(Value::as_str)) {
+                    (Some(path), _) => {
+                        let path = self.path(path)?;
+                        fs::read(&path)
+                            .map_err(|e| Error(SERVER_ERROR, format!("Can't read {}: {}", path.display(), e)))?
+                    },
+                    (None, Some(state)) => from_hex(state).ok_or_else(|| invalid_params("state isn't hex"))?,
+                    (None, None) => return Err(invalid_params("Either path or state is needed"))
+                };
+                savestate::load(cpu, &state).map_err(|e| Error(SERVER_ERROR, e))?;
+                cpu.draw = true;
+                Ok(json!(true))
+            },
+            "subscribe" | "unsubscribe" => {
+                let events: Vec<String> = params.get("events").cloned()
+                    .and_then(|events| serde_json::from_value(events).ok())
+                    .ok_or_else(|| invalid_params("events has to be an array of names"))?;
+                if let Some(unknown) = events.iter().find(|event| !EVENTS.contains(&event.as_str())) {
+                    return Err(invalid_params(&format!("Unknown event {}, use one of {}",
+                                                       unknown, EVENTS.join(", "))));
+                }
+                let client = self.clients.get_mut(&client)
+                    .ok_or_else(|| Error(SERVER_ERROR, "Not connected".to_string()))?;
+                for event in events {
+                    if method == "subscribe" {
+                        client.events.insert(event);
+                    } else {
+                        client.events.remove(&event);
+                    }
+                }
+                let mut events: Vec<&String> = client.events.iter().collect();
+                events.sort();
+                Ok(json!(events))
+            },
+            _ => Err(Error(METHOD_NOT_FOUND, format!("Unknown method {}", method)))
+        }
+    }
+
+    // A path from a call inside the root. Only plain names are allowed and
+    // neither the file nor its directory can be a link to somewhere outside
+    fn path(&self, path: &str) -> Result<PathBuf, Error> {
+        let outside = || invalid_params(&format!("{} isn't inside {}", path, self.root.display()));
+        let relative = Path::new(path);
+        if path.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_) | Component::CurDir)) {
+            return Err(outside());
+        }
+        let path = self.root.join(relative);
+        let real = match fs::canonicalize(&path) {
+            Ok(real) => Some(real),
+            Err(_) => path.parent().and_then(|directory| fs::canonicalize(directory).ok())
+        };
+        match real {
+            Some(real) if real.starts_with(&self.root) => Ok(path),
+            _ => Err(outside())
+        }
+    }
+
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Some(socket) = &self.socket {
+            let _ = fs::remove_file(socket);
+        }
+    }
+}
+
+fn accept<S>(incoming: impl Iterator<Item = io::Result<S>>, clone: fn(&S) -> io::Result<S>,
+             messages: Sender<Message>) where S: Read + Write + Send + 'static {
+    for (id, stream) in (0..).zip(incoming) {
+        if let Ok((reader, writer)) = stream.and_then(|stream| Ok((clone(&stream)?, stream))) {
+            serve(id, reader, writer, messages.clone());
+        }
+    }
+}
+
+// Reads lines on one thread and writes responses and events on another
+fn serve<R, W>(id: u64, reader: R, writer: W, messages: Sender<Message>)
+    where R: Read + Send + 'static, W: Write + Send + 'static {
+    let (sender, outgoing) = mpsc::channel::<String>();
+    if messages.send(Message::Connected(id, sender)).is_err() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut writer = writer;
+        for line in outgoing {
+            if writeln!(writer, "{}", line).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            match line {
+                Ok(line) if line.trim().is_empty() => continue,
+                Ok(line) => {
+                    if messages.send(Message::Call(id, line)).is_err() {
+                        return;
+                    }
+                },
+                Err(_) => break
+            }
+        }
+        let _ = messages.send(Message::Disconnected(id));
+    });
+}
+
+fn error_response(id: Value, Error(code, message): Error) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+fn string_param<'a>(params: &'a Value, name: &str) -> Result<&'a str, Error> {
+    params.get(name).and_then(Value::as_str)
+        .ok_or_else(|| invalid_params(&format!("{} has to be a string", name)))
+}
+
+fn int_param(params: &Value, name: &str) -> Result<u64, Error> {
+    optional_param(params, name)?.ok_or_else(|| invalid_params(&format!("{} is missing", name)))
+}
+
+fn optional_param(params: &Value, name: &str) -> Result<Option<u64>, Error> {
+    match params.get(name) {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some)
+            .ok_or_else(|| invalid_params(&format!("{} has to be a positive integer", name)))
+    }
+}
+
+fn memory_range(address: usize, length: usize) -> Result<std::ops::Range<usize>, Error> {
+    match address.checked_add(length) {
+        Some(end) if end <= MEMORY_SIZE => Ok(address..end),
+        _ => Err(invalid_params("Memory goes from 0x000 to 0xFFF"))
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn tcp_only_listens_on_loopback() {
+        assert!(Server::bind("0.0.0.0:0").is_err());
+        assert!(Server::bind("127.0.0.1:0").is_ok());
+        assert!(Server::bind("0").is_ok());
+    }
+
+    #[test]
+    fn paths_stay_inside_the_root() {
+        let server = Server::bind("0").unwrap();
+        for path in &["", "/etc/passwd", "../rom.ch8", "roms/../../rom.ch8"] {
+            assert!(server.path(path).is_err(), "{}", path);
+        }
+        assert_eq!(server.path("rom.ch8").ok(), Some(server.root.join("rom.ch8")));
+        assert!(server.path("./src/cpu.rs").is_ok());
+        assert!(server.path("missing/rom.ch8").is_err());
+    }
+
+}
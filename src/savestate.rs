@@ -0,0 +1,138 @@
+use crate::cpu::{seeded_rng, Address, Quirks, CPU, MEMORY_SIZE};
+use crate::timing;
+
+// Save states are the whole CPU as bytes, so they can be written to disk or
+// handed to other languages and loaded back later. After the magic and the
+// version every field has a fixed size and is little endian. The registers
+// are kept as 64 bits, which is what they were when the format was made.
+// Version 2 added the cycles left in the frame at the end, version 1 states
+// still load with a whole frame left. Version 3 added the key FX0A waits to
+// be released after them, FF for none
+const MAGIC: &[u8; 8] = b"RUST8SS\0";
+const VERSION: u8 = 3;
+
+pub fn save(cpu: &CPU) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(MAGIC);
+    data.push(VERSION);
+    for register in registers(cpu) {
+        data.extend_from_slice(&register.to_le_bytes());
+    }
+    data.extend_from_slice(&cpu.keypad().to_le_bytes());
+    data.push(cpu.draw as u8 | (cpu.breakexe as u8) << 1);
+    data.push(cpu.quirks.bits());
+    data.extend_from_slice(&cpu.seed.to_le_bytes());
+    data.extend_from_slice(&cpu.rng.get_word_pos().to_le_bytes());
+    data.extend_from_slice(cpu.memory());
+    data.extend_from_slice(&cpu.gfx);
+    data.extend_from_slice(&cpu.cycles.to_le_bytes());
+    data.push(cpu.key_wait.unwrap_or(0xFF));
+    data
+}
+
+// Nothing in the CPU changes unless the whole state is valid
+pub fn load(cpu: &mut CPU, data: &[u8]) -> Result<(), String> {
+    let mut reader = Reader { data };
+    if reader.take(8)? != MAGIC {
+        return Err("Not a rust8 save state".to_string());
+    }
+    let version = reader.take(1)?[0];
+    if !(1..=VERSION).contains(&version) {
+        return Err(format!("Unsupported save state version {}", version));
+    }
+    let mut state = cpu.clone();
+    // Whatever doesn't fit in its register means the state is broken
+    let address = |value: u64| Address::new(value as usize);
+    state.opcode = reader.register(0xFFFF)? as u16;
+    state.pc = address(reader.register(MEMORY_SIZE as u64 - 1)?);
+    state.i = address(reader.register(MEMORY_SIZE as u64 - 1)?);
+    state.sp = reader.register(state.stack.len() as u64)? as usize;
+    state.delay_timer = reader.register(0xFF)? as u8;
+    state.sound_timer = reader.register(0xFF)? as u8;
+    for register in state.v.iter_mut() {
+        *register = reader.register(0xFF)? as u8;
+    }
+    for entry in state.stack.iter_mut() {
+        *entry = address(reader.register(MEMORY_SIZE as u64 - 1)?);
+    }
+    let keypad = reader.take(2)?;
+    state.set_keypad(u16::from_le_bytes([keypad[0], keypad[1]]));
+    let flags = reader.take(1)?[0];
+    state.draw = flags & 1 != 0;
+    state.breakexe = flags & 2 != 0;
+    // The reason isn't saved, only the CPU that halted knows it
+    state.halted = None;
+    state.quirks = Quirks::from_bits(reader.take(1)?[0]);
+    state.seed = reader.u64()?;
+    let mut word_pos = [0; 16];
+    word_pos.copy_from_slice(reader.take(16)?);
+    state.rng = seeded_rng(state.seed);
+    state.rng.set_word_pos(u128::from_le_bytes(word_pos));
+    state.write_memory(0, reader.take(MEMORY_SIZE)?);
+    state.gfx.copy_from_slice(reader.take(2048)?);
+    state.cycles = timing::BUDGET;
+    if version >= 2 {
+        let cycles = reader.take(4)?;
+        state.cycles = i32::from_le_bytes([cycles[0], cycles[1], cycles[2], cycles[3]]);
+        if state.cycles > timing::BUDGET {
+            return Err(format!("The save state has more cycles left than a frame: {}", state.cycles));
+        }
+    }
+    // It runs FX0A again if that's where it was
+    state.waiting = false;
+    state.key_wait = None;
+    if version >= 3 {
+        state.key_wait = match reader.take(1)?[0] {
+            0xFF => None,
+            key if key < 16 => Some(key),
+            key => return Err(format!("The save state waits for a key that doesn't exist: {:#x}", key))
+        };
+    }
+    if !reader.data.is_empty() {
+        return Err("The save state has trailing data".to_string());
+    }
+    *cpu = state;
+    Ok(())
+}
+
+// Opcode, PC, I, SP, the timers, V0 to VF and the stack, in the order they
+// are saved
+fn registers(cpu: &CPU) -> Vec<u64> {
+    let special = [cpu.opcode as u64, cpu.pc.get() as u64, cpu.i.get() as u64, cpu.sp as u64,
+                   cpu.delay_timer as u64, cpu.sound_timer as u64];
+    special.iter().copied()
+        .chain(cpu.v.iter().map(|&v| v as u64))
+        .chain(cpu.stack.iter().map(|address| address.get() as u64))
+        .collect()
+}
+
+struct Reader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> Reader<'a> {
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < len {
+            return Err("The save state is truncated".to_string());
+        }
+        let (taken, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn register(&mut self, max: u64) -> Result<u64, String> {
+        let value = self.u64()?;
+        if value > max {
+            return Err(format!("The save state has a register out of range: {:#x}", value));
+        }
+        Ok(value)
+    }
+
+}
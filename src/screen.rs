@@ -0,0 +1,72 @@
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{Window, WindowContext};
+
+use system8::palette::Palette;
+use system8::phosphor::Phosphor;
+
+// The SDL side of the CHIP-8 screen. The texture has the size of the CHIP-8
+// screen and it's scaled when copied to the window, it has to be created
+// again if the screen resolution changes
+pub struct Screen<'a> {
+    texture_creator: &'a TextureCreator<WindowContext>,
+    texture: Texture<'a>,
+    display: (usize, usize),
+    pixels: Vec<u8>,
+    integer_scale: bool
+}
+
+impl<'a> Screen<'a> {
+
+    pub fn new(texture_creator: &'a TextureCreator<WindowContext>, display: (usize, usize),
+               integer_scale: bool) -> Result<Screen<'a>, String> {
+        let texture = texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, display.0 as u32, display.1 as u32)
+            .map_err(|e| e.to_string())?;
+        Ok(Screen {
+            texture_creator,
+            texture,
+            display,
+            pixels: vec![0; display.0 * display.1 * 3],
+            integer_scale
+        })
+    }
+
+    // Converts a framebuffer, the CPU's or a broadcast's, to colours and
+    // shows it
+    pub fn draw(&mut self, canvas: &mut Canvas<Window>, gfx: &[u8], display: (usize, usize),
+                palette: &Palette, phosphor: &mut Phosphor) -> Result<(), String> {
+        if display != self.display {
+            *self = Screen::new(self.texture_creator, display, self.integer_scale)?;
+        }
+        palette.render(gfx, &mut self.pixels);
+        phosphor.apply(gfx, &mut self.pixels);
+        self.texture.update(None, &self.pixels, self.display.0 * 3).map_err(|e| e.to_string())?;
+        self.present(canvas)
+    }
+
+    // Shows the last frame again, for when the window changes
+    pub fn present(&self, canvas: &mut Canvas<Window>) -> Result<(), String> {
+        let rect = viewport(canvas.output_size()?, self.display, self.integer_scale);
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.copy(&self.texture, None, Some(rect))?;
+        canvas.present();
+        Ok(())
+    }
+
+}
+
+// Where the screen goes inside a window of the given size, as big as
+// possible while keeping the aspect ratio and centered with black bars
+fn viewport(output: (u32, u32), display: (usize, usize), integer_scale: bool) -> Rect {
+    let (width, height) = (output.0 as f32, output.1 as f32);
+    let (display_width, display_height) = (display.0 as f32, display.1 as f32);
+    let mut scale = (width / display_width).min(height / display_height);
+    if integer_scale && scale >= 1.0 {
+        scale = scale.floor();
+    }
+    let (w, h) = ((display_width * scale) as u32, (display_height * scale) as u32);
+    Rect::new(((output.0 - w) / 2) as i32, ((output.1 - h) / 2) as i32, w.max(1), h.max(1))
+}
@@ -0,0 +1,84 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+
+use crate::cpu::CPU;
+use crate::palette::Palette;
+
+// Makes every CHIP-8 pixel a scale x scale square, pixels are made of
+// `channels` bytes (3 for RGB, 1 for palette indices)
+pub fn scale_pixels(pixels: &[u8], display: (usize, usize), channels: usize,
+                    scale: usize) -> Vec<u8> {
+    let (width, height) = display;
+    let stride = width * channels;
+    let mut scaled = Vec::with_capacity(pixels.len() * scale * scale);
+    for y in 0..height * scale {
+        let row = &pixels[(y / scale) * stride..(y / scale + 1) * stride];
+        for pixel in row.chunks_exact(channels) {
+            for _ in 0..scale {
+                scaled.extend_from_slice(pixel);
+            }
+        }
+    }
+    scaled
+}
+
+// Writes the framebuffer as a PNG in the colours of the palette
+pub fn encode_png<W: Write>(writer: W, gfx: &[u8], display: (usize, usize),
+                            palette: &Palette, scale: usize) -> Result<(), String> {
+    let mut rgb = vec![0; display.0 * display.1 * 3];
+    palette.render(gfx, &mut rgb);
+    let rgb = scale_pixels(&rgb, display, 3, scale);
+
+    let mut encoder = png::Encoder::new(writer, (display.0 * scale) as u32,
+                                        (display.1 * scale) as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer.write_image_data(&rgb).map_err(|e| e.to_string())
+}
+
+// A new file name inside dir for something saved right now, the directory
+// is created if it doesn't exist yet
+pub fn timestamped_path(dir: &Path, extension: &str) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+    // Not with_extension, it would take the milliseconds for the extension
+    let name = format!("{}.{}", Local::now().format("rust8-%Y%m%d-%H%M%S%.3f"), extension);
+    Ok(dir.join(name))
+}
+
+// Saves what the CPU shows right now to a new timestamped PNG inside dir.
+// A scale of 1 is the native resolution of the screen
+pub fn capture(cpu: &CPU, palette: &Palette, scale: usize, dir: &Path) -> Result<PathBuf, String> {
+    let path = timestamped_path(dir, "png")?;
+    let file = File::create(&path).map_err(|e| format!("Can't create {}: {}", path.display(), e))?;
+    encode_png(BufWriter::new(file), &cpu.gfx, cpu.display_size(), palette, scale.max(1))?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn paths_keep_the_milliseconds() {
+        let dir = env::temp_dir().join("rust8-screenshot-test");
+        let first = timestamped_path(&dir, "png").unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let second = timestamped_path(&dir, "png").unwrap();
+        assert_ne!(first, second);
+        // rust8-YYYYMMDD-HHMMSS.mmm.png
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert_eq!(name.len(), "rust8-20240101-000000.000.png".len(), "{}", name);
+        assert!(name.ends_with(".png"));
+        let _ = fs::remove_dir(&dir);
+    }
+
+}
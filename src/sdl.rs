@@ -0,0 +1,241 @@
+use std::time::{Duration, Instant};
+use std::thread;
+
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::Keycode;
+use sdl2::video::FullscreenType;
+
+use system8::broadcast::Viewer;
+use system8::config::Settings;
+use system8::{phosphor, recorder, screenshot};
+
+use crate::screen;
+use crate::session::Session;
+
+// The window frontend, runs until Escape, closing the window or an unknown
+// opcode
+pub fn run(session: &mut Session) -> Result<(), String> {
+    let settings = session.settings.clone();
+    let mut keymap = Vec::new();
+    for (key, names) in settings.keymap.iter().enumerate() {
+        for name in names {
+            let keycode = Keycode::from_name(name)
+                .ok_or(format!("Unknown key name in keymap: {}", name))?;
+            keymap.push((keycode, key));
+        }
+    }
+
+    // Helpers and SDL2 graphics initialization
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let mut window = video_subsystem.window(&session.title, 64 * settings.scale, 32 * settings.scale)
+        .position_centered()
+        .resizable()
+        .opengl()
+        .build()
+        .map_err(|e| e.to_string())?;
+    if settings.fullscreen {
+        window.set_fullscreen(FullscreenType::Desktop)?;
+    }
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut screen = screen::Screen::new(&texture_creator, (64, 32), settings.integer_scale)?;
+    let mut phosphor = phosphor::Phosphor::new(settings.phosphor);
+    screen.present(&mut canvas)?;
+
+    // Presenting once per frame instead of on every draw also gives the
+    // phosphor filter a steady rate to fade at
+    let frame_sync = settings.frame_sync || phosphor.enabled();
+
+    // SDL2 Keyboard event pump
+    let mut event_pump = sdl_context.event_pump()?;
+
+    // MAIN LOOP ----
+
+    // Timers and the screen run at 60Hz, the CPU runs settings.speed
+    // instructions on every one of those frames, or as many as fit in the
+    // cycles of a frame with the timing quirk
+    let frame_duration = Duration::from_nanos(1_000_000_000 / 60);
+
+    'mainloop: loop {
+        let frame_start = Instant::now();
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } |
+                Event::Quit { .. } => break 'mainloop,
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off
+                    };
+                    window.set_fullscreen(fullscreen)?;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F12), repeat: false, .. } => {
+                    let scale = settings.screenshot_scale as usize;
+                    match screenshot::capture(&session.cpu, &settings.palette, scale, &settings.screenshot_dir) {
+                        Ok(path) => println!("Screenshot saved to {}", path.display()),
+                        Err(error) => eprintln!("Screenshot failed: {}", error)
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), repeat: false, .. } => {
+                    match session.recorder.take() {
+                        Some(recording) => {
+                            let path = recording.path().to_path_buf();
+                            match recording.finish() {
+                                Ok(()) => println!("Recording saved to {}", path.display()),
+                                Err(error) => eprintln!("Recording failed: {}", error)
+                            }
+                        },
+                        None => {
+                            let scale = settings.screenshot_scale as usize;
+                            let started = screenshot::timestamped_path(&settings.screenshot_dir, "gif")
+                                .and_then(|path| recorder::Recorder::create(&path, session.cpu.display_size(),
+                                                                            &settings.palette, scale));
+                            match started {
+                                Ok(recording) => {
+                                    println!("Recording to {}", recording.path().display());
+                                    session.recorder = Some(recording);
+                                },
+                                Err(error) => eprintln!("Recording failed: {}", error)
+                            }
+                        }
+                    }
+                },
+                Event::Window { win_event: WindowEvent::SizeChanged(..), .. } |
+                Event::Window { win_event: WindowEvent::Exposed, .. } => {
+                    screen.present(&mut canvas)?;
+                },
+                Event::KeyDown { keycode: Some(keycode), ..} if !session.playing() => {
+                    for &(_, key) in keymap.iter().filter(|&&(k, _)| k == keycode) {
+                        session.cpu.key.set(key as u8, true)
+                    }
+                },
+                Event::KeyUp { keycode: Some(keycode), ..} if !session.playing() => {
+                    for &(_, key) in keymap.iter().filter(|&&(k, _)| k == keycode) {
+                        session.cpu.key.set(key as u8, false)
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        session.start_frame();
+
+        // The frame is also over on an unknown instruction
+        let running = session.running();
+        let mut ran = 0;
+        while running && !session.frame_over(ran) {
+            // Fetch opcodes, the JIT runs several at once
+            ran += session.run(ran);
+            let cpu = &mut session.cpu;
+
+            // Draw the screen
+            if cpu.draw && !frame_sync {
+                screen.draw(&mut canvas, &cpu.gfx, cpu.display_size(), &settings.palette, &mut phosphor)?;
+                cpu.draw = false;
+            }
+        }
+        if session.over() {
+            break 'mainloop
+        }
+        let cpu = &mut session.cpu;
+
+        // Whatever the RPC server changed has to be shown too
+        if !frame_sync && cpu.draw {
+            screen.draw(&mut canvas, &cpu.gfx, cpu.display_size(), &settings.palette, &mut phosphor)?;
+            cpu.draw = false;
+        }
+
+        if frame_sync && (cpu.draw || phosphor.fading()) {
+            screen.draw(&mut canvas, &cpu.gfx, cpu.display_size(), &settings.palette, &mut phosphor)?;
+            cpu.draw = false;
+        }
+
+        // If sound timer reaches 0 let's beep
+        if cpu.sound_timer == 1 {
+            // TODO: Some real sound but looks difficult in SDL2 :(
+            println!("BEEEP");
+        }
+
+        if let Some(notice) = session.end_frame() {
+            println!("{}", notice);
+        }
+
+        // Let's sleep for the rest of the frame instead of fetching the next instruction
+        if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining)
+        }
+    }
+
+    Ok(())
+}
+
+// Shows a broadcast until it ends, Escape or the window is closed
+pub fn watch(viewer: &mut Viewer, settings: &Settings, title: &str) -> Result<(), String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let mut window = video_subsystem.window(title, 64 * settings.scale, 32 * settings.scale)
+        .position_centered()
+        .resizable()
+        .opengl()
+        .build()
+        .map_err(|e| e.to_string())?;
+    if settings.fullscreen {
+        window.set_fullscreen(FullscreenType::Desktop)?;
+    }
+    let mut canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let texture_creator = canvas.texture_creator();
+    let mut screen = screen::Screen::new(&texture_creator, (64, 32), settings.integer_scale)?;
+    let mut phosphor = phosphor::Phosphor::new(settings.phosphor);
+    screen.present(&mut canvas)?;
+    let mut event_pump = sdl_context.event_pump()?;
+    let mut last = None;
+    let mut sound = false;
+
+    let frame_duration = Duration::from_nanos(1_000_000_000 / 60);
+
+    'mainloop: loop {
+        let frame_start = Instant::now();
+
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } |
+                Event::Quit { .. } => break 'mainloop,
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                    let window = canvas.window_mut();
+                    let fullscreen = match window.fullscreen_state() {
+                        FullscreenType::Off => FullscreenType::Desktop,
+                        _ => FullscreenType::Off
+                    };
+                    window.set_fullscreen(fullscreen)?;
+                },
+                Event::Window { win_event: WindowEvent::SizeChanged(..), .. } |
+                Event::Window { win_event: WindowEvent::Exposed, .. } => {
+                    screen.present(&mut canvas)?;
+                },
+                _ => {}
+            }
+        }
+
+        let frame = viewer.poll()?;
+        if let Some(frame) = frame.as_ref().or(last.as_ref().filter(|_| phosphor.fading())) {
+            screen.draw(&mut canvas, &frame.gfx, frame.display, &settings.palette, &mut phosphor)?;
+        }
+        if let Some(frame) = frame {
+            // Same as the game, a beep when the buzzer stops
+            if sound && !frame.sound {
+                println!("BEEEP");
+            }
+            sound = frame.sound;
+            last = Some(frame);
+        }
+
+        if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining)
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,206 @@
+use std::path::Path;
+
+use system8::broadcast::Broadcaster;
+use system8::config::Settings;
+use system8::cpu::CPU;
+#[cfg(feature = "jit")]
+use system8::jit::Jit;
+use system8::movie::{Movie, Player};
+use system8::netplay::Netplay;
+use system8::recorder::Recorder;
+use system8::rpc::{Controls, Server};
+use system8::vip::Vip;
+
+// Everything a frontend drives one 60Hz frame at a time, the movie being
+// recorded or played and the video recording, so the SDL window and the
+// terminal behave the same
+pub struct Session {
+    pub cpu: CPU,
+    pub settings: Settings,
+    pub title: String,
+    pub player: Option<Player>,
+    pub movie: Option<Movie>,
+    pub recorder: Option<Recorder>,
+    pub rpc: Option<Server>,
+    pub netplay: Option<Netplay>,
+    pub broadcaster: Option<Broadcaster>,
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
+    // Runs instead of the CPU, which only shows what it does
+    pub vip: Option<Vip>,
+    // Only the RPC server pauses, the timers stop too
+    pub paused: bool,
+    // Frames since the ROM started
+    pub frame: u64
+}
+
+impl Session {
+
+    // The keyboard does nothing while a movie is playing
+    pub fn playing(&self) -> bool {
+        self.player.is_some()
+    }
+
+    // Whether the frontend should run instructions this frame
+    pub fn running(&self) -> bool {
+        !self.paused && !self.cpu.breakexe && !self.waiting()
+    }
+
+    // Netplay got too far ahead of the other player
+    fn waiting(&self) -> bool {
+        self.netplay.as_ref().is_some_and(|netplay| netplay.stalled())
+    }
+
+    // An unknown opcode ends the game, unless there's an RPC server whose
+    // clients will want to look at what happened
+    pub fn over(&self) -> bool {
+        self.cpu.breakexe && self.rpc.is_none()
+    }
+
+    // Inputs only change between frames so movies replay exactly, call it
+    // once the keyboard has been read and before running instructions
+    pub fn start_frame(&mut self) {
+        if let Some(rpc) = &mut self.rpc {
+            rpc.poll(&mut Controls {
+                cpu: &mut self.cpu,
+                paused: &mut self.paused,
+                palette: &self.settings.palette,
+                screenshot_dir: &self.settings.screenshot_dir,
+                screenshot_scale: self.settings.screenshot_scale as usize,
+                speed: self.settings.speed
+            });
+        }
+        if let Some(netplay) = &mut self.netplay {
+            netplay.start_frame(&mut self.cpu);
+        }
+        if let Some(player) = &mut self.player {
+            player.apply(self.frame, &mut self.cpu);
+        }
+        if let Some(movie) = &mut self.movie {
+            movie.record(self.frame, &self.cpu);
+        }
+    }
+
+    // Whether a frame that already ran that many instructions is done
+    pub fn frame_over(&self, ran: u32) -> bool {
+        if self.vip.is_some() {
+            return ran > 0;
+        }
+        self.cpu.frame_over(ran, self.settings.speed)
+    }
+
+    // Runs the next instructions of a frame that already ran that many, one
+    // at a time or with the JIT until the frame is over or something new is
+    // drawn. Returns how many ran
+    #[cfg_attr(not(feature = "jit"), allow(unused_variables))]
+    pub fn run(&mut self, ran: u32) -> u32 {
+        // The VIP runs a whole frame at once
+        if let Some(vip) = &mut self.vip {
+            vip.set_keypad(self.cpu.key);
+            vip.run_frame();
+            vip.show(&mut self.cpu);
+            return 1;
+        }
+        #[cfg(feature = "jit")]
+        if let Some(jit) = &mut self.jit {
+            // The JIT doesn't count cycles, with the timing quirk it's all
+            // left to the interpreter
+            if !self.cpu.quirks.timing {
+                return jit.run(&mut self.cpu, self.settings.speed - ran);
+            }
+        }
+        self.cpu.fetch_opcode();
+        1
+    }
+
+    // Call after the instructions of the frame have run. Returns what the
+    // player should be told, the frontend decides how
+    pub fn end_frame(&mut self) -> Option<String> {
+        let mut notice = None;
+        if let Some(recording) = &mut self.recorder {
+            if let Err(error) = recording.frame(&self.cpu) {
+                notice = Some(format!("Recording stopped: {}", error));
+                self.recorder = None;
+            }
+        }
+
+        if let Some(broadcaster) = &mut self.broadcaster {
+            broadcaster.frame(&self.cpu);
+        }
+
+        // A frame spent waiting for the other player didn't happen
+        let waiting = self.waiting();
+        // The VIP ticks its own timers
+        if !self.paused && !waiting && self.vip.is_none() {
+            self.cpu.tick_timers();
+        }
+        if !waiting {
+            self.frame += 1;
+        }
+        if let Some(netplay) = &mut self.netplay {
+            if let Some(message) = netplay.end_frame(&mut self.cpu) {
+                notice = Some(message);
+            }
+        }
+
+        // Once the movie is over the keyboard takes over
+        if self.player.as_ref().is_some_and(|p| p.finished(self.frame)) {
+            if let Some(player) = self.player.take() {
+                notice = Some(playback_report(&player, &self.cpu).unwrap_or_else(|error| error));
+            }
+            self.cpu.set_keypad(0);
+        }
+        notice
+    }
+
+    // Saves the movie and the recording once the frontend is done, and
+    // says why the CPU halted if it did
+    pub fn finish(self, movie_path: Option<&Path>) -> Result<(), String> {
+        if let Some(reason) = &self.cpu.halted {
+            println!("{}", reason);
+        }
+
+        // Stopping on an unknown opcode can end a movie in the middle of a frame
+        if let Some(player) = self.player {
+            if player.finished(self.frame) {
+                match playback_report(&player, &self.cpu) {
+                    Ok(report) => println!("{}", report),
+                    Err(error) => eprintln!("{}", error)
+                }
+            } else {
+                println!("Playback stopped before the end of the movie");
+            }
+        }
+        if let (Some(mut movie), Some(path)) = (self.movie, movie_path) {
+            movie.finish(self.frame, &self.cpu);
+            movie.save(path)?;
+            println!("Movie saved to {}", path.display());
+        }
+
+        if let Some(netplay) = self.netplay {
+            println!("Netplay with {} rolled back {} times", netplay.peer(), netplay.rollbacks);
+        }
+
+        #[cfg(feature = "jit")]
+        if let Some(jit) = self.jit {
+            println!("The JIT ran {} instructions natively and left {} to the interpreter",
+                     jit.native, jit.interpreted);
+            if let Some(mismatch) = jit.mismatch {
+                return Err(mismatch);
+            }
+        }
+
+        if let Some(recording) = self.recorder {
+            let path = recording.path().to_path_buf();
+            recording.finish()?;
+            println!("Recording saved to {}", path.display());
+        }
+
+        Ok(())
+    }
+
+}
+
+fn playback_report(player: &Player, cpu: &CPU) -> Result<String, String> {
+    player.verify(cpu).map(|()| "Movie played back, the final state matches".to_string())
+}
@@ -0,0 +1,106 @@
+use crate::cpu::CPU;
+use crate::instruction::Instruction;
+
+// How long instructions take on the COSMAC VIP, for the timing quirk. The
+// 1802 runs at 1.76MHz and a machine cycle is 8 clocks, so a 60Hz frame
+// has 3668 of them. The 1861 shows 128 lines of 14 cycles and the
+// interrupt routine has to point R0 back at the row after each of them to
+// show every row on 4 lines, so the display takes all of those. Starting
+// the routine and ticking the timers takes some more and the interpreter
+// gets what's left, see vip.rs for the real thing
+
+pub const FRAME_CYCLES: i32 = 3668;
+pub const DISPLAY_CYCLES: i32 = 128 * 14;
+pub const INTERRUPT_CYCLES: i32 = 46;
+
+// What the interpreter has between two interrupts
+pub const BUDGET: i32 = FRAME_CYCLES - DISPLAY_CYCLES - INTERRUPT_CYCLES;
+
+// Reading the two bytes and jumping to the routine of the instruction,
+// every instruction pays it
+const FETCH: i32 = 40;
+
+// Taking a skip is one more branch
+const SKIP: i32 = 4;
+
+// Machine cycles the instruction takes, worked out before it runs because
+// skips, FX33 and DXYN depend on the registers. These are roughly what the
+// routines of the VIP interpreter take, not an exact count
+pub fn cycles(cpu: &CPU, instruction: &Instruction) -> i32 {
+    let skip = |taken: bool| if taken { SKIP } else { 0 };
+    let v = |x: u8| cpu.v[x as usize];
+    FETCH + match *instruction {
+        // Clearing the 256 bytes of the display takes most of a frame
+        Instruction::Clear => 3078,
+        Instruction::Return => 10,
+        Instruction::Jump(_) => 12,
+        Instruction::Call(_) => 26,
+        Instruction::SkipEqual(x, nn) => 10 + skip(v(x) == nn),
+        Instruction::SkipNotEqual(x, nn) => 10 + skip(v(x) != nn),
+        Instruction::SkipEqualRegisters(x, y) => 14 + skip(v(x) == v(y)),
+        Instruction::SkipNotEqualRegisters(x, y) => 14 + skip(v(x) != v(y)),
+        Instruction::Load(..) => 6,
+        Instruction::Add(..) => 10,
+        // The VIP builds a tiny routine in memory and calls it for every
+        // 8XYN, whatever it does
+        Instruction::Move(..) | Instruction::Or(..) | Instruction::And(..) | Instruction::Xor(..) |
+        Instruction::AddRegisters(..) | Instruction::Sub(..) | Instruction::ShiftRight(..) |
+        Instruction::SubReversed(..) | Instruction::ShiftLeft(..) => 44,
+        Instruction::LoadI(_) => 12,
+        Instruction::JumpV0(_) => 22,
+        Instruction::Random(..) => 36,
+        // Sprites that don't start at a byte boundary touch two bytes a row,
+        // the wait for the interrupt comes on top of this
+        Instruction::Draw(x, _, rows) => {
+            let row = if v(x) % 8 == 0 { 46 } else { 68 };
+            26 + row * rows as i32
+        },
+        Instruction::SkipPressed(x) => 14 + skip(cpu.key.pressed(v(x))),
+        Instruction::SkipNotPressed(x) => 14 + skip(!cpu.key.pressed(v(x))),
+        Instruction::GetDelay(_) | Instruction::SetDelay(_) | Instruction::SetSound(_) => 10,
+        // Every look at the keypad, the frame ends there while it waits
+        Instruction::WaitKey(_) => 16,
+        Instruction::AddI(_) | Instruction::Font(_) => 16,
+        // Each digit is found by subtracting until it would go negative
+        Instruction::Bcd(x) => {
+            let value = v(x);
+            let digits = value / 100 + (value / 10) % 10 + value % 10;
+            80 + 16 * digits as i32
+        },
+        Instruction::Store(x) | Instruction::Restore(x) => 14 + 14 * (x as i32 + 1),
+        // Nothing the VIP had, they stop the machine anyway
+        _ => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn the_interpreter_gets_what_the_display_leaves() {
+        assert_eq!(BUDGET, 3668 - 1792 - 46);
+    }
+
+    #[test]
+    fn costs_depend_on_the_registers() {
+        let mut cpu = CPU::initialize();
+        cpu.v[1] = 3;
+        cpu.v[2] = 255;
+        assert_eq!(cycles(&cpu, &Instruction::Load(0, 1)), 46);
+        assert_eq!(cycles(&cpu, &Instruction::Add(0, 1)), 50);
+        assert_eq!(cycles(&cpu, &Instruction::AddRegisters(0, 1)), 84);
+        assert_eq!(cycles(&cpu, &Instruction::Clear), 3118);
+        // Skips cost more when they're taken
+        assert_eq!(cycles(&cpu, &Instruction::SkipEqual(1, 3)), 54);
+        assert_eq!(cycles(&cpu, &Instruction::SkipEqual(1, 4)), 50);
+        // V0 is on a byte boundary and V1 isn't
+        assert_eq!(cycles(&cpu, &Instruction::Draw(0, 0, 5)), 40 + 26 + 46 * 5);
+        assert_eq!(cycles(&cpu, &Instruction::Draw(1, 0, 5)), 40 + 26 + 68 * 5);
+        // 2 + 5 + 5 subtractions
+        assert_eq!(cycles(&cpu, &Instruction::Bcd(2)), 40 + 80 + 16 * 12);
+        assert_eq!(cycles(&cpu, &Instruction::Store(2)), 40 + 14 + 14 * 3);
+    }
+
+}
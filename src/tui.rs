@@ -0,0 +1,286 @@
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+                       KeyboardEnhancementFlags, PopKeyboardEnhancementFlags,
+                       PushKeyboardEnhancementFlags};
+use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{execute, queue};
+
+use system8::broadcast::{Frame, Viewer};
+use system8::palette::Palette;
+use system8::screenshot;
+
+use crate::session::Session;
+
+// Most terminals only say when a key goes down and then repeat it, so
+// without release events a key counts as held for this many frames after
+// the last press or repeat
+const KEY_HOLD_FRAMES: u32 = 8;
+
+// Puts the terminal back the way it was however the frontend ends
+struct Terminal {
+    enhanced: bool
+}
+
+impl Terminal {
+
+    fn enter() -> io::Result<Terminal> {
+        terminal::enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen, Hide, Clear(ClearType::All))?;
+        // Terminals with the kitty keyboard protocol report key releases
+        let enhanced = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if enhanced {
+            execute!(io::stdout(), PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(Terminal { enhanced })
+    }
+
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        if self.enhanced {
+            let _ = execute!(io::stdout(), PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(io::stdout(), ResetColor, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+// The terminal frontend for when there's no window to open, over SSH for
+// example. The screen is drawn with half blocks, two pixels per character
+// in full colour, or braille, eight pixels per character in two colours.
+// The bell character is the buzzer. Runs until Escape, Ctrl+C or an
+// unknown opcode
+pub fn run(session: &mut Session, braille: bool) -> Result<(), String> {
+    let keymap = keymap(&session.settings.keymap);
+    let terminal = Terminal::enter().map_err(|e| e.to_string())?;
+    let mut held = [0u32; 16];
+    let mut buzzing = false;
+    let mut redraw = true;
+    let mut status = format!("{} - Esc quits, F12 saves a screenshot", session.title);
+
+    let frame_duration = Duration::from_nanos(1_000_000_000 / 60);
+
+    'mainloop: loop {
+        let frame_start = Instant::now();
+
+        while event::poll(Duration::ZERO).map_err(|e| e.to_string())? {
+            match event::read().map_err(|e| e.to_string())? {
+                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => break 'mainloop,
+                Event::Key(KeyEvent { code: KeyCode::Char('c'), modifiers, .. })
+                    if modifiers.contains(KeyModifiers::CONTROL) => break 'mainloop,
+                Event::Key(KeyEvent { code: KeyCode::F(12), kind: KeyEventKind::Press, .. }) => {
+                    let settings = &session.settings;
+                    let scale = settings.screenshot_scale as usize;
+                    status = match screenshot::capture(&session.cpu, &settings.palette, scale,
+                                                       &settings.screenshot_dir) {
+                        Ok(path) => format!("Screenshot saved to {}", path.display()),
+                        Err(error) => format!("Screenshot failed: {}", error)
+                    };
+                    redraw = true;
+                },
+                Event::Key(KeyEvent { code, kind, .. }) => {
+                    let code = match code {
+                        KeyCode::Char(c) => KeyCode::Char(c.to_ascii_lowercase()),
+                        code => code
+                    };
+                    for &(_, key) in keymap.iter().filter(|&&(k, _)| k == code) {
+                        held[key] = match kind {
+                            KeyEventKind::Release => 0,
+                            // With release events a key is held until it's let go
+                            _ if terminal.enhanced => u32::MAX,
+                            _ => KEY_HOLD_FRAMES
+                        };
+                    }
+                },
+                Event::Resize(..) => redraw = true,
+                _ => {}
+            }
+        }
+
+        if !session.playing() {
+            for (key, frames) in held.iter_mut().enumerate() {
+                session.cpu.key.set(key as u8, *frames > 0);
+                if *frames != u32::MAX {
+                    *frames = frames.saturating_sub(1);
+                }
+            }
+        }
+
+        session.start_frame();
+
+        if session.running() {
+            let mut ran = 0;
+            while !session.frame_over(ran) {
+                ran += session.run(ran);
+            }
+        }
+        if session.over() {
+            break 'mainloop
+        }
+
+        if session.cpu.draw || redraw {
+            let mut out = Vec::new();
+            if redraw {
+                queue!(out, ResetColor, Clear(ClearType::All)).map_err(|e| e.to_string())?;
+            }
+            let cpu = &session.cpu;
+            draw(&mut out, &cpu.gfx, cpu.display_size(), &session.settings.palette, braille, &status)
+                .map_err(|e| e.to_string())?;
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&out).and_then(|_| stdout.flush()).map_err(|e| e.to_string())?;
+            session.cpu.draw = false;
+            redraw = false;
+        }
+
+        // Ring the bell when the buzzer starts
+        let sound = session.cpu.sound_timer > 0;
+        if sound && !buzzing {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(b"\x07").and_then(|_| stdout.flush()).map_err(|e| e.to_string())?;
+        }
+        buzzing = sound;
+
+        if let Some(notice) = session.end_frame() {
+            status = notice;
+            redraw = true;
+        }
+
+        if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining)
+        }
+    }
+
+    Ok(())
+}
+
+// Shows a broadcast until it ends, Escape or Ctrl+C
+pub fn watch(viewer: &mut Viewer, palette: &Palette, braille: bool, title: &str) -> Result<(), String> {
+    let _terminal = Terminal::enter().map_err(|e| e.to_string())?;
+    let mut last: Option<Frame> = None;
+    let mut redraw = false;
+    let status = format!("{} - Esc quits", title);
+
+    let frame_duration = Duration::from_nanos(1_000_000_000 / 60);
+
+    'mainloop: loop {
+        let frame_start = Instant::now();
+
+        while event::poll(Duration::ZERO).map_err(|e| e.to_string())? {
+            match event::read().map_err(|e| e.to_string())? {
+                Event::Key(KeyEvent { code: KeyCode::Esc, .. }) => break 'mainloop,
+                Event::Key(KeyEvent { code: KeyCode::Char('c'), modifiers, .. })
+                    if modifiers.contains(KeyModifiers::CONTROL) => break 'mainloop,
+                Event::Resize(..) => redraw = true,
+                _ => {}
+            }
+        }
+
+        let mut out = Vec::new();
+        let frame = viewer.poll()?;
+        // Ring the bell when the buzzer starts
+        if frame.as_ref().is_some_and(|frame| frame.sound) && !last.as_ref().is_some_and(|last| last.sound) {
+            out.push(0x07);
+        }
+        if redraw {
+            queue!(out, ResetColor, Clear(ClearType::All)).map_err(|e| e.to_string())?;
+        }
+        if let Some(frame) = frame.or_else(|| if redraw { last.take() } else { None }) {
+            draw(&mut out, &frame.gfx, frame.display, palette, braille, &status)
+                .map_err(|e| e.to_string())?;
+            last = Some(frame);
+            redraw = false;
+        }
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(&out).and_then(|_| stdout.flush()).map_err(|e| e.to_string())?;
+
+        if let Some(remaining) = frame_duration.checked_sub(frame_start.elapsed()) {
+            thread::sleep(remaining)
+        }
+    }
+
+    Ok(())
+}
+
+// The keymap uses SDL key names, the ones a terminal can tell apart are
+// letters, digits, symbols, Space, Return and the arrows. Others like
+// Left Shift are left out
+fn keymap(names: &[Vec<String>; 16]) -> Vec<(KeyCode, usize)> {
+    let mut keymap = Vec::new();
+    for (key, names) in names.iter().enumerate() {
+        for name in names {
+            let mut chars = name.chars();
+            let code = match (chars.next(), chars.next(), name.as_str()) {
+                (Some(c), None, _) => KeyCode::Char(c.to_ascii_lowercase()),
+                (_, _, "Space") => KeyCode::Char(' '),
+                (_, _, "Return") => KeyCode::Enter,
+                (_, _, "Up") => KeyCode::Up,
+                (_, _, "Down") => KeyCode::Down,
+                (_, _, "Left") => KeyCode::Left,
+                (_, _, "Right") => KeyCode::Right,
+                _ => continue
+            };
+            keymap.push((code, key));
+        }
+    }
+    keymap
+}
+
+fn rgb(colour: [u8; 3]) -> Color {
+    Color::Rgb { r: colour[0], g: colour[1], b: colour[2] }
+}
+
+// Writes the whole screen from the top left corner and the status line
+// under it, colours are only sent when they change
+fn draw<W: Write>(out: &mut W, gfx: &[u8], display: (usize, usize), palette: &Palette,
+                  braille: bool, status: &str) -> io::Result<()> {
+    let (width, height) = display;
+    let pixel = |x: usize, y: usize| gfx[y * width + x];
+    let mut colours = None;
+    let mut lines = 0;
+    if braille {
+        // Dots of a braille character by column and row
+        const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+        queue!(out, SetForegroundColor(rgb(palette.colour(1))),
+               SetBackgroundColor(rgb(palette.colour(0))))?;
+        for row in (0..height).step_by(4) {
+            queue!(out, MoveTo(0, lines))?;
+            let line: String = (0..width).step_by(2).map(|column| {
+                let mut bits = 0;
+                for (dx, dots) in DOTS.iter().enumerate() {
+                    for (dy, dot) in dots.iter().enumerate() {
+                        let (x, y) = (column + dx, row + dy);
+                        if x < width && y < height && pixel(x, y) != 0 {
+                            bits |= dot;
+                        }
+                    }
+                }
+                std::char::from_u32(0x2800 + bits).unwrap_or(' ')
+            }).collect();
+            queue!(out, Print(line))?;
+            lines += 1;
+        }
+    } else {
+        // The upper half block takes the top pixel as the foreground and
+        // the bottom one as the background
+        for row in (0..height).step_by(2) {
+            queue!(out, MoveTo(0, lines))?;
+            for x in 0..width {
+                let top = palette.colour(pixel(x, row));
+                let bottom = palette.colour(if row + 1 < height { pixel(x, row + 1) } else { 0 });
+                if colours != Some((top, bottom)) {
+                    queue!(out, SetForegroundColor(rgb(top)), SetBackgroundColor(rgb(bottom)))?;
+                    colours = Some((top, bottom));
+                }
+                queue!(out, Print('▀'))?;
+            }
+            lines += 1;
+        }
+    }
+    queue!(out, ResetColor, MoveTo(0, lines), Clear(ClearType::CurrentLine), Print(status))
+}
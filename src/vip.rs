@@ -0,0 +1,300 @@
+use crate::cdp1802::{Bus, Cdp1802};
+use crate::cpu::{Keypad, CPU};
+
+// The whole COSMAC VIP: a CDP1802, 4KB of RAM, the 512 bytes of the monitor
+// ROM, the CDP1861 drawing the screen with DMA and the hex keypad. CHIP-8
+// isn't built in, the interpreter sat in RAM from 0000 and the monitor
+// jumps there after a reset, so both have to come from the user. It's the
+// reference for how the original really behaved, the CPU struct is what
+// everything else runs
+
+pub const RAM_SIZE: usize = 4096;
+pub const ROM_SIZE: usize = 512;
+
+// Programs are loaded after the interpreter and can't reach the variables
+// and the display it keeps at the top of RAM
+pub const PROGRAM_START: usize = 0x200;
+pub const PROGRAM_END: usize = 0xEA0;
+
+// Where the interpreter keeps V0 to VF on a 4KB VIP
+const REGISTERS: usize = 0xEF0;
+
+// The 1861 draws 262 lines of 14 machine cycles, 60 times a second. 128 of
+// them show 8 bytes each, read by DMA as the line starts
+const LINE_CYCLES: u32 = 14;
+const FRAME_CYCLES: u32 = 262 * LINE_CYCLES;
+const FIRST_LINE: u32 = 64;
+pub const DISPLAY_LINES: usize = 128;
+const DMA_BYTES: usize = 8;
+
+// The interrupt comes 29 cycles before the first DMA so the routine has
+// time to point R0 at the display, and EF1 is asserted for the 4 lines
+// before the display and the last 4 of it so it knows when to stop
+const INTERRUPT_CYCLE: u32 = FIRST_LINE * LINE_CYCLES - 29;
+const FLAG_LINES: u32 = 4;
+
+pub struct Vip {
+    pub cpu: Cdp1802,
+    board: Board,
+    // Machine cycles into the current frame
+    cycle: u32,
+    next_line: u32,
+    interrupted: bool
+}
+
+// Everything the 1802 is wired to
+struct Board {
+    ram: [u8; RAM_SIZE],
+    rom: [u8; ROM_SIZE],
+    // After a reset the ROM also shows up at 0000, until the monitor jumps
+    // to where it really is and A15 goes high
+    rom_at_zero: bool,
+    // OUT 2 picks the key EF3 reports
+    latch: u8,
+    keypad: Keypad,
+    // INP 1 turns the 1861 on and OUT 1 off
+    display_on: bool,
+    display: [[u8; DMA_BYTES]; DISPLAY_LINES],
+    // To know when EF1 is asserted
+    cycle: u32
+}
+
+impl Vip {
+
+    // The monitor ROM, the CHIP-8 interpreter to put at 0000 and the
+    // program to put at 0200. Starts like after pressing RUN
+    pub fn new(monitor: &[u8], interpreter: &[u8], program: &[u8]) -> Result<Vip, String> {
+        if monitor.len() != ROM_SIZE {
+            return Err(format!("The VIP ROM has to be {} bytes, not {}", ROM_SIZE, monitor.len()));
+        }
+        if interpreter.len() > PROGRAM_START {
+            return Err(format!("The CHIP-8 interpreter has to fit in {} bytes", PROGRAM_START));
+        }
+        if program.len() > PROGRAM_END - PROGRAM_START {
+            return Err(format!("The ROM is too big for the VIP, programs end at {:#05X}", PROGRAM_END - 1));
+        }
+        let mut board = Board {
+            ram: [0; RAM_SIZE],
+            rom: [0; ROM_SIZE],
+            rom_at_zero: true,
+            latch: 0,
+            keypad: Keypad::default(),
+            display_on: false,
+            display: [[0; DMA_BYTES]; DISPLAY_LINES],
+            cycle: 0
+        };
+        board.rom.copy_from_slice(monitor);
+        board.ram[..interpreter.len()].copy_from_slice(interpreter);
+        board.ram[PROGRAM_START..PROGRAM_START + program.len()].copy_from_slice(program);
+        Ok(Vip { cpu: Cdp1802::new(), board, cycle: 0, next_line: FIRST_LINE, interrupted: false })
+    }
+
+    pub fn set_keypad(&mut self, keypad: Keypad) {
+        self.board.keypad = keypad;
+    }
+
+    pub fn ram(&self) -> &[u8; RAM_SIZE] {
+        &self.board.ram
+    }
+
+    // The lines the 1861 showed in the last frame, 8 bytes of pixels each
+    pub fn display(&self) -> &[[u8; DMA_BYTES]; DISPLAY_LINES] {
+        &self.board.display
+    }
+
+    // Runs until the next frame starts. DMA comes first, then the
+    // interrupt and then the 1802 gets the cycles left
+    pub fn run_frame(&mut self) {
+        let display_end = FIRST_LINE + DISPLAY_LINES as u32;
+        while self.cycle < FRAME_CYCLES {
+            self.board.cycle = self.cycle;
+            if self.next_line < display_end && self.cycle >= self.next_line * LINE_CYCLES {
+                if self.board.display_on {
+                    let line = (self.next_line - FIRST_LINE) as usize;
+                    for byte in 0..DMA_BYTES {
+                        self.board.display[line][byte] = self.cpu.dma_out(&mut self.board);
+                    }
+                    self.cycle += DMA_BYTES as u32;
+                }
+                self.next_line += 1;
+                continue;
+            }
+            // The interrupt line is only held until the display starts, if
+            // interrupts are off by then the frame goes without
+            let interrupt = self.board.display_on && !self.interrupted &&
+                (INTERRUPT_CYCLE..FIRST_LINE * LINE_CYCLES).contains(&self.cycle);
+            if interrupt && self.cpu.ie {
+                self.cpu.interrupt();
+                self.interrupted = true;
+                self.cycle += 1;
+                continue;
+            }
+            if self.cpu.idle {
+                self.cycle = self.next_event();
+                continue;
+            }
+            self.cycle += self.cpu.step(&mut self.board);
+        }
+        self.cycle -= FRAME_CYCLES;
+        self.next_line = FIRST_LINE;
+        self.interrupted = false;
+    }
+
+    // Where IDL stops waiting, the next DMA or interrupt or the next frame
+    fn next_event(&self) -> u32 {
+        let mut next = FRAME_CYCLES;
+        if self.board.display_on {
+            if !self.interrupted && self.cycle < INTERRUPT_CYCLE {
+                next = next.min(INTERRUPT_CYCLE);
+            }
+            if self.next_line < FIRST_LINE + DISPLAY_LINES as u32 {
+                next = next.min(self.next_line * LINE_CYCLES);
+            }
+        }
+        next.max(self.cycle + 2)
+    }
+
+    // Shows the VIP through a CPU so the frontends, screenshots and the
+    // recorder work as usual. The interpreter repeats every row of CHIP-8
+    // pixels on 4 lines so every fourth line is a row, the buzzer is Q
+    // and memory and V0 to VF are copied for anyone looking at them
+    pub fn show(&self, cpu: &mut CPU) {
+        let (width, _) = cpu.display_size();
+        let mut gfx = [0; 2048];
+        for (row, pixels) in gfx.chunks_mut(width).enumerate() {
+            let line = &self.board.display[row * DISPLAY_LINES / 32];
+            for (x, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = (line[x / 8] >> (7 - x % 8)) & 1;
+            }
+        }
+        if gfx != cpu.gfx {
+            cpu.gfx = gfx;
+            cpu.draw = true;
+        }
+        if self.board.ram != *cpu.memory() {
+            cpu.write_memory(0, &self.board.ram);
+        }
+        cpu.v.copy_from_slice(&self.board.ram[REGISTERS..REGISTERS + 16]);
+        cpu.sound_timer = self.cpu.q as u8;
+    }
+
+}
+
+impl Bus for Board {
+
+    fn read(&mut self, address: u16) -> u8 {
+        if address & 0x8000 != 0 {
+            self.rom_at_zero = false;
+        }
+        if address & 0x8000 != 0 || self.rom_at_zero {
+            self.rom[address as usize % ROM_SIZE]
+        } else {
+            self.ram[address as usize % RAM_SIZE]
+        }
+    }
+
+    // The ROM can't be written, not even while it's at 0000
+    fn write(&mut self, address: u16, value: u8) {
+        if address & 0x8000 == 0 {
+            self.ram[address as usize % RAM_SIZE] = value;
+        }
+    }
+
+    // Nothing drives the bus, it reads as zero
+    fn input(&mut self, port: u8) -> u8 {
+        if port == 1 {
+            self.display_on = true;
+        }
+        0
+    }
+
+    fn output(&mut self, port: u8, value: u8) {
+        match port {
+            1 => self.display_on = false,
+            2 => self.latch = value & 0xF,
+            _ => {}
+        }
+    }
+
+    // EF1 is the 1861, EF2 the cassette, EF3 the key picked by OUT 2 and
+    // EF4 the IN button
+    fn flag(&mut self, flag: u8) -> bool {
+        match flag {
+            1 => {
+                let line = self.cycle / LINE_CYCLES;
+                let last = FIRST_LINE + DISPLAY_LINES as u32;
+                (FIRST_LINE - FLAG_LINES..FIRST_LINE).contains(&line) ||
+                    (last - FLAG_LINES..last).contains(&line)
+            },
+            3 => self.keypad.pressed(self.latch),
+            _ => false
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // A VIP whose ROM is all IDL, running from RAM with R3 as the program
+    // counter, the program at 0100 and the display on
+    fn vip(program: &[u8]) -> Vip {
+        let mut vip = Vip::new(&[0; ROM_SIZE], &[], &[]).unwrap();
+        vip.board.rom_at_zero = false;
+        vip.board.display_on = true;
+        vip.board.ram[0x100..0x100 + program.len()].copy_from_slice(program);
+        vip.cpu.p = 3;
+        vip.cpu.r[3] = 0x100;
+        vip
+    }
+
+    #[test]
+    fn dma_shows_what_r0_points_at() {
+        // Nothing resets R0 so the lines are the 1KB from 0C00
+        let mut vip = vip(&[0x00]);
+        vip.cpu.ie = false;
+        vip.cpu.r[0] = 0xC00;
+        for offset in 0..DISPLAY_LINES * DMA_BYTES {
+            vip.board.ram[0xC00 + offset] = (offset % 251) as u8;
+        }
+        vip.run_frame();
+        for (line, bytes) in vip.display().iter().enumerate() {
+            for (byte, &value) in bytes.iter().enumerate() {
+                assert_eq!(value, ((line * DMA_BYTES + byte) % 251) as u8, "line {} byte {}", line, byte);
+            }
+        }
+        assert_eq!(vip.cpu.r[0], 0x1000);
+    }
+
+    #[test]
+    fn ef1_is_asserted_around_the_display() {
+        let mut board = vip(&[]).board;
+        let mut ef1 = |line: u32, cycle: u32| {
+            board.cycle = line * LINE_CYCLES + cycle;
+            board.flag(1)
+        };
+        assert!(!ef1(FIRST_LINE - FLAG_LINES - 1, LINE_CYCLES - 1));
+        assert!(ef1(FIRST_LINE - FLAG_LINES, 0));
+        assert!(ef1(0, INTERRUPT_CYCLE));
+        assert!(ef1(FIRST_LINE - 1, LINE_CYCLES - 1));
+        assert!(!ef1(FIRST_LINE, 0));
+        let last = FIRST_LINE + DISPLAY_LINES as u32;
+        assert!(!ef1(last - FLAG_LINES - 1, LINE_CYCLES - 1));
+        assert!(ef1(last - FLAG_LINES, 0));
+        assert!(!ef1(last, 0));
+    }
+
+    #[test]
+    fn the_interrupt_comes_while_ef1_is_asserted() {
+        // The routine at 0200 sets Q if EF1 is asserted: BN1 0205, SEQ, IDL
+        let mut vip = vip(&[0x00]);
+        vip.board.ram[0x200..0x206].copy_from_slice(&[0x3C, 0x05, 0x7B, 0x00, 0x00, 0x00]);
+        vip.cpu.r[1] = 0x200;
+        vip.run_frame();
+        assert_eq!((vip.cpu.p, vip.cpu.ie), (1, false));
+        assert!(vip.cpu.q);
+    }
+
+}
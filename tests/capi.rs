@@ -0,0 +1,105 @@
+// Checks include/system8.h is what cbindgen makes of src/capi.rs (run with
+// SYSTEM8_WRITE_HEADER=1 to generate it again), then
+// builds examples/capi.c against the library and runs it. The Python
+// feature leaves the library full of symbols only Python has, so it's
+// skipped there
+#![cfg(all(unix, not(feature = "python")))]
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// Draws a 5 that moves right while key 5 is held, with the buzzer on for
+// the first 48 frames
+const ROM: [u8; 24] = [
+    0x00, 0xE0, // CLS
+    0x60, 0x05, // V0 = 5
+    0xF0, 0x29, // I = sprite of V0
+    0x61, 0x00, // V1 = 0
+    0x62, 0x0A, // V2 = 10
+    0x63, 0x30, // V3 = 48
+    0xF3, 0x18, // Sound timer = V3
+    0x00, 0xE0, // CLS
+    0xD1, 0x25, // Draw at V1, V2
+    0xE0, 0xA1, // Skip if key V0 isn't pressed
+    0x71, 0x01, // V1 += 1
+    0x12, 0x0E  // Jump to the CLS
+];
+
+#[test]
+fn header_is_up_to_date() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let config = cbindgen::Config::from_file(root.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::Builder::new()
+        .with_src(root.join("src/capi.rs"))
+        .with_config(config)
+        .generate()
+        .unwrap()
+        .write(&mut generated);
+    let path = root.join("include/system8.h");
+    if env::var_os("SYSTEM8_WRITE_HEADER").is_some() {
+        fs::write(&path, generated).unwrap();
+        return;
+    }
+    let current = fs::read_to_string(&path).unwrap_or_default();
+    let generated = String::from_utf8(generated).unwrap();
+    assert!(current == generated, "{} is out of date, SYSTEM8_WRITE_HEADER=1 cargo test generates it again:\n{}",
+            path.display(), diff(&current, &generated));
+}
+
+// The lines from the first one that differs, enough to see what changed
+fn diff(current: &str, generated: &str) -> String {
+    let (current, generated): (Vec<&str>, Vec<&str>) = (current.lines().collect(), generated.lines().collect());
+    let same = current.iter().zip(&generated).take_while(|(a, b)| a == b).count();
+    let mut text = format!("@@ line {}\n", same + 1);
+    for line in current.iter().skip(same).take(20) {
+        text += &format!("-{}\n", line);
+    }
+    for line in generated.iter().skip(same).take(20) {
+        text += &format!("+{}\n", line);
+    }
+    text
+}
+
+#[test]
+fn c_example_runs() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let tmp = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    // The test runs from target/<profile>/deps and the library is one up,
+    // but cargo test only builds the rlib so the cdylib is built here
+    let exe = env::current_exe().unwrap();
+    let lib_dir = exe.parent().and_then(Path::parent).unwrap();
+    let mut cargo = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()));
+    cargo.current_dir(root).args(["build", "--lib"]);
+    if lib_dir.ends_with("release") {
+        cargo.arg("--release");
+    }
+    assert!(cargo.status().unwrap().success(), "The library doesn't build");
+
+    let example = tmp.join("capi");
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(root.join("examples/capi.c"))
+        .arg("-I").arg(root.join("include"))
+        .arg("-L").arg(lib_dir)
+        .arg("-lsystem8")
+        .arg("-o").arg(&example)
+        .status()
+        .expect("Can't run the C compiler");
+    assert!(status.success(), "examples/capi.c doesn't build");
+
+    let rom = tmp.join("capi.ch8");
+    fs::write(&rom, ROM).unwrap();
+    let output = Command::new(&example)
+        .arg(&rom)
+        .env("LD_LIBRARY_PATH", lib_dir)
+        .env("DYLD_LIBRARY_PATH", lib_dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    assert!(stdout.contains('#'), "Nothing was drawn:\n{}", stdout);
+    assert!(stdout.contains("47 frames with sound"), "{}", stdout);
+    assert!(stdout.contains("replay matches"), "{}", stdout);
+}